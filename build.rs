@@ -0,0 +1,38 @@
+//! Records which commit the server was built from, and when, for `/version`.
+//!
+//! Docker builds don't have the `.git` directory, so they pass the commit in `GIT_SHA` instead.
+//! `SOURCE_DATE_EPOCH` overrides the build time, for reproducible builds.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+            output.status.success().then_some(())?;
+            Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default()
+        });
+
+    println!("cargo:rustc-env=GIT_SHA={git_sha}");
+    println!("cargo:rustc-env=BUILD_TIME={build_time}");
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
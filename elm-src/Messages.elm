@@ -0,0 +1,240 @@
+module Messages exposing
+    ( InMsg(..)
+    , MessageError(..)
+    , OutMsg(..)
+    , Rejection
+    , Restart
+    , Task
+    , Tasks
+    , decodeInMsg
+    , decodeMessageError
+    , decodeOutMsg
+    , decodeRejection
+    , decodeRestart
+    , decodeTask
+    , decodeTasks
+    , encodeInMsg
+    , encodeMessageError
+    , encodeOutMsg
+    , encodeRejection
+    , encodeRestart
+    , encodeTask
+    , encodeTasks
+    )
+
+{-| The messages sent over the websocket.
+
+Generated from the Rust types by `cargo run -- generate-elm`, don't edit it by hand.
+
+-}
+
+import Json.Decode as Decode exposing (Decoder)
+import Json.Encode as Encode exposing (Value)
+
+
+type InMsg
+    = InMsgTasks Tasks
+
+
+decodeInMsg : Decoder InMsg
+decodeInMsg =
+    Decode.field "action" Decode.string
+        |> Decode.andThen
+            (\tag ->
+                case tag of
+                    "tasks" ->
+                        Decode.map InMsgTasks (Decode.field "payload" decodeTasks)
+
+                    _ ->
+                        Decode.fail ("Unknown action: " ++ tag)
+            )
+
+
+encodeInMsg : InMsg -> Value
+encodeInMsg value =
+    case value of
+        InMsgTasks content ->
+            Encode.object
+                [ ( "action", Encode.string "tasks" )
+                , ( "payload", encodeTasks content )
+                ]
+
+
+type MessageError
+    = MessageErrorTooLarge
+    | MessageErrorTooManyTasks
+    | MessageErrorSummaryTooLong
+
+
+decodeMessageError : Decoder MessageError
+decodeMessageError =
+    Decode.string
+        |> Decode.andThen
+            (\value ->
+                case value of
+                    "too_large" ->
+                        Decode.succeed MessageErrorTooLarge
+
+                    "too_many_tasks" ->
+                        Decode.succeed MessageErrorTooManyTasks
+
+                    "summary_too_long" ->
+                        Decode.succeed MessageErrorSummaryTooLong
+
+                    _ ->
+                        Decode.fail ("Unknown MessageError: " ++ value)
+            )
+
+
+encodeMessageError : MessageError -> Value
+encodeMessageError value =
+    Encode.string <|
+        case value of
+            MessageErrorTooLarge ->
+                "too_large"
+
+            MessageErrorTooManyTasks ->
+                "too_many_tasks"
+
+            MessageErrorSummaryTooLong ->
+                "summary_too_long"
+
+
+type OutMsg
+    = OutMsgNewTasks Tasks
+    | OutMsgRejected Rejection
+    | OutMsgRestarting Restart
+
+
+decodeOutMsg : Decoder OutMsg
+decodeOutMsg =
+    Decode.field "action" Decode.string
+        |> Decode.andThen
+            (\tag ->
+                case tag of
+                    "new_tasks" ->
+                        Decode.map OutMsgNewTasks (Decode.field "payload" decodeTasks)
+
+                    "rejected" ->
+                        Decode.map OutMsgRejected (Decode.field "payload" decodeRejection)
+
+                    "restarting" ->
+                        Decode.map OutMsgRestarting (Decode.field "payload" decodeRestart)
+
+                    _ ->
+                        Decode.fail ("Unknown action: " ++ tag)
+            )
+
+
+encodeOutMsg : OutMsg -> Value
+encodeOutMsg value =
+    case value of
+        OutMsgNewTasks content ->
+            Encode.object
+                [ ( "action", Encode.string "new_tasks" )
+                , ( "payload", encodeTasks content )
+                ]
+
+        OutMsgRejected content ->
+            Encode.object
+                [ ( "action", Encode.string "rejected" )
+                , ( "payload", encodeRejection content )
+                ]
+
+        OutMsgRestarting content ->
+            Encode.object
+                [ ( "action", Encode.string "restarting" )
+                , ( "payload", encodeRestart content )
+                ]
+
+
+type alias Rejection =
+    { error : MessageError
+    , message : String
+    }
+
+
+decodeRejection : Decoder Rejection
+decodeRejection =
+    Decode.succeed Rejection
+        |> andMap (Decode.field "error" decodeMessageError)
+        |> andMap (Decode.field "message" Decode.string)
+
+
+encodeRejection : Rejection -> Value
+encodeRejection value =
+    Encode.object
+        [ ( "error", encodeMessageError value.error )
+        , ( "message", Encode.string value.message )
+        ]
+
+
+type alias Restart =
+    { message : String
+    , reconnectIn : Int
+    }
+
+
+decodeRestart : Decoder Restart
+decodeRestart =
+    Decode.succeed Restart
+        |> andMap (Decode.field "message" Decode.string)
+        |> andMap (Decode.field "reconnect_in" Decode.int)
+
+
+encodeRestart : Restart -> Value
+encodeRestart value =
+    Encode.object
+        [ ( "message", Encode.string value.message )
+        , ( "reconnect_in", Encode.int value.reconnectIn )
+        ]
+
+
+type alias Task =
+    { id : Int
+    , summary : String
+    }
+
+
+decodeTask : Decoder Task
+decodeTask =
+    Decode.succeed Task
+        |> andMap (Decode.field "id" Decode.int)
+        |> andMap (Decode.field "summary" Decode.string)
+
+
+encodeTask : Task -> Value
+encodeTask value =
+    Encode.object
+        [ ( "id", Encode.int value.id )
+        , ( "summary", Encode.string value.summary )
+        ]
+
+
+type alias Tasks =
+    { nextId : Int
+    , tasks : List Task
+    , version : Int
+    }
+
+
+decodeTasks : Decoder Tasks
+decodeTasks =
+    Decode.succeed Tasks
+        |> andMap (Decode.field "next_id" Decode.int)
+        |> andMap (Decode.field "tasks" (Decode.list decodeTask))
+        |> andMap (Decode.field "version" Decode.int)
+
+
+encodeTasks : Tasks -> Value
+encodeTasks value =
+    Encode.object
+        [ ( "next_id", Encode.int value.nextId )
+        , ( "tasks", Encode.list encodeTask value.tasks )
+        , ( "version", Encode.int value.version )
+        ]
+
+
+andMap : Decoder a -> Decoder (a -> b) -> Decoder b
+andMap =
+    Decode.map2 (|>)
@@ -27,6 +27,7 @@ import Html.Styled.Attributes
         )
 import Html.Styled.Events exposing (onInput, onSubmit)
 import Http
+import Json.Decode as Decode
 import Json.Encode as Encode
 import Route
 
@@ -48,7 +49,7 @@ type Msg
     = UpdateUsername String
     | UpdatePassword String
     | Submit
-    | Response (Result Http.Error ())
+    | Response (Result String ())
 
 
 init : Model
@@ -74,7 +75,7 @@ update msg (M model) =
             , Http.post
                 { url = "/api/register"
                 , body = Http.jsonBody <| Encode.object [ ( "username", Encode.string model.username ), ( "password", Encode.string model.password ) ]
-                , expect = Http.expectWhatever Response
+                , expect = Http.expectStringResponse Response registerResponse
                 }
             , None
             )
@@ -87,11 +88,25 @@ update msg (M model) =
                     , PushRoute Route.Login
                     )
 
-                Err (Http.BadStatus 409) ->
-                    ( M { model | message = Just "Username already exists, pick a different one" }, Cmd.none, None )
+                Err message ->
+                    ( M { model | message = Just message }, Cmd.none, None )
 
-                Err _ ->
-                    ( M { model | message = Just "Some error occurred" }, Cmd.none, None )
+
+{-| The server explains why an account was rejected in the `message` field of the body
+-}
+registerResponse : Http.Response String -> Result String ()
+registerResponse response =
+    case response of
+        Http.GoodStatus_ _ _ ->
+            Ok ()
+
+        Http.BadStatus_ _ body ->
+            Decode.decodeString (Decode.field "message" Decode.string) body
+                |> Result.withDefault "Some error occurred"
+                |> Err
+
+        _ ->
+            Err "Some error occurred"
 
 
 view : Model -> Html Msg
@@ -1,7 +1,8 @@
 port module Ports exposing (InMessage(..), OutMessage(..), connectWebsocket, decodeIncomingMessage, recv, send)
 
-import Json.Decode exposing (errorToString, field, map2)
-import Json.Encode exposing (Value, object, string)
+import Json.Decode exposing (errorToString)
+import Json.Encode exposing (Value)
+import Messages as M
 import Tasks as T
 
 
@@ -26,26 +27,7 @@ send : OutMessage -> Cmd msg
 send outMsg =
     case outMsg of
         Tasks ts ->
-            sendTasks ts
-
-
-sendTasks : T.Tasks -> Cmd msg
-sendTasks ts =
-    sendHelp "tasks" (Just (T.encodeTasks ts))
-
-
-sendHelp : String -> Maybe Value -> Cmd msg
-sendHelp action payload =
-    let
-        act =
-            [ ( "action", string action ) ]
-
-        pay =
-            payload
-                |> Maybe.map (\p -> [ ( "payload", p ) ])
-                |> Maybe.withDefault []
-    in
-    sendMessage <| object <| act ++ pay
+            sendMessage <| M.encodeInMsg (M.InMsgTasks (T.toMessage ts))
 
 
 recv : (InMessage -> msg) -> (String -> msg) -> Sub msg
@@ -62,37 +44,15 @@ recv onMessage onError =
 
 decodeIncomingMessage : Value -> Result String InMessage
 decodeIncomingMessage value =
-    case Json.Decode.decodeValue decodeAction value of
-        Ok action ->
-            case action.action of
-                "new_tasks" ->
-                    decodeTasks action.payload
-
-                unknown ->
-                    Err ("Unknown action: " ++ unknown)
-
-        Err e ->
-            Err ("Failed to decode action: " ++ errorToString e)
+    case Json.Decode.decodeValue M.decodeOutMsg value of
+        Ok (M.OutMsgNewTasks tasks) ->
+            Ok (NewTasks (T.fromMessage tasks))
 
+        Ok (M.OutMsgRejected rejection) ->
+            Err rejection.message
 
-decodeTasks : Value -> Result String InMessage
-decodeTasks value =
-    case Json.Decode.decodeValue T.decodeTasks value of
-        Ok tasks ->
-            Ok (NewTasks tasks)
+        Ok (M.OutMsgRestarting restart) ->
+            Err restart.message
 
         Err e ->
-            Err ("Failed to decode tasks payload: " ++ errorToString e)
-
-
-type alias Action =
-    { action : String
-    , payload : Value
-    }
-
-
-decodeAction : Json.Decode.Decoder Action
-decodeAction =
-    map2 Action
-        (field "action" Json.Decode.string)
-        (field "payload" Json.Decode.value)
+            Err ("Failed to decode message: " ++ errorToString e)
@@ -12,15 +12,18 @@ module Tasks exposing
     , encodeTaskId
     , encodeTasks
     , find
+    , fromMessage
     , generateTaskId
     , newTask
     , taskIdFromString
     , taskIdToString
+    , toMessage
     )
 
 import Dict exposing (Dict)
 import Json.Decode as Decode exposing (Decoder)
 import Json.Encode as Encode exposing (Value)
+import Messages
 import Random
 
 
@@ -44,7 +47,7 @@ encodeTaskId (TaskId id) =
 
 generateTaskId : Random.Seed -> ( TaskId, Random.Seed )
 generateTaskId seed =
-    Random.step (Random.int 1 0xFFFFFFFF) seed |> Tuple.mapFirst TaskId
+    Random.step (Random.int 1 Random.maxInt) seed |> Tuple.mapFirst TaskId
 
 
 taskIdToString : TaskId -> String
@@ -81,12 +84,15 @@ type Tasks
 
 type alias Tasks_ =
     { tasks : Dict Int Task
+
+    -- Kept as the server sent it, so its ids never go backwards
+    , nextId : Int
     }
 
 
 empty : Tasks
 empty =
-    Tasks { tasks = Dict.empty }
+    Tasks { tasks = Dict.empty, nextId = 0 }
 
 
 newTask : Tasks -> Random.Seed -> Task -> ( Tasks, Random.Seed )
@@ -97,7 +103,7 @@ newTask (Tasks tasks) seed task =
     in
     case Dict.get id tasks.tasks of
         Nothing ->
-            ( Tasks { tasks = Dict.insert id task tasks.tasks }, newSeed )
+            ( Tasks { tasks | tasks = Dict.insert id task tasks.tasks }, newSeed )
 
         Just _ ->
             newTask (Tasks tasks) newSeed task
@@ -127,10 +133,8 @@ encodeTask task =
 
 
 encodeTasks : Tasks -> Value
-encodeTasks (Tasks tasks) =
-    Encode.object
-        [ ( "tasks", Encode.dict String.fromInt encodeTask tasks.tasks )
-        ]
+encodeTasks =
+    toMessage >> Messages.encodeTasks
 
 
 
@@ -144,40 +148,31 @@ decodeTask =
 
 decodeTasks : Decoder Tasks
 decodeTasks =
-    Decode.map Tasks <|
-        Decode.map Tasks_ <|
-            Decode.andThen
-                (\maybeDict ->
-                    case maybeDict of
-                        Just dict ->
-                            Decode.succeed dict
-
-                        Nothing ->
-                            Decode.fail "Failed to decode tasks"
-                )
-            <|
-                Decode.map
-                    mapKeys
-                <|
-                    Decode.field "tasks" (Decode.dict decodeTask)
-
-
-mapKeys : Dict String Task -> Maybe (Dict Int Task)
-mapKeys tasks =
-    Dict.toList tasks
-        |> List.map
-            (\( k, v ) ->
-                String.toInt k |> Maybe.map (\key -> ( key, v ))
-            )
-        |> List.foldl
-            (\a ->
-                \b ->
-                    case ( a, b ) of
-                        ( Just c, Just d ) ->
-                            Just (c :: d)
-
-                        _ ->
-                            Nothing
-            )
-            (Just [])
-        |> Maybe.map Dict.fromList
+    Decode.map fromMessage Messages.decodeTasks
+
+
+
+-- MESSAGES
+
+
+{-| The tasks as they are sent to and from the server.
+-}
+toMessage : Tasks -> Messages.Tasks
+toMessage (Tasks tasks) =
+    { tasks =
+        Dict.toList tasks.tasks
+            |> List.map (\( id, task ) -> { id = id, summary = task.summary })
+    , nextId = tasks.nextId
+    , version = 1
+    }
+
+
+fromMessage : Messages.Tasks -> Tasks
+fromMessage message =
+    Tasks
+        { tasks =
+            message.tasks
+                |> List.map (\task -> ( task.id, { summary = task.summary } ))
+                |> Dict.fromList
+        , nextId = message.nextId
+        }
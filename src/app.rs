@@ -1,58 +1,330 @@
 use axum::{
     Json, Router,
+    extract::Request,
     extract::{
-        FromRef, FromRequestParts, State,
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, FromRef, FromRequestParts, MatchedPath, Path, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
     },
-    http::{StatusCode, request::Parts},
+    http::{HeaderMap, StatusCode, header, request::Parts},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{any, post},
+    routing::{any, delete, get, post},
 };
 use axum_extra::{
     TypedHeader,
     extract::{
-        PrivateCookieJar,
+        CookieJar, PrivateCookieJar,
         cookie::{Cookie, Key, SameSite},
     },
     headers,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-use tracing::instrument;
+use sha2::{Digest, Sha256};
+use tokio::sync::{
+    Mutex,
+    mpsc::{self, error::TrySendError},
+    watch,
+};
+use tokio::task::JoinHandle;
+use tracing::{Span, instrument};
 
 use std::ops::ControlFlow;
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use tower_http::{
     services::{ServeDir, ServeFile},
-    trace::{DefaultMakeSpan, TraceLayer},
+    trace::TraceLayer,
 };
 
 use crate::auth::*;
+use crate::csrf::*;
+use crate::password::*;
+use crate::policy::*;
 use futures_util::{
-    sink::SinkExt,
-    stream::{SplitSink, StreamExt},
+    sink::{Sink, SinkExt},
+    stream::StreamExt,
 };
 
-type WsSender = Arc<Mutex<SplitSink<WebSocket, Message>>>;
+mod backup;
+mod dav;
+mod elm;
+mod encoding;
+mod export;
+mod health;
+mod ical;
+mod import;
+mod metrics;
+mod migrations;
+mod openapi;
+mod paths;
+mod shutdown;
+mod store;
+mod tasks_api;
+
+pub use backup::Backup;
+pub use elm::{elm_module, elm_module_path};
+use encoding::Encoding;
+pub use shutdown::Shutdown;
+
+type ConnectionId = u64;
+
+/// How many messages can wait for a connection before it's dropped for falling behind. Every
+/// message has all of the user's tasks, so a connection that far behind is better off reconnecting.
+const OUTBOX_CAPACITY: usize = 32;
+
+/// How long to wait for a connection that's being closed to take its close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Queues messages for a websocket, which a task of its own writes out, so nobody sending to it
+/// waits on a slow or stuck browser.
+#[derive(Clone)]
+struct Outbox {
+    queue: mpsc::Sender<Message>,
+    close: Arc<watch::Sender<Option<CloseFrame>>>,
+}
+
+impl Outbox {
+    /// Starts writing to the sink, with a task that finishes once the outbox is closed or dropped.
+    fn open<S>(sink: S) -> (Self, JoinHandle<()>)
+    where
+        S: Sink<Message> + Send + Unpin + 'static,
+        S::Error: std::fmt::Display,
+    {
+        let (queue, messages) = mpsc::channel(OUTBOX_CAPACITY);
+        let close = Arc::new(watch::channel(None).0);
+        let writer = tokio::spawn(write_outbox(sink, messages, close.clone()));
+        (Self { queue, close }, writer)
+    }
+
+    /// Queues a message, or closes the connection if its queue is full. Returns whether the
+    /// message will be sent.
+    fn send(&self, message: Message) -> bool {
+        if self.close.borrow().is_some() {
+            return false;
+        }
+        match self.queue.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.close(CloseFrame {
+                    code: close_code::AGAIN,
+                    reason: "fell behind, reconnect to catch up".into(),
+                });
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Sends a close frame ahead of anything still queued, and stops sending.
+    fn close(&self, frame: CloseFrame) {
+        self.close.send_if_modified(|close| {
+            let first = close.is_none();
+            close.get_or_insert(frame);
+            first
+        });
+    }
+
+    /// Resolves once the connection has been closed with [`Outbox::close`].
+    async fn closed(&self) -> Option<CloseFrame> {
+        closed(&self.close).await
+    }
+}
+
+async fn closed(close: &watch::Sender<Option<CloseFrame>>) -> Option<CloseFrame> {
+    let mut closing = close.subscribe();
+    let frame = closing.wait_for(Option::is_some).await.ok()?;
+    frame.clone()
+}
+
+/// Writes an [`Outbox`] to its websocket, until it's closed or everyone has dropped it.
+async fn write_outbox<S>(
+    mut sink: S,
+    mut messages: mpsc::Receiver<Message>,
+    close: Arc<watch::Sender<Option<CloseFrame>>>,
+) where
+    S: Sink<Message> + Unpin,
+    S::Error: std::fmt::Display,
+{
+    let frame = loop {
+        let message = tokio::select! {
+            biased;
+            frame = closed(&close) => break frame,
+            message = messages.recv() => match message {
+                Some(message) => message,
+                None => return,
+            },
+        };
+        tokio::select! {
+            biased;
+            frame = closed(&close) => break frame,
+            sent = sink.send(message) => if let Err(e) = sent {
+                tracing::debug!("Failed to write to a websocket: {}", e);
+                return;
+            },
+        }
+    };
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, sink.send(Message::Close(frame))).await;
+}
+
+/// How often the server pings each websocket, and how many unanswered pings it waits for before
+/// deciding the connection is dead. Without this, a connection that drops without closing (a
+/// laptop going to sleep, say) stays registered forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub missed_pongs: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            missed_pongs: 2,
+        }
+    }
+}
+
+/// The most a client can send over a websocket, or store any other way. Bigger messages are
+/// refused, and the connection closed, so one client can't make the server store and send
+/// everyone something huge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// In bytes
+    pub max_message_size: usize,
+    pub max_tasks: usize,
+    /// In characters
+    pub max_summary_length: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 1024 * 1024,
+            max_tasks: 10_000,
+            max_summary_length: 1000,
+        }
+    }
+}
+
+impl MessageLimits {
+    fn check(&self, tasks: &Tasks) -> Result<(), Rejection> {
+        if tasks.tasks.len() > self.max_tasks {
+            return Err(Rejection {
+                error: MessageError::TooManyTasks,
+                message: format!("There can't be more than {} tasks", self.max_tasks),
+            });
+        }
+        let too_long = |task: &&Task| self.summary_too_long(&task.summary);
+        if let Some(task) = tasks.tasks.iter().find(too_long) {
+            return Err(Rejection {
+                error: MessageError::SummaryTooLong,
+                message: format!(
+                    "Task {} is longer than {} characters",
+                    task.id, self.max_summary_length
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn summary_too_long(&self, summary: &str) -> bool {
+        summary.chars().count() > self.max_summary_length
+    }
+}
 
 #[derive(Clone)]
 struct AppState {
-    tasks: Arc<Mutex<HashMap<UserId, Tasks>>>,
-    clients: Arc<Mutex<HashMap<SessionId, WsSender>>>,
+    store: store::Store,
     users: Arc<Mutex<Users>>,
+    account_policy: Arc<AccountPolicy>,
+    allowed_origins: Arc<Vec<String>>,
+    /// A hash of the operator's token for `/api/admin`, which is turned off without one
+    admin_token: Option<[u8; 32]>,
+    heartbeat: Heartbeat,
+    message_limits: MessageLimits,
+    /// Marks cookies `Secure`, for servers behind HTTPS
+    secure_cookies: bool,
+    /// Set to how long clients should wait before reconnecting once the server is shutting down.
+    /// Every websocket holds a receiver, so shutting down can wait for them to finish.
+    restarting: Arc<watch::Sender<Option<Duration>>>,
     key: Key,
+    /// Keys cookies used to be encrypted with, which are still accepted
+    retired_keys: Arc<Vec<Key>>,
+    metrics: Arc<metrics::Metrics>,
+    /// A hash of the token `/metrics` asks for, if any
+    metrics_token: Option<[u8; 32]>,
 }
 
 impl AppState {
     pub fn new(key: [u8; 64]) -> Self {
         let key = Key::from(&key);
+        let metrics = Arc::new(metrics::Metrics::default());
         Self {
-            tasks: Arc::new(Mutex::new(HashMap::new())),
-            clients: Arc::new(Mutex::new(HashMap::new())),
+            store: store::Store::new(metrics.broadcasts.clone()),
             users: Arc::new(Mutex::new(Users::default())),
+            account_policy: Arc::new(AccountPolicy::default()),
+            allowed_origins: Arc::new(Vec::new()),
+            admin_token: None,
+            heartbeat: Heartbeat::default(),
+            message_limits: MessageLimits::default(),
+            secure_cookies: false,
+            restarting: Arc::new(watch::channel(None).0),
             key,
+            retired_keys: Arc::new(Vec::new()),
+            metrics,
+            metrics_token: None,
         }
     }
+
+    pub fn with_account_policy(mut self, account_policy: AccountPolicy) -> Self {
+        self.account_policy = Arc::new(account_policy);
+        self
+    }
+
+    /// Origins other than the server's own that are allowed to make authenticated requests.
+    pub fn with_allowed_origins(mut self, allowed_origins: Vec<String>) -> Self {
+        self.allowed_origins = Arc::new(allowed_origins);
+        self
+    }
+
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token.map(|token| Sha256::digest(token.as_bytes()).into());
+        self
+    }
+
+    pub fn with_metrics_token(mut self, metrics_token: Option<String>) -> Self {
+        self.metrics_token = metrics_token.map(|token| Sha256::digest(token.as_bytes()).into());
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn with_message_limits(mut self, message_limits: MessageLimits) -> Self {
+        self.message_limits = message_limits;
+        self
+    }
+
+    /// Keeps sessions from before the cookie key changed, which are moved over to the new key
+    /// the next time they're used.
+    pub fn with_retired_keys(mut self, retired_keys: Vec<[u8; 64]>) -> Self {
+        self.retired_keys = Arc::new(retired_keys.iter().map(|key| Key::from(key)).collect());
+        self
+    }
+
+    pub fn with_secure_cookies(mut self, secure_cookies: bool) -> Self {
+        self.secure_cookies = secure_cookies;
+        self
+    }
+
+    /// Starts with the users and tasks from a backup, instead of with nothing.
+    pub fn with_backup(mut self, backup: Backup) -> Self {
+        self.users = Arc::new(Mutex::new(backup.users));
+        self.store = store::Store::from_tasks(backup.tasks, self.metrics.broadcasts.clone());
+        self
+    }
 }
 
 impl FromRef<AppState> for Key {
@@ -64,42 +336,187 @@ impl FromRef<AppState> for Key {
 pub struct Env {
     pub port: u16,
     pub host: String,
-    pub cookie_secret: String,
+    /// The Elm app and other static files
+    pub assets_dir: PathBuf,
+    pub cookie_key: [u8; 64],
+    pub retired_cookie_keys: Vec<[u8; 64]>,
+    pub cookie_secure: bool,
+    pub account_policy: AccountPolicy,
+    pub allowed_origins: Vec<String>,
+    /// Lets operators use `/api/admin`
+    pub admin_token: Option<String>,
+    pub restore: Option<Backup>,
+    pub heartbeat: Heartbeat,
+    pub message_limits: MessageLimits,
+    pub shutdown: Shutdown,
+    /// Where users and tasks are saved on shutdown
+    pub state_file: Option<PathBuf>,
+    /// Serves `/metrics` here instead of alongside the app
+    pub metrics_address: Option<SocketAddr>,
+    /// Has `/metrics` ask for this bearer token
+    pub metrics_token: Option<String>,
 }
 
-pub async fn run_app(env: Env) {
-    let assets_dir = PathBuf::from(".").join("assets");
-
-    let key = env.cookie_secret.as_bytes().first_chunk().unwrap();
-    let app_state = AppState::new(*key);
+pub async fn run_app(env: Env) -> std::io::Result<()> {
+    let mut app_state = AppState::new(env.cookie_key)
+        .with_account_policy(env.account_policy)
+        .with_allowed_origins(env.allowed_origins)
+        .with_admin_token(env.admin_token)
+        .with_metrics_token(env.metrics_token)
+        .with_heartbeat(env.heartbeat)
+        .with_message_limits(env.message_limits)
+        .with_secure_cookies(env.cookie_secure)
+        .with_retired_keys(env.retired_cookie_keys);
+    if let Some(backup) = env.restore {
+        tracing::info!("Restored users and tasks from a backup");
+        app_state = app_state.with_backup(backup);
+    }
 
-    let app = make_app(assets_dir, app_state);
+    let mut app = make_app(env.assets_dir, app_state.clone());
+    match env.metrics_address {
+        Some(address) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            tracing::debug!("serving metrics on http://{}", listener.local_addr()?);
+            let metrics = metrics::routes(app_state.clone());
+            tokio::spawn(async move {
+                if let Err(error) = axum::serve(listener, metrics).await {
+                    tracing::error!("Stopped serving metrics: {error}");
+                }
+            });
+        }
+        None => app = app.merge(metrics::routes(app_state.clone())),
+    }
 
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", env.host, env.port))
-        .await
-        .unwrap();
-    tracing::debug!("listening on http://{}", listener.local_addr().unwrap());
-    axum::serve(
+    let listener = tokio::net::TcpListener::bind((env.host.as_str(), env.port)).await?;
+    tracing::debug!("listening on http://{}", listener.local_addr()?);
+    shutdown::serve(
         listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
+        app,
+        app_state,
+        shutdown::signal(),
+        env.shutdown,
+        env.state_file,
     )
-    .await
-    .unwrap();
+    .await;
+    Ok(())
 }
 
 fn make_app(assets_dir: PathBuf, app_state: AppState) -> Router {
     Router::new()
-        .route("/", axum::routing::get_service(ServeFile::new(assets_dir.join("index.html"))))
+        .route(
+            paths::INDEX,
+            axum::routing::get_service(ServeFile::new(assets_dir.join("index.html"))),
+        )
         .fallback_service(ServeDir::new(&assets_dir))
-        .route("/ws", any(ws_handler))
-        .route("/api/register", post(handle_register))
-        .route("/api/login", post(handle_login))
-        .route("/api/logout", post(handle_logout))
-        .with_state(app_state)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::default().include_headers(false)),
+        .route(paths::WEBSOCKET, any(ws_handler))
+        .route(paths::REGISTER, post(handle_register))
+        .route(paths::LOGIN, post(handle_login))
+        .route(paths::LOGOUT, post(handle_logout))
+        .route(
+            paths::SESSIONS,
+            get(handle_list_sessions).delete(handle_logout_everywhere),
         )
+        .route(paths::SESSION, delete(handle_revoke_session))
+        .route(
+            paths::TOKENS,
+            get(handle_list_tokens).post(handle_create_token),
+        )
+        .route(paths::TOKEN, delete(handle_revoke_token))
+        .merge(tasks_api::routes())
+        .merge(export::routes())
+        .merge(import::routes())
+        .merge(ical::routes())
+        .merge(dav::routes())
+        .merge(backup::routes())
+        .merge(openapi::routes())
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            origin_protection,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            reissue_session_cookie,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            metrics::track_requests,
+        ))
+        .with_state(app_state.clone())
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        // After the trace layer, so probes every few seconds don't fill the logs
+        .merge(health::routes(app_state))
+}
+
+/// Like tower-http's `DefaultMakeSpan`, but with the route instead of the URI. Paths can hold
+/// secrets, like the calendar feed's, which mustn't end up in the logs.
+fn request_span(request: &Request) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str);
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        route,
+        version = ?request.version(),
+    )
+}
+
+/// Rejects unsafe requests from pages on other origins. Browsers send the session cookie with
+/// any request to this server, wherever the page making it came from.
+async fn origin_protection(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.method().is_safe() && !origin_allowed(request.headers(), &state.allowed_origins) {
+        tracing::warn!(
+            "Rejected cross-origin {} {}",
+            request.method(),
+            request.uri()
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+/// Filled in by [`AuthedUser`] when a valid session cookie was encrypted with a retired key.
+#[derive(Debug, Clone, Default)]
+struct ReissueSession(Arc<std::sync::OnceLock<SessionId>>);
+
+/// Sends session cookies encrypted with a retired key back encrypted with the current one, once
+/// they've been used successfully.
+async fn reissue_session_cookie(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if state.retired_keys.is_empty() {
+        return next.run(request).await;
+    }
+    let reissue = ReissueSession::default();
+    request.extensions_mut().insert(reissue.clone());
+    let response = next.run(request).await;
+    match reissue.0.get() {
+        Some(session_id) if response.status().is_success() => {
+            let cookie = session_cookie(*session_id, state.secure_cookies);
+            (
+                PrivateCookieJar::new(state.key.clone()).add(cookie),
+                response,
+            )
+                .into_response()
+        }
+        _ => response,
+    }
+}
+
+fn session_cookie(session_id: SessionId, secure: bool) -> Cookie<'static> {
+    let mut cookie = Cookie::new("session", format!("{session_id}"));
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_secure(secure);
+    cookie
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -121,15 +538,35 @@ impl FromRequestParts<AppState> for AuthedUser {
         let jar = PrivateCookieJar::<Key>::from_request_parts(parts, state)
             .await
             .map_err(|_| StatusCode::UNAUTHORIZED)?;
-        let cookie = jar.get("session").ok_or(StatusCode::UNAUTHORIZED)?;
+        // A cross-site page can make the browser send the cookie, but can't read the CSRF token
+        let plain_jar = CookieJar::from_headers(&parts.headers);
+        if !parts.method.is_safe() && !csrf_token_matches(&parts.headers, &plain_jar) {
+            tracing::warn!(
+                "Rejected {} {} without a CSRF token",
+                parts.method,
+                parts.uri
+            );
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let (cookie, retired) = match jar.get("session") {
+            Some(cookie) => (cookie, false),
+            None => {
+                let cookie = state.retired_keys.iter().find_map(|key| {
+                    PrivateCookieJar::from_headers(&parts.headers, key.clone()).get("session")
+                });
+                (cookie.ok_or(StatusCode::UNAUTHORIZED)?, true)
+            }
+        };
         let session = cookie.value();
         let session_id = session.parse().map_err(|_| StatusCode::UNAUTHORIZED)?;
-        let user_id = state
-            .users
-            .lock()
-            .await
+        let mut users = state.users.lock().await;
+        let user_id = users
             .get_session(session_id)
             .ok_or(StatusCode::UNAUTHORIZED)?;
+        users.touch_session(session_id, None);
+        if retired && let Some(ReissueSession(reissue)) = parts.extensions.get() {
+            let _ = reissue.set(session_id);
+        }
         Ok(AuthedUser {
             session_id,
             user_id,
@@ -137,220 +574,406 @@ impl FromRequestParts<AppState> for AuthedUser {
     }
 }
 
+/// A request authenticated with an API token in the `Authorization: Bearer` header.
+#[derive(Debug, Clone, Copy)]
+struct TokenUser {
+    token_id: TokenId,
+    user_id: UserId,
+    scope: Scope,
+}
+
+impl FromRequestParts<AppState> for TokenUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let TypedHeader(headers::Authorization(bearer)) = TypedHeader::<
+            headers::Authorization<headers::authorization::Bearer>,
+        >::from_request_parts(
+            parts, state
+        )
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let (token_id, user_id, scope) = state
+            .users
+            .lock()
+            .await
+            .use_token(bearer.token())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        Ok(TokenUser {
+            token_id,
+            user_id,
+            scope,
+        })
+    }
+}
+
+/// A request authenticated with either a session cookie or an API token.
+/// Sessions are allowed to do everything, tokens are limited to their scope.
+#[derive(Debug, Clone, Copy)]
+struct Caller {
+    user_id: UserId,
+    credential: Credential,
+    scope: Scope,
+}
+
+impl Caller {
+    fn require(self, scope: Scope) -> Result<Self, StatusCode> {
+        if self.scope.allows(scope) {
+            Ok(self)
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(header::AUTHORIZATION) {
+            let token = TokenUser::from_request_parts(parts, state).await?;
+            Ok(Caller {
+                user_id: token.user_id,
+                credential: Credential::Token(token.token_id),
+                scope: token.scope,
+            })
+        } else {
+            let session = AuthedUser::from_request_parts(parts, state).await?;
+            Ok(Caller {
+                user_id: session.user_id,
+                credential: Credential::Session(session.session_id),
+                scope: Scope::Admin,
+            })
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = TypedHeader::<headers::UserAgent>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .map(|TypedHeader(user_agent)| user_agent.to_string());
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(ClientInfo { user_agent, ip })
+    }
+}
+
 /// The handler for the HTTP request (this gets called when the HTTP request lands at the start
 /// of websocket negotiation). After this completes, the actual switching from HTTP to
 /// websocket protocol will occur.
 /// This is the last point where we can extract TCP/IP metadata such as IP address of the client
 /// as well as things from HTTP headers such as user-agent of the browser etc.
 #[axum::debug_handler]
-#[instrument(skip(ws, app_state, user_agent))]
+#[instrument(skip(ws, app_state, headers, client))]
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(app_state): State<AppState>,
-    user_agent: Option<TypedHeader<headers::UserAgent>>,
-    user: AuthedUser,
-) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
-        user_agent.to_string()
-    } else {
-        String::from("Unknown browser")
-    };
-    tracing::debug!("`{user_agent}` for session {} connected.", user.session_id);
-    ws.on_upgrade(move |socket| handle_socket(socket, user, app_state))
-}
-
-#[instrument(skip(socket, app_state))]
-async fn handle_socket(socket: WebSocket, session: AuthedUser, app_state: AppState) {
-    // By splitting socket we can send and receive at the same time. In this example we will send
-    // unsolicited messages to client based on some sort of server's internal event (i.e .timer).
-    let (sender, mut receiver) = socket.split();
-    let sender = Arc::new(Mutex::new(sender));
-
+    headers: HeaderMap,
+    client: ClientInfo,
+    caller: Caller,
+) -> Response {
+    // Websockets aren't covered by the same origin policy, so without this any site could
+    // connect with the user's cookie
+    if !origin_allowed(&headers, &app_state.allowed_origins) {
+        tracing::warn!("Rejected cross-origin websocket for {}", caller.credential);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if app_state.restarting.borrow().is_some() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let user_agent = client
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| String::from("Unknown browser"));
+    tracing::debug!("`{user_agent}` for {} connected.", caller.credential);
     app_state
-        .clients
+        .users
         .lock()
         .await
-        .insert(session.session_id, sender.clone());
+        .touch(caller.credential, Some(client));
+    let (ws, encoding) = Encoding::negotiate(ws);
+    // Messages a little over the limit are read, so they can be refused with a reason. Ones far
+    // over it are cut off while they're read, which just drops the connection.
+    let hard_limit = app_state.message_limits.max_message_size.saturating_mul(2);
+    let ws = ws.max_message_size(hard_limit).max_frame_size(hard_limit);
+    ws.on_upgrade(move |socket| handle_socket(socket, caller, encoding, app_state))
+        .into_response()
+}
 
-    // Send current tasks to the newly connected client
-    {
-        let tasks_map = app_state.tasks.lock().await;
-        let ts = Tasks::default();
-        let tasks = tasks_map.get(&session.user_id).unwrap_or(&ts);
-        let send = sender.lock().await;
-        let new_tasks = OutMsg::NewTasks(tasks.clone());
-        let sent = send_outmsg(send, new_tasks).await;
-        if let Err(e) = sent {
-            tracing::error!(
-                "Failed to send initial tasks to user {}: {}",
-                session.user_id,
-                e
-            );
-        }
+#[instrument(skip(socket, app_state))]
+async fn handle_socket(
+    socket: WebSocket,
+    session: Caller,
+    encoding: Encoding,
+    app_state: AppState,
+) {
+    // By splitting socket we can send and receive at the same time. Sending goes through the
+    // connection's outbox, so broadcasts to it never wait on the socket.
+    let (sender, mut receiver) = socket.split();
+    let (outbox, writer) = Outbox::open(sender);
+    // Held until the websocket is done with, so shutting down can wait for it
+    let restarting = app_state.restarting.subscribe();
+    let connection_id = app_state.store.lock(session.user_id).await.connect(
+        session.credential,
+        encoding,
+        outbox.clone(),
+    );
+    // Shutting down may have gone through the store before this was in it
+    if let Some(reconnect_in) = *restarting.borrow() {
+        shutdown::restart(&outbox, encoding, reconnect_in);
     }
 
-    // This second task will receive messages from client and print them on server console
-    let app_state_clone = app_state.clone();
-    let recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            process_message(msg, session, sender.clone(), app_state_clone.clone()).await?;
+    let Heartbeat {
+        interval,
+        missed_pongs: max_missed_pongs,
+    } = app_state.heartbeat;
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut missed_pongs = 0;
+    // Every user shares the lock touching takes, so it's done at most once a heartbeat. Connecting
+    // already touched the credential.
+    let mut touched = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            _ = outbox.closed() => break,
+            msg = receiver.next() => {
+                let Some(Ok(msg)) = msg else { break };
+                if let Message::Pong(_) = msg {
+                    missed_pongs = 0;
+                }
+                if touched.elapsed() >= interval {
+                    touched = tokio::time::Instant::now();
+                    app_state.users.lock().await.touch(session.credential, None);
+                }
+                let flow = process_message(msg, session, encoding, &outbox, app_state.clone()).await;
+                if flow.is_break() {
+                    break;
+                }
+            }
+            _ = heartbeat.tick() => {
+                if missed_pongs >= max_missed_pongs {
+                    tracing::info!(
+                        "Closing websocket for {}, which missed {missed_pongs} pongs",
+                        session.credential
+                    );
+                    outbox.close(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "missed heartbeats".into(),
+                    });
+                    break;
+                }
+                outbox.send(Message::Ping(Default::default()));
+                missed_pongs += 1;
+            }
         }
-        ControlFlow::Continue(())
-    });
-
-    let _ = recv_task.await.unwrap();
+    }
 
-    app_state.clients.lock().await.remove(&session.session_id);
+    app_state
+        .store
+        .lock(session.user_id)
+        .await
+        .disconnect(connection_id);
+    // The writer finishes what's queued once nobody else has the outbox
+    drop(outbox);
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, writer).await;
+    drop(restarting);
 
-    tracing::debug!(
-        "Websocket context for session {} destroyed",
-        session.session_id
-    );
+    tracing::debug!("Websocket context for {} destroyed", session.credential);
 }
 
-async fn send_outmsg(
-    mut send: tokio::sync::MutexGuard<'_, SplitSink<WebSocket, Message>>,
-    new_tasks: OutMsg,
-) -> Result<(), axum::Error> {
-    tracing::debug!(?new_tasks);
-    send.send(Message::Text(
-        serde_json::to_string(&new_tasks).unwrap().into(),
-    ))
-    .await
-}
+type TaskId = i32;
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
 struct Task {
-    id: i32,
+    id: TaskId,
     summary: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
-#[serde(rename_all = "snake_case")]
+/// Stored and sent in a versioned format, which is migrated when it's read. See [`migrations`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default, JsonSchema)]
+#[serde(try_from = "serde_json::Value", into = "migrations::CurrentTasks")]
+#[schemars(with = "migrations::CurrentTasks")]
 struct Tasks {
     tasks: Vec<Task>,
-    next_id: i32,
+    next_id: TaskId,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, JsonSchema)]
 #[serde(tag = "action", content = "payload", rename_all = "snake_case")]
 enum OutMsg {
     NewTasks(Tasks),
+    /// The client's last message was refused, and the server closes the connection after this.
+    Rejected(Rejection),
+    /// The server is shutting down, and closes the connection after this.
+    Restarting(Restart),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-#[serde(tag = "action", content = "payload", rename_all = "snake_case")]
-enum InMsg {
-    Tasks(Tasks),
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, JsonSchema)]
+struct Rejection {
+    error: MessageError,
+    message: String,
 }
 
-type Shared<T> = Arc<Mutex<T>>;
-
-#[instrument(skip(app_state))]
-async fn broadcast_tasks(app_state: &AppState, user_id: u64) {
-    let tasks_stored = app_state.tasks.lock().await;
-    let tasks = match tasks_stored.get(&user_id) {
-        Some(tasks) => tasks,
-        None => return,
-    };
-    let message = Message::Text(
-        serde_json::to_string(&OutMsg::NewTasks(tasks.clone()))
-            .unwrap()
-            .into(),
-    );
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, JsonSchema)]
+struct Restart {
+    /// In seconds
+    reconnect_in: u64,
+    message: String,
+}
 
-    let sessions = app_state.users.lock().await.get_sessions(user_id);
-    let clients = app_state.clients.lock().await;
-    let user_clients = clients
-        .iter()
-        .filter(|(entry_session, _)| sessions.contains(entry_session));
-    for (session_id, sender) in user_clients {
-        let mut send = sender.lock().await;
-        if let Err(e) = send.send(message.clone()).await {
-            tracing::error!(
-                "Failed to send tasks update to session {}: {}",
-                session_id,
-                e
-            );
-        }
-    }
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum MessageError {
+    TooLarge,
+    TooManyTasks,
+    SummaryTooLong,
+}
 
-    tracing::debug!(
-        "Broadcasted tasks to {} clients: {} tasks, next_id: {}",
-        sessions.len(),
-        tasks.tasks.len(),
-        tasks.next_id
-    );
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "action", content = "payload", rename_all = "snake_case")]
+enum InMsg {
+    Tasks(Tasks),
 }
 
-/// helper to print contents of messages to stdout. Has special treatment for Close.
-#[instrument(skip(_sender, app_state))]
+/// Handles a message from a client, breaking once the connection should be closed.
+#[instrument(skip(outbox, app_state))]
 async fn process_message(
     msg: Message,
-    session: AuthedUser,
-    _sender: Shared<SplitSink<WebSocket, Message>>,
+    session: Caller,
+    encoding: Encoding,
+    outbox: &Outbox,
     app_state: AppState,
 ) -> ControlFlow<(), ()> {
-    match msg {
-        Message::Text(t) => {
-            let k = serde_json::from_str::<InMsg>(t.as_str());
-
-            match k {
-                Ok(InMsg::Tasks(client_tasks)) => {
-                    // Client is source of truth - replace server state with client state
-                    {
-                        let mut current_state = app_state.tasks.lock().await;
-                        current_state.insert(session.user_id, client_tasks);
-                    }
-
-                    // Broadcast the updated tasks to ALL connected clients
-                    broadcast_tasks(&app_state, session.user_id).await;
-                }
-                Err(e) => {
-                    tracing::error!(
-                        "Unhandled message from session {}: {}",
-                        session.session_id,
-                        e
-                    );
-                }
-            }
-        }
-        Message::Binary(d) => {
-            println!(
-                ">>> session {} sent {} bytes: {d:?}",
-                session.session_id,
-                d.len()
-            );
-        }
+    let (sent_in, data) = match &msg {
+        Message::Text(t) => (Encoding::Json, t.as_bytes()),
+        Message::Binary(d) => (Encoding::MessagePack, &d[..]),
         Message::Close(c) => {
             if let Some(cf) = c {
-                println!(
-                    ">>> session {} sent close with code {} and reason `{}`",
-                    session.session_id, cf.code, cf.reason
+                tracing::debug!(
+                    "{} sent close with code {} and reason `{}`",
+                    session.credential,
+                    cf.code,
+                    cf.reason
                 );
             } else {
-                println!(
-                    ">>> user {} somehow sent close message without CloseFrame",
-                    session.user_id
-                );
+                tracing::debug!("{} sent close without a close frame", session.credential);
             }
             return ControlFlow::Break(());
         }
 
+        // Pongs answer the heartbeat in `handle_socket`
         Message::Pong(v) => {
-            println!(">>> session {} sent pong with {v:?}", session.user_id);
+            tracing::trace!("{} sent pong with {v:?}", session.credential);
+            return ControlFlow::Continue(());
         }
         // You should never need to manually handle Message::Ping, as axum's websocket library
         // will do so for you automagically by replying with Pong and copying the v according to
         // spec. But if you need the contents of the pings you can see them here.
         Message::Ping(v) => {
-            println!(">>> session {} sent ping with {v:?}", session.user_id);
+            tracing::trace!("{} sent ping with {v:?}", session.credential);
+            return ControlFlow::Continue(());
         }
+    };
+
+    let limits = app_state.message_limits;
+    if data.len() > limits.max_message_size {
+        let rejection = Rejection {
+            error: MessageError::TooLarge,
+            message: format!(
+                "Messages can't be bigger than {} bytes",
+                limits.max_message_size
+            ),
+        };
+        count_message(&app_state, "rejected");
+        return reject(session, encoding, outbox, rejection);
     }
+    let msg = sent_in.decode(data);
+    if let Ok(InMsg::Tasks(tasks)) = &msg
+        && let Err(rejection) = limits.check(tasks)
+    {
+        count_message(&app_state, "rejected");
+        return reject(session, encoding, outbox, rejection);
+    }
+    let kind = match &msg {
+        Ok(InMsg::Tasks(_)) => "tasks",
+        Err(_) => "invalid",
+    };
+    count_message(&app_state, kind);
+    receive_inmsg(msg, session, app_state).await;
     ControlFlow::Continue(())
 }
 
-#[derive(Debug, Deserialize)]
+fn count_message(app_state: &AppState, kind: &'static str) {
+    let labels = metrics::MessageLabels { kind };
+    app_state.metrics.messages.get_or_create(&labels).inc();
+}
+
+/// Tells the client why its message was refused, and closes the connection.
+fn reject(
+    session: Caller,
+    encoding: Encoding,
+    outbox: &Outbox,
+    rejection: Rejection,
+) -> ControlFlow<(), ()> {
+    tracing::warn!(
+        "Refused a message from {}: {}",
+        session.credential,
+        rejection.message
+    );
+    let (code, reason) = match rejection.error {
+        MessageError::TooLarge => (close_code::SIZE, "message too big"),
+        MessageError::TooManyTasks => (close_code::POLICY, "too many tasks"),
+        MessageError::SummaryTooLong => (close_code::POLICY, "summary too long"),
+    };
+    outbox.send(encoding.encode(&OutMsg::Rejected(rejection)));
+    // Queued after the rejection, unlike `Outbox::close`, so it's sent first
+    outbox.send(Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    })));
+    ControlFlow::Break(())
+}
+
+async fn receive_inmsg(msg: Result<InMsg, String>, session: Caller, app_state: AppState) {
+    match msg {
+        Ok(InMsg::Tasks(_)) if !session.scope.allows(Scope::Write) => {
+            tracing::warn!(
+                "Ignoring tasks from {}, which is not allowed to write",
+                session.credential
+            );
+        }
+        Ok(InMsg::Tasks(client_tasks)) => {
+            // Client is source of truth - replace server state with client state
+            let mut shard = app_state.store.lock(session.user_id).await;
+            // Except for the next id, so ids of deleted tasks aren't handed out again
+            let next_id = shard.tasks.next_id.max(client_tasks.next_id);
+            shard.tasks = Tasks {
+                next_id,
+                ..client_tasks
+            };
+
+            // Broadcast the updated tasks to ALL connected clients
+            shard.broadcast();
+        }
+        Err(e) => {
+            tracing::error!("Unhandled message from {}: {}", session.credential, e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct RegisterRequest {
     username: String,
     password: String,
@@ -361,14 +984,69 @@ struct RegisterRequest {
 async fn handle_register(
     State(state): State<AppState>,
     Json(req): Json<RegisterRequest>,
-) -> impl IntoResponse {
-    let mut users = state.users.lock().await;
-    match UserData::new(req.username, req.password)
-        .ok()
-        .and_then(|user| users.try_add(user))
-    {
-        Some(_) => StatusCode::CREATED,
-        None => StatusCode::CONFLICT,
+) -> Response {
+    let policy = state.account_policy.clone();
+    let result = match policy.check(&req.username, &req.password) {
+        Ok(username) => {
+            // Hash before taking the lock, so a slow hash doesn't hold up everyone else
+            let user = tokio::task::spawn_blocking(move || UserData::new(username, req.password))
+                .await
+                .unwrap_or(Err(AccountCreationError::HashingFailed));
+            match user {
+                Ok(user) => state.users.lock().await.try_add(user),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => StatusCode::CREATED.into_response(),
+        Err(error) => {
+            let status = match error {
+                AccountCreationError::UsernameTaken => StatusCode::CONFLICT,
+                AccountCreationError::HashingFailed => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            let body = ErrorResponse {
+                error,
+                message: policy.describe(error),
+            };
+            (status, Json(body)).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct ErrorResponse<E> {
+    error: E,
+    message: String,
+}
+
+/// Checks a username and password, upgrading the stored hash if it uses old parameters.
+/// Unknown usernames take as long to check as known ones, so they can't be told apart.
+async fn verify_login(state: &AppState, username: &str, password: String) -> Option<UserId> {
+    let login = state.users.lock().await.find_login(username);
+    let checked = tokio::task::spawn_blocking(move || match login {
+        Some((user_id, pass_hash)) => {
+            let check = check_password(&password, &pass_hash);
+            let rehashed = match check {
+                PasswordCheck::ValidNeedsRehash => hash_password(&password).ok(),
+                _ => None,
+            };
+            (Some(user_id), check, rehashed)
+        }
+        None => (None, check_no_password(&password), None),
+    })
+    .await;
+    match checked {
+        Ok((Some(user_id), PasswordCheck::Valid | PasswordCheck::ValidNeedsRehash, rehashed)) => {
+            if let Some(pass_hash) = rehashed {
+                tracing::info!("Upgraded password hash for user {user_id}");
+                state.users.lock().await.set_pass_hash(user_id, pass_hash);
+            }
+            Some(user_id)
+        }
+        _ => None,
     }
 }
 
@@ -377,17 +1055,23 @@ async fn handle_register(
 async fn handle_login(
     State(state): State<AppState>,
     jar: PrivateCookieJar,
+    plain_jar: CookieJar,
+    client: ClientInfo,
     Json(req): Json<RegisterRequest>,
 ) -> Response {
-    let mut users = state.users.lock().await;
-    match users.try_login(req.username, req.password) {
+    let session_id = match verify_login(&state, &req.username, req.password).await {
+        Some(user_id) => Some(state.users.lock().await.start_session(user_id, client)),
+        None => None,
+    };
+    let labels = metrics::LoginLabels {
+        success: session_id.is_some(),
+    };
+    state.metrics.logins.get_or_create(&labels).inc();
+    match session_id {
         Some(session_id) => {
-            let mut cookie = Cookie::new("session", format!("{session_id}"));
-            cookie.set_path("/");
-            cookie.set_http_only(true);
-            cookie.set_same_site(SameSite::Strict);
-            let jar = jar.add(cookie);
-            (jar, StatusCode::OK).into_response()
+            let jar = jar.add(session_cookie(session_id, state.secure_cookies));
+            let plain_jar = plain_jar.add(new_csrf_cookie(state.secure_cookies));
+            (jar, plain_jar, StatusCode::OK).into_response()
         }
         None => StatusCode::UNAUTHORIZED.into_response(),
     }
@@ -398,27 +1082,208 @@ async fn handle_login(
 async fn handle_logout(
     State(state): State<AppState>,
     jar: PrivateCookieJar,
+    plain_jar: CookieJar,
     session: AuthedUser,
 ) -> impl IntoResponse {
-    let mut users = state.users.lock().await;
-    users.logout_session(session.session_id);
+    state.users.lock().await.logout_session(session.session_id);
+    close_connections(
+        &state,
+        session.user_id,
+        &[Credential::Session(session.session_id)],
+    )
+    .await;
     let jar = jar.remove("session");
-    (jar, StatusCode::NO_CONTENT).into_response()
+    let plain_jar = plain_jar.remove(CSRF_COOKIE);
+    (jar, plain_jar, StatusCode::NO_CONTENT).into_response()
 }
 
-#[cfg(test)]
-mod tests {
-    use axum::http::StatusCode;
-    use axum_test::{TestServer, Transport};
-    #[allow(unused_imports)]
-    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
-    use serde_json::json;
-    use std::time::Duration;
-    use tokio::time::timeout;
-
-    use super::*;
+#[derive(Debug, Serialize, JsonSchema)]
+struct SessionResponse {
+    /// Session ids don't fit in a javascript number, so they are sent as strings
+    id: String,
+    current: bool,
+    #[serde(flatten)]
+    data: SessionData,
+}
 
-    #[tokio::test]
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_list_sessions(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<Json<Vec<SessionResponse>>, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let sessions = state
+        .users
+        .lock()
+        .await
+        .list_sessions(caller.user_id)
+        .into_iter()
+        .map(|(id, data)| SessionResponse {
+            id: id.to_string(),
+            current: caller.credential == Credential::Session(id),
+            data,
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_revoke_session(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    plain_jar: CookieJar,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let revoked = id.parse::<SessionId>().map_err(|_| StatusCode::NOT_FOUND)?;
+    {
+        let mut users = state.users.lock().await;
+        if users.get_session(revoked) != Some(caller.user_id) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        users.logout_session(revoked);
+    }
+    let revoked = Credential::Session(revoked);
+    close_connections(&state, caller.user_id, &[revoked]).await;
+    if revoked == caller.credential {
+        let jar = jar.remove("session");
+        let plain_jar = plain_jar.remove(CSRF_COOKIE);
+        Ok((jar, plain_jar, StatusCode::NO_CONTENT).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_logout_everywhere(
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    plain_jar: CookieJar,
+    caller: Caller,
+) -> Result<impl IntoResponse, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let revoked: Vec<_> = state
+        .users
+        .lock()
+        .await
+        .logout_all(caller.user_id)
+        .into_iter()
+        .map(Credential::Session)
+        .collect();
+    close_connections(&state, caller.user_id, &revoked).await;
+    let jar = jar.remove("session");
+    let plain_jar = plain_jar.remove(CSRF_COOKIE);
+    Ok((jar, plain_jar, StatusCode::NO_CONTENT))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct CreateTokenRequest {
+    name: String,
+    scope: Scope,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct TokenResponse {
+    /// Token ids don't fit in a javascript number, so they are sent as strings
+    id: String,
+    /// The plaintext token, only ever sent when the token is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(flatten)]
+    data: TokenData,
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_list_tokens(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<Json<Vec<TokenResponse>>, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let tokens = state
+        .users
+        .lock()
+        .await
+        .list_tokens(caller.user_id)
+        .into_iter()
+        .map(|(id, data)| TokenResponse {
+            id: id.to_string(),
+            token: None,
+            data,
+        })
+        .collect();
+    Ok(Json(tokens))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_create_token(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(req): Json<CreateTokenRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let (id, token, data) =
+        state
+            .users
+            .lock()
+            .await
+            .create_token(caller.user_id, req.name, req.scope);
+    let response = TokenResponse {
+        id: id.to_string(),
+        token: Some(token),
+        data,
+    };
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_revoke_token(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let revoked = id.parse::<TokenId>().map_err(|_| StatusCode::NOT_FOUND)?;
+    if !state
+        .users
+        .lock()
+        .await
+        .revoke_token(caller.user_id, revoked)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    close_connections(&state, caller.user_id, &[Credential::Token(revoked)]).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Closes the user's websockets opened with credentials that are no longer valid.
+async fn close_connections(app_state: &AppState, user_id: UserId, revoked: &[Credential]) {
+    let frame = CloseFrame {
+        code: close_code::POLICY,
+        reason: "credentials revoked".into(),
+    };
+    app_state.store.lock(user_id).await.close(revoked, frame);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum_test::{TestRequest, TestResponse, TestServer, Transport, WsMessage};
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
     async fn unit_websocket() {
         let server = test_server_http();
 
@@ -448,6 +1313,46 @@ mod tests {
         websocket.close().await;
     }
 
+    #[tokio::test]
+    async fn unit_websocket_cannot_move_next_id_back() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        for summary in ["buy milk", "water plants"] {
+            server
+                .post("/api/tasks")
+                .with_csrf(&login)
+                .json(&json!({ "summary": summary }))
+                .await
+                .assert_status(StatusCode::CREATED);
+        }
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        websocket.receive_outmsg().await;
+
+        // The newest task deleted, by a client that worked out the next id for itself
+        let tasks = Tasks {
+            tasks: vec![Task {
+                id: 0,
+                summary: "buy milk".to_string(),
+            }],
+            next_id: 1,
+        };
+        websocket.send_inmsg(InMsg::Tasks(tasks.clone())).await;
+        let OutMsg::NewTasks(stored) = websocket.receive_outmsg().await else {
+            panic!("expected the new tasks");
+        };
+        assert_eq!(stored.tasks, tasks.tasks);
+        assert_eq!(stored.next_id, 2);
+
+        let response = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "call mom" }))
+            .await;
+        assert_eq!(response.json::<Task>().id, 2);
+
+        websocket.close().await;
+    }
+
     #[tokio::test]
     async fn unit_websocket_unauthenticated() {
         let server = test_server_http();
@@ -511,6 +1416,57 @@ mod tests {
         response2.assert_status(StatusCode::CONFLICT);
     }
 
+    #[tokio::test]
+    async fn unit_register_duplicate_ignores_case() {
+        let server = test_server();
+
+        server
+            .post("/api/register")
+            .json(&json!({
+                "username": "testuser",
+                "password": "testpass"
+            }))
+            .await;
+        let response = server
+            .post("/api/register")
+            .json(&json!({
+                "username": "TestUser",
+                "password": "testpass"
+            }))
+            .await;
+
+        response.assert_status(StatusCode::CONFLICT);
+        assert_eq!(
+            response.json::<serde_json::Value>()["error"],
+            json!("username_taken")
+        );
+    }
+
+    #[tokio::test]
+    async fn unit_register_policy_errors() {
+        let server = test_server();
+
+        for (username, password, error) in [
+            ("ab", "testpass", "username_too_short"),
+            ("test user", "testpass", "username_invalid_characters"),
+            ("testuser", "short", "password_too_short"),
+            ("testuser", "password123", "password_too_common"),
+        ] {
+            let response = server
+                .post("/api/register")
+                .json(&json!({
+                    "username": username,
+                    "password": password
+                }))
+                .await;
+
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            let body = response.json::<serde_json::Value>();
+            assert_eq!(body["error"], json!(error));
+            assert!(body["message"].is_string());
+        }
+    }
+
     #[tokio::test]
     async fn unit_two_registers() {
         let server = test_server();
@@ -546,6 +1502,28 @@ mod tests {
         response.cookies().get("session").expect("Cookie not found");
     }
 
+    #[tokio::test]
+    async fn unit_login_ignores_username_case() {
+        let server = test_server();
+
+        server
+            .post("/api/register")
+            .json(&json!({
+                "username": "testuser",
+                "password": "testpass"
+            }))
+            .await;
+        let response = server
+            .post("/api/login")
+            .json(&json!({
+                "username": "TESTUSER",
+                "password": "testpass"
+            }))
+            .await;
+
+        response.assert_status(StatusCode::OK);
+    }
+
     #[tokio::test]
     async fn unit_no_register_login() {
         let server = test_server();
@@ -682,16 +1660,707 @@ mod tests {
         });
 
         server.post("/api/register").json(&user_data).await;
-        let _ = server.post("/api/login").json(&user_data).await;
+        let login = server.post("/api/login").json(&user_data).await;
 
-        let logout_response = server.post("/api/logout");
+        let logout_response = server.post("/api/logout").with_csrf(&login);
         logout_response.await.assert_status(StatusCode::NO_CONTENT);
 
         let response = server.get_websocket("/ws").await;
         response.assert_status(StatusCode::UNAUTHORIZED);
     }
 
-    fn test_server() -> TestServer {
+    #[tokio::test]
+    async fn unit_list_sessions() {
+        let server = test_server_http();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        let client1 = server.post("/api/login").json(&user_data).await;
+        let _client2 = server.post("/api/login").json(&user_data).await;
+
+        let sessions = server
+            .get("/api/sessions")
+            .add_cookie(client1.cookie("session"))
+            .await
+            .json::<Vec<serde_json::Value>>();
+
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(
+            sessions
+                .iter()
+                .filter(|s| s["current"] == json!(true))
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn unit_list_sessions_unauthenticated() {
+        let server = test_server();
+
+        let response = server.get("/api/sessions").await;
+
+        response.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unit_sessions_survive_a_new_cookie_key() {
+        let old_state = AppState::new([1; 64]);
+        let old_server = test_server_http_with_state(old_state.clone());
+        let login = login_test_user(&old_server).await;
+        let new_state = AppState {
+            key: Key::from(&[2; 64]),
+            ..old_state.clone()
+        };
+
+        let rotated =
+            test_server_http_with_state(new_state.clone().with_retired_keys(vec![[1; 64]]));
+        let response = rotated
+            .get("/api/sessions")
+            .add_cookie(login.cookie("session"))
+            .await;
+        response.assert_status_ok();
+        let reissued = response.cookie("session");
+        assert_ne!(reissued.value(), login.cookie("session").value());
+        // Once it's been reissued, the old key isn't needed any more
+        assert!(
+            rotated
+                .get("/api/sessions")
+                .add_cookie(reissued.clone())
+                .await
+                .maybe_cookie("session")
+                .is_none()
+        );
+        test_server_http_with_state(new_state.clone())
+            .get("/api/sessions")
+            .add_cookie(reissued)
+            .await
+            .assert_status_ok();
+
+        test_server_http_with_state(new_state)
+            .get("/api/sessions")
+            .add_cookie(login.cookie("session"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    const TEST_HEARTBEAT: Heartbeat = Heartbeat {
+        interval: Duration::from_secs(30),
+        missed_pongs: 2,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn unit_heartbeat_reaps_silent_peers() {
+        let app_state = AppState::new([42; 64]).with_heartbeat(TEST_HEARTBEAT);
+        let server = test_server_http_with_state(app_state.clone());
+        login_test_user(&server).await;
+        // Never reads while the server pings it, so never answers, like a peer that's gone away
+        let mut silent = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = silent.receive_outmsg().await;
+        assert_eq!(app_state.store.connections().await, 1);
+
+        tokio::time::sleep(TEST_HEARTBEAT.interval * 3 + Duration::from_secs(1)).await;
+
+        assert_eq!(app_state.store.connections().await, 0);
+        // Reading any further would answer the ping, on a connection the server has dropped
+        assert!(matches!(silent.receive_message().await, WsMessage::Ping(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unit_heartbeat_keeps_responsive_peers() {
+        let app_state = AppState::new([42; 64]).with_heartbeat(TEST_HEARTBEAT);
+        let server = test_server_http_with_state(app_state.clone());
+        login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = websocket.receive_outmsg().await;
+
+        for _ in 0..5 {
+            let WsMessage::Ping(payload) = websocket.receive_message().await else {
+                panic!("expected a ping");
+            };
+            websocket.send_message(WsMessage::Pong(payload)).await;
+        }
+
+        assert_eq!(app_state.store.connections().await, 1);
+        websocket
+            .send_inmsg(InMsg::Tasks(Tasks::single_task()))
+            .await;
+        // Paused time can skip to the next ping while the server is busy, and a timeout like
+        // `receive_outmsg`'s could run out
+        let reply = loop {
+            match websocket.receive_message().await {
+                WsMessage::Ping(payload) => websocket.send_message(WsMessage::Pong(payload)).await,
+                WsMessage::Text(text) => break serde_json::from_str::<OutMsg>(&text).unwrap(),
+                other => panic!("expected tasks, got {other:?}"),
+            }
+        };
+        assert_eq!(reply, OutMsg::NewTasks(Tasks::single_task()));
+    }
+
+    #[tokio::test]
+    async fn unit_messages_dont_wait_on_other_users() {
+        let app_state = AppState::new([42; 64]);
+        let server = test_server_http_with_state(app_state.clone());
+        login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = websocket.receive_outmsg().await;
+        // Like another user's request taking a while
+        let _users = app_state.users.lock().await;
+        websocket
+            .send_message(WsMessage::Pong(Default::default()))
+            .await;
+
+        websocket
+            .send_inmsg(InMsg::Tasks(Tasks::single_task()))
+            .await;
+
+        let reply = timeout(Duration::from_secs(1), websocket.receive_message())
+            .await
+            .expect("the message waited on the users lock");
+        let WsMessage::Text(text) = reply else {
+            panic!("expected tasks, got {reply:?}");
+        };
+        assert_eq!(
+            serde_json::from_str::<OutMsg>(&text).unwrap(),
+            OutMsg::NewTasks(Tasks::single_task())
+        );
+    }
+
+    /// A websocket that passes on everything sent to it.
+    fn test_sink() -> (
+        impl Sink<Message, Error = Infallible> + Send + Unpin + 'static,
+        mpsc::UnboundedReceiver<Message>,
+    ) {
+        let (sent, received) = mpsc::unbounded_channel();
+        let sink = futures_util::sink::unfold(sent, |sent, message| async move {
+            let _ = sent.send(message);
+            Ok(sent)
+        });
+        (Box::pin(sink), received)
+    }
+
+    /// A websocket whose browser has stopped reading, so sending to it never finishes.
+    fn stalled_sink() -> impl Sink<Message, Error = Infallible> + Send + Unpin + 'static {
+        Box::pin(futures_util::sink::unfold((), |(), _| {
+            std::future::pending::<Result<(), Infallible>>()
+        }))
+    }
+
+    async fn connect_sink<S>(app_state: &AppState, user_id: UserId, sink: S) -> Outbox
+    where
+        S: Sink<Message> + Send + Unpin + 'static,
+        S::Error: std::fmt::Display,
+    {
+        let (outbox, _) = Outbox::open(sink);
+        let mut shard = app_state.store.lock(user_id).await;
+        shard.connect(Credential::Token(user_id), Encoding::Json, outbox.clone());
+        outbox
+    }
+
+    async fn set_tasks(app_state: &AppState, user_id: UserId, tasks: Tasks) {
+        let mut shard = app_state.store.lock(user_id).await;
+        shard.tasks = tasks;
+        shard.broadcast();
+    }
+
+    #[tokio::test]
+    async fn unit_slow_clients_dont_hold_up_broadcasts() {
+        let app_state = AppState::new([42; 64]);
+        let (sink, mut received) = test_sink();
+        connect_sink(&app_state, 1, sink).await;
+        let stalled = connect_sink(&app_state, 1, stalled_sink()).await;
+        let _initial = received.recv().await.unwrap();
+
+        for next_id in 0..OUTBOX_CAPACITY as TaskId * 2 {
+            let tasks = Tasks {
+                tasks: vec![],
+                next_id,
+            };
+            timeout(
+                Duration::from_millis(100),
+                set_tasks(&app_state, 1, tasks.clone()),
+            )
+            .await
+            .unwrap();
+
+            let message = received.recv().await.unwrap();
+            assert_eq!(message, Encoding::Json.encode(&OutMsg::NewTasks(tasks)));
+        }
+
+        let frame = timeout(Duration::from_millis(100), stalled.closed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.code, close_code::AGAIN);
+    }
+
+    /// Run with `cargo test --release -- --ignored bench_broadcast_fan_out --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn bench_broadcast_fan_out() {
+        const USERS: UserId = 50;
+        const SESSIONS_PER_USER: usize = 10;
+        const BROADCASTS: usize = 2000;
+
+        let app_state = AppState::new([42; 64]);
+        let mut receivers = vec![];
+        let mut stalled = vec![];
+        for user_id in 0..USERS {
+            for session in 0..SESSIONS_PER_USER {
+                // One in fifty browsers stops reading
+                if (user_id as usize * SESSIONS_PER_USER + session).is_multiple_of(50) {
+                    stalled.push(connect_sink(&app_state, user_id, stalled_sink()).await);
+                } else {
+                    let (sink, received) = test_sink();
+                    connect_sink(&app_state, user_id, sink).await;
+                    receivers.push(received);
+                }
+            }
+        }
+
+        // Every user makes changes at the same time
+        let start = tokio::time::Instant::now();
+        let writers: Vec<_> = (0..USERS)
+            .map(|user_id| {
+                let app_state = app_state.clone();
+                tokio::spawn(async move {
+                    for i in 0..BROADCASTS / USERS as usize {
+                        let tasks = Tasks {
+                            tasks: vec![Task {
+                                id: 0,
+                                summary: format!("update {i}"),
+                            }],
+                            next_id: i as TaskId,
+                        };
+                        set_tasks(&app_state, user_id, tasks).await;
+                        // Changes come from requests, which give the writers a chance to run
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        // Give the writers a moment to catch up
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut delivered = 0;
+        for received in &mut receivers {
+            while received.try_recv().is_ok() {
+                delivered += 1;
+            }
+        }
+        // Everything sent to the sessions that kept reading, including the initial tasks
+        let sent = receivers.len() * (BROADCASTS / USERS as usize + 1);
+        println!(
+            "{BROADCASTS} broadcasts to {} sessions in {elapsed:?} ({:?} each), \
+             {delivered} of {sent} messages delivered, the rest were to sessions that fell behind",
+            USERS as usize * SESSIONS_PER_USER,
+            elapsed / BROADCASTS as u32,
+        );
+        for outbox in stalled {
+            assert!(outbox.closed().await.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_revoke_session_closes_its_websocket() {
+        let server = test_server_http();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        let client1 = server.post("/api/login").json(&user_data).await;
+        let client2 = server.post("/api/login").json(&user_data).await;
+        let mut ws2 = server
+            .get_websocket("/ws")
+            .add_cookie(client2.cookie("session"))
+            .await
+            .into_websocket()
+            .await;
+        let _initial = ws2.receive_outmsg().await;
+
+        let revoked = current_session_id(&server, client2.cookie("session")).await;
+        server
+            .delete(&format!("/api/sessions/{revoked}"))
+            .add_cookie(client1.cookie("session"))
+            .with_csrf(&client1)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        let message = timeout(Duration::from_millis(100), ws2.receive_message())
+            .await
+            .unwrap();
+        match message {
+            WsMessage::Close(Some(frame)) => assert_eq!(u16::from(frame.code), close_code::POLICY),
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+        server
+            .get_websocket("/ws")
+            .add_cookie(client2.cookie("session"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/api/sessions")
+            .add_cookie(client1.cookie("session"))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn unit_cannot_revoke_other_users_session() {
+        let server = test_server_http();
+
+        let user1 = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        let user2 = json!({
+            "username": "testuser2",
+            "password": "testpass2"
+        });
+        server.post("/api/register").json(&user1).await;
+        server.post("/api/register").json(&user2).await;
+        let client1 = server.post("/api/login").json(&user1).await;
+        let client2 = server.post("/api/login").json(&user2).await;
+
+        let other = current_session_id(&server, client2.cookie("session")).await;
+        server
+            .delete(&format!("/api/sessions/{other}"))
+            .add_cookie(client1.cookie("session"))
+            .with_csrf(&client1)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .get("/api/sessions")
+            .add_cookie(client2.cookie("session"))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn unit_logout_everywhere() {
+        let server = test_server_http();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        let client1 = server.post("/api/login").json(&user_data).await;
+        let client2 = server.post("/api/login").json(&user_data).await;
+
+        server
+            .delete("/api/sessions")
+            .add_cookie(client1.cookie("session"))
+            .with_csrf(&client1)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        for client in [client1, client2] {
+            server
+                .get("/api/sessions")
+                .add_cookie(client.cookie("session"))
+                .await
+                .assert_status(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_api_token_lists_tokens() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "admin").await;
+
+        let tokens = server
+            .get("/api/tokens")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .json::<Vec<serde_json::Value>>();
+
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0]["scope"], json!("admin"));
+        assert!(tokens[0]["last_used"].is_u64());
+        assert!(tokens[0].get("token").is_none());
+    }
+
+    #[tokio::test]
+    async fn unit_api_token_bad_token() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "admin").await;
+
+        server
+            .get("/api/tokens")
+            .clear_cookies()
+            .authorization_bearer(format!("{token}0"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/api/tokens")
+            .clear_cookies()
+            .authorization_bearer("not a token")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unit_api_token_scope_is_enforced() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "read").await;
+
+        server
+            .get("/api/tokens")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn unit_api_token_websocket() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "write").await;
+
+        let mut websocket = server
+            .get_websocket("/ws")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .into_websocket()
+            .await;
+        let _initial = websocket.receive_outmsg().await;
+        websocket
+            .send_inmsg(InMsg::Tasks(Tasks::single_task()))
+            .await;
+
+        let updated = websocket.receive_outmsg().await;
+        assert_eq!(updated, OutMsg::NewTasks(Tasks::single_task()));
+    }
+
+    #[tokio::test]
+    async fn unit_read_only_token_cannot_write_over_websocket() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "read").await;
+
+        let mut websocket = server
+            .get_websocket("/ws")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .into_websocket()
+            .await;
+        let _initial = websocket.receive_outmsg().await;
+        websocket
+            .send_inmsg(InMsg::Tasks(Tasks::single_task()))
+            .await;
+
+        let updated = timeout(Duration::from_millis(50), websocket.receive_message()).await;
+        assert!(updated.is_err(), "read only token changed tasks");
+    }
+
+    #[tokio::test]
+    async fn unit_revoke_api_token() {
+        let server = test_server_http();
+        let (login, token) = logged_in_token(&server, "write").await;
+        let mut websocket = server
+            .get_websocket("/ws")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .into_websocket()
+            .await;
+        let _initial = websocket.receive_outmsg().await;
+
+        let tokens = server
+            .get("/api/tokens")
+            .await
+            .json::<Vec<serde_json::Value>>();
+        let id = tokens[0]["id"].as_str().unwrap();
+        server
+            .delete(&format!("/api/tokens/{id}"))
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        let message = timeout(Duration::from_millis(100), websocket.receive_message())
+            .await
+            .unwrap();
+        assert!(matches!(message, WsMessage::Close(Some(_))));
+        server
+            .get_websocket("/ws")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unit_logout_without_csrf_token() {
+        let server = test_server_http();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        server.post("/api/login").json(&user_data).await;
+
+        server
+            .post("/api/logout")
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post("/api/logout")
+            .add_header(CSRF_HEADER, "not the token")
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server.get("/api/sessions").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn unit_api_token_does_not_need_csrf_token() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "admin").await;
+
+        server
+            .post("/api/tokens")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .json(&json!({ "name": "another", "scope": "read" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn unit_cross_origin_login_rejected() {
+        let server = test_server();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        let response = server
+            .post("/api/login")
+            .add_header(header::ORIGIN, "https://evil.example")
+            .json(&user_data)
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn unit_cross_origin_websocket_rejected() {
+        let server = test_server_http();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        server.post("/api/login").json(&user_data).await;
+
+        server
+            .get_websocket("/ws")
+            .add_header(header::ORIGIN, "https://evil.example")
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn unit_same_origin_websocket_allowed() {
+        let server = test_server_http();
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        server.post("/api/login").json(&user_data).await;
+        let origin = server.server_address().unwrap();
+        let origin = origin.as_str().trim_end_matches('/');
+
+        let mut websocket = server
+            .get_websocket("/ws")
+            .add_header(header::ORIGIN, origin)
+            .await
+            .into_websocket()
+            .await;
+
+        websocket.receive_outmsg().await;
+    }
+
+    #[tokio::test]
+    async fn unit_allowed_origin_websocket() {
+        let app_state =
+            AppState::new([42; 64]).with_allowed_origins(vec!["https://app.example".to_string()]);
+        let server = test_server_http_with_state(app_state);
+
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        server.post("/api/login").json(&user_data).await;
+
+        let mut websocket = server
+            .get_websocket("/ws")
+            .add_header(header::ORIGIN, "https://app.example")
+            .await
+            .into_websocket()
+            .await;
+
+        websocket.receive_outmsg().await;
+    }
+
+    /// Registers and logs in a user, then creates a token for them with the given scope.
+    pub(super) async fn logged_in_token(
+        server: &TestServer,
+        scope: &str,
+    ) -> (TestResponse, String) {
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        let login = server.post("/api/login").json(&user_data).await;
+        let response = server
+            .post("/api/tokens")
+            .with_csrf(&login)
+            .json(&json!({ "name": "script", "scope": scope }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let token = response.json::<serde_json::Value>()["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (login, token)
+    }
+
+    async fn current_session_id(
+        server: &TestServer,
+        cookie: axum_extra::extract::cookie::Cookie<'static>,
+    ) -> String {
+        let sessions = server
+            .get("/api/sessions")
+            .add_cookie(cookie)
+            .await
+            .json::<Vec<serde_json::Value>>();
+        sessions
+            .into_iter()
+            .find(|s| s["current"] == json!(true))
+            .and_then(|s| s["id"].as_str().map(String::from))
+            .expect("current session missing")
+    }
+
+    pub(super) fn test_server() -> TestServer {
         let temp = std::env::temp_dir();
         let app_state = AppState::new([42; 64]);
         let app = make_app(temp, app_state);
@@ -699,9 +2368,12 @@ mod tests {
         TestServer::new(app).unwrap()
     }
 
-    fn test_server_http() -> TestServer {
+    pub(super) fn test_server_http() -> TestServer {
+        test_server_http_with_state(AppState::new([42; 64]))
+    }
+
+    pub(super) fn test_server_http_with_state(app_state: AppState) -> TestServer {
         let temp = std::env::temp_dir();
-        let app_state = AppState::new([42; 64]);
         let app = make_app(temp, app_state);
 
         let mut config = axum_test::TestServerConfig::new();
@@ -712,7 +2384,7 @@ mod tests {
     }
 
     impl Tasks {
-        fn single_task() -> Self {
+        pub(super) fn single_task() -> Self {
             Self {
                 tasks: vec![Task {
                     id: 1,
@@ -723,7 +2395,30 @@ mod tests {
         }
     }
 
-    trait TestWebSocketExt {
+    /// Registers and logs in the usual test user, returning the login response.
+    pub(super) async fn login_test_user(server: &TestServer) -> TestResponse {
+        let user_data = json!({
+            "username": "testuser",
+            "password": "testpass"
+        });
+        server.post("/api/register").json(&user_data).await;
+        let login = server.post("/api/login").json(&user_data).await;
+        login.assert_status_ok();
+        login
+    }
+
+    pub(super) trait TestRequestExt {
+        fn with_csrf(self, login: &TestResponse) -> Self;
+    }
+    impl TestRequestExt for TestRequest {
+        /// Double-submits the CSRF token that was set when logging in, like the browser does.
+        fn with_csrf(self, login: &TestResponse) -> Self {
+            let csrf = login.cookie(CSRF_COOKIE);
+            self.add_header(CSRF_HEADER, csrf.value()).add_cookie(csrf)
+        }
+    }
+
+    pub(super) trait TestWebSocketExt {
         async fn receive_outmsg(&mut self) -> OutMsg;
         async fn send_inmsg(&mut self, msg: impl Into<InMsg>);
     }
@@ -0,0 +1,416 @@
+//! Backups of the whole server, for moving it to a new host or going back to an earlier state.
+//!
+//! A backup is a JSON archive of every user, their API tokens, calendar feeds and tasks, and
+//! optionally their sessions. Passwords and secrets are only in it as hashes. Operators download
+//! one from `GET /api/admin/backup` with the server's `ADMIN_TOKEN`, and load it when starting the
+//! server with `rust-elm restore <file>`. A server with a `STATE_FILE` saves one there, sessions
+//! and all, when it shuts down, and starts from it.
+//!
+//! Archives carry a format version and a SHA-256 checksum of their contents. Restoring refuses
+//! archives in a version this server can't read, and archives that were edited or cut short.
+
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::instrument;
+
+use super::paths;
+use super::{AppState, Tasks};
+use crate::auth::{UserId, Users, UsersBackup, unix_now};
+
+const FORMAT: &str = "rust-elm-backup";
+/// Bumped whenever the contents change in a way older servers can't read.
+const VERSION: u64 = 1;
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new().route(paths::BACKUP, get(handle_backup))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Archive {
+    format: String,
+    version: u64,
+    /// Seconds since the unix epoch
+    created_at: u64,
+    /// Hex encoded SHA-256 of `data` as compact JSON
+    sha256: String,
+    data: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupData {
+    users: UsersBackup,
+    tasks: Vec<UserTasks>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserTasks {
+    user_id: UserId,
+    tasks: Tasks,
+}
+
+/// A backup that has been checked, ready to start a server with.
+#[derive(Debug)]
+pub struct Backup {
+    pub(super) users: Users,
+    pub(super) tasks: HashMap<UserId, Tasks>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupError {
+    NotJson(String),
+    NotABackup,
+    IncompatibleVersion(u64),
+    ChecksumMismatch,
+    Inconsistent(String),
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotJson(error) => write!(f, "the file isn't valid JSON: {error}"),
+            Self::NotABackup => write!(f, "the file isn't a backup of this server"),
+            Self::IncompatibleVersion(version) => write!(
+                f,
+                "the backup is in version {version} of the format, but this server can only \
+                 restore version {VERSION}"
+            ),
+            Self::ChecksumMismatch => write!(
+                f,
+                "the backup doesn't match its checksum, so it was changed or cut short"
+            ),
+            Self::Inconsistent(reason) => write!(f, "the backup is inconsistent: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+fn checksum(data: &Value) -> String {
+    let json = serde_json::to_vec(data).unwrap_or_default();
+    hex::encode(Sha256::digest(json))
+}
+
+/// Writes an archive of everything on the server.
+fn archive(users: &Users, tasks: &HashMap<UserId, Tasks>, sessions: bool, now: u64) -> Vec<u8> {
+    let mut tasks: Vec<_> = tasks
+        .iter()
+        .map(|(user_id, tasks)| UserTasks {
+            user_id: *user_id,
+            tasks: tasks.clone(),
+        })
+        .collect();
+    tasks.sort_by_key(|tasks| tasks.user_id);
+    let data = BackupData {
+        users: users.backup(sessions),
+        tasks,
+    };
+    let data = serde_json::to_value(data).unwrap_or_default();
+    let archive = Archive {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        created_at: now,
+        sha256: checksum(&data),
+        data,
+    };
+    serde_json::to_vec_pretty(&archive).unwrap_or_default()
+}
+
+/// Writes an archive of the server as it is now.
+async fn snapshot(state: &AppState, sessions: bool, now: u64) -> Vec<u8> {
+    let users = state.users.lock().await;
+    let tasks = state.store.tasks().await;
+    archive(&users, &tasks, sessions, now)
+}
+
+/// Saves a backup, with sessions, to the state file. It's written next to it first and moved into
+/// place, so a crash part way through leaves the last one intact.
+pub(super) async fn save(state: &AppState, path: &Path) -> std::io::Result<()> {
+    let archive = snapshot(state, true, unix_now()).await;
+    let path = path.to_path_buf();
+    let mut partial = path.clone().into_os_string();
+    partial.push(".partial");
+    tokio::task::spawn_blocking(move || {
+        std::fs::write(&partial, archive)?;
+        std::fs::rename(&partial, &path)
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+impl Backup {
+    /// Reads and checks an archive. The version is checked before anything else, so archives
+    /// from newer servers are refused with a clear error even if their layout has changed.
+    pub fn from_slice(archive: &[u8]) -> Result<Self, BackupError> {
+        let archive: Value =
+            serde_json::from_slice(archive).map_err(|e| BackupError::NotJson(e.to_string()))?;
+        if archive.get("format").and_then(Value::as_str) != Some(FORMAT) {
+            return Err(BackupError::NotABackup);
+        }
+        match archive.get("version").and_then(Value::as_u64) {
+            Some(VERSION) => {}
+            Some(version) => return Err(BackupError::IncompatibleVersion(version)),
+            None => return Err(BackupError::NotABackup),
+        }
+        let archive: Archive =
+            serde_json::from_value(archive).map_err(|_| BackupError::NotABackup)?;
+        if checksum(&archive.data) != archive.sha256 {
+            return Err(BackupError::ChecksumMismatch);
+        }
+
+        let data: BackupData = serde_json::from_value(archive.data)
+            .map_err(|e| BackupError::Inconsistent(e.to_string()))?;
+        let users = Users::restore(data.users).map_err(BackupError::Inconsistent)?;
+        let mut tasks = HashMap::new();
+        for user_tasks in data.tasks {
+            if !users.contains(user_tasks.user_id) {
+                return Err(BackupError::Inconsistent(format!(
+                    "there are tasks for user {}, who doesn't exist",
+                    user_tasks.user_id
+                )));
+            }
+            if tasks.insert(user_tasks.user_id, user_tasks.tasks).is_some() {
+                return Err(BackupError::Inconsistent(format!(
+                    "user {} has two lists of tasks",
+                    user_tasks.user_id
+                )));
+            }
+        }
+        Ok(Backup { users, tasks })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BackupQuery {
+    #[serde(default)]
+    sessions: bool,
+}
+
+/// Only for operators, who are trusted with every user's data. It's turned off unless the
+/// server was given an admin token.
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_backup(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<BackupQuery>,
+) -> Result<Response, StatusCode> {
+    let admin_token = state.admin_token.ok_or(StatusCode::NOT_FOUND)?;
+    let TypedHeader(Authorization(bearer)) = bearer.ok_or(StatusCode::UNAUTHORIZED)?;
+    // Comparing hashes, so how long it takes doesn't give away how much of the token was right
+    if <[u8; 32]>::from(Sha256::digest(bearer.token())) != admin_token {
+        tracing::warn!("Rejected a backup request with the wrong admin token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let now = unix_now();
+    let archive = snapshot(&state, query.sessions, now).await;
+    tracing::info!("Wrote a backup of {} bytes", archive.len());
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{FORMAT}-{now}.json\""),
+            ),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+
+    use super::super::tests::*;
+    use super::super::{Task, Tasks};
+    use super::*;
+
+    const ADMIN_TOKEN: &str = "operator secret";
+
+    fn server_with(app_state: AppState) -> TestServer {
+        test_server_http_with_state(app_state.with_admin_token(Some(ADMIN_TOKEN.to_string())))
+    }
+
+    async fn download(server: &TestServer, sessions: bool) -> Vec<u8> {
+        let response = server
+            .get("/api/admin/backup")
+            .add_query_param("sessions", sessions)
+            .authorization_bearer(ADMIN_TOKEN)
+            .await;
+        response.assert_status_ok();
+        response.as_bytes().to_vec()
+    }
+
+    /// Edits an archive's data, and fixes up the checksum so only the edit is checked.
+    fn edited(archive: &[u8], edit: impl FnOnce(&mut Value)) -> Vec<u8> {
+        let mut archive: Value = serde_json::from_slice(archive).unwrap();
+        edit(&mut archive["data"]);
+        archive["sha256"] = json!(checksum(&archive["data"]));
+        serde_json::to_vec(&archive).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unit_backup_and_restore() {
+        let server = server_with(AppState::new([42; 64]));
+        let (login, token) = logged_in_token(&server, "read").await;
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let archive = download(&server, false).await;
+
+        let backup = Backup::from_slice(&archive).unwrap();
+        let restored = server_with(AppState::new([42; 64]).with_backup(backup));
+
+        let tasks = restored
+            .get("/api/tasks")
+            .authorization_bearer(&token)
+            .await
+            .json::<Vec<Task>>();
+        assert_eq!(
+            tasks,
+            vec![Task {
+                id: 0,
+                summary: "buy milk".to_string()
+            }]
+        );
+        login_test_user(&restored).await;
+        // The old session wasn't backed up
+        restored
+            .get("/api/sessions")
+            .add_cookie(login.cookie("session"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        let tasks_in = |archive: &[u8]| {
+            serde_json::from_slice::<Value>(archive).unwrap()["data"]["tasks"].clone()
+        };
+        assert_eq!(
+            tasks_in(&download(&restored, false).await),
+            tasks_in(&archive)
+        );
+    }
+
+    #[tokio::test]
+    async fn unit_backup_with_sessions() {
+        let server = server_with(AppState::new([42; 64]));
+        let login = login_test_user(&server).await;
+        let archive = download(&server, true).await;
+
+        let backup = Backup::from_slice(&archive).unwrap();
+        let restored = server_with(AppState::new([42; 64]).with_backup(backup));
+
+        restored
+            .get("/api/sessions")
+            .add_cookie(login.cookie("session"))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn unit_backup_needs_admin_token() {
+        let disabled = test_server_http();
+        let server = server_with(AppState::new([42; 64]));
+        let (_, token) = logged_in_token(&server, "admin").await;
+
+        disabled
+            .get("/api/admin/backup")
+            .authorization_bearer(ADMIN_TOKEN)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .get("/api/admin/backup")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/api/admin/backup")
+            .authorization_bearer(token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn unit_restore_refuses_bad_archives() {
+        let mut tasks = HashMap::new();
+        tasks.insert(7, Tasks::single_task());
+        let archive = archive(&Users::default(), &tasks, false, 0);
+
+        let error = |archive: &[u8]| Backup::from_slice(archive).unwrap_err();
+
+        assert!(matches!(error(b"{"), BackupError::NotJson(_)));
+        assert_eq!(error(b"{\"tasks\": []}"), BackupError::NotABackup);
+        let newer = String::from_utf8(archive.clone())
+            .unwrap()
+            .replace("\"version\": 1", "\"version\": 2");
+        assert_eq!(
+            error(newer.as_bytes()).to_string(),
+            "the backup is in version 2 of the format, but this server can only restore version 1"
+        );
+        let tampered = String::from_utf8(archive.clone())
+            .unwrap()
+            .replace("\"test\"", "\"tset\"");
+        assert_eq!(error(tampered.as_bytes()), BackupError::ChecksumMismatch);
+        assert_eq!(
+            error(&archive),
+            BackupError::Inconsistent("there are tasks for user 7, who doesn't exist".to_string())
+        );
+    }
+
+    #[test]
+    fn unit_restore_checks_users() {
+        let user = json!({ "id": 1, "username": "testuser", "pass_hash": "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA" });
+        let archive = archive(&Users::default(), &HashMap::new(), false, 0);
+
+        let twice = edited(&archive, |data| {
+            let mut other = user.clone();
+            other["id"] = json!(2);
+            other["username"] = json!("TestUser");
+            data["users"]["users"] = json!([user, other]);
+        });
+        let unknown_owner = edited(&archive, |data| {
+            data["users"]["users"] = json!([user]);
+            data["users"]["feeds"] = json!([{ "user_id": 3, "secret_hash": "00".repeat(32) }]);
+        });
+        let bad_hash = edited(&archive, |data| {
+            data["users"]["users"] = json!([user]);
+            data["users"]["tokens"] = json!([{
+                "id": 1, "user_id": 1, "name": "script", "scope": "read",
+                "secret_hash": "plaintext", "created_at": 0, "last_used": null
+            }]);
+        });
+
+        let error = |archive: &[u8]| Backup::from_slice(archive).unwrap_err().to_string();
+        assert_eq!(
+            error(&twice),
+            "the backup is inconsistent: username \"TestUser\" is used twice"
+        );
+        assert_eq!(
+            error(&unknown_owner),
+            "the backup is inconsistent: user 3 doesn't exist"
+        );
+        assert_eq!(
+            error(&bad_hash),
+            "the backup is inconsistent: \"plaintext\" isn't a SHA-256 hash"
+        );
+    }
+}
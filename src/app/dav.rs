@@ -0,0 +1,1081 @@
+//! A minimal CalDAV server, so calendar and todo apps can sync tasks as `VTODO`s.
+//!
+//! Each user has one calendar, `/dav/tasks/`, holding a `{id}.ics` resource per task. `/dav/` is
+//! both the user's principal and their calendar home, which is all clients need to find the
+//! calendar from the server's address. Only what syncing needs is supported: `PROPFIND`, the
+//! `calendar-query` and `calendar-multiget` reports, and `GET`, `PUT` and `DELETE` on tasks, with
+//! ETags to spot changes. Edits are broadcast to the user's websockets like any other.
+//!
+//! Calendar apps can only send a username and password, so requests use HTTP Basic. An API token
+//! works as the password, and is much cheaper to check than a real password.
+//!
+//! The server picks task ids, but clients pick the names of the todos they create. Those names
+//! are kept in [`ResourceNames`], so the task stays where the client put it. They're forgotten
+//! when the server restarts, and clients then find the tasks under their ids.
+
+use axum::{
+    Router,
+    extract::{FromRequestParts, State},
+    http::{HeaderMap, HeaderName, Method, StatusCode, Uri, header, request::Parts},
+    response::{IntoResponse, Redirect, Response},
+    routing::any,
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{self, ETag, IfMatch, IfNoneMatch, authorization::Basic},
+};
+use quick_xml::{NsReader, escape::escape, events::Event, name::ResolveResult};
+use std::collections::HashMap;
+use tracing::instrument;
+
+use super::ical::{CalendarTodo, parse_todo, render_calendar};
+use super::paths;
+use super::tasks_api::{TaskInput, etag_of, etag_value, typed_header};
+use super::{AppState, Task, TaskId, Tasks, TokenUser, verify_login};
+use crate::auth::{Scope, UserId, unix_now};
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT";
+const HOME: &str = "/dav/";
+const CALENDAR: &str = "/dav/tasks/";
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new()
+        .route(paths::DAV, any(handle_dav))
+        .route(paths::DAV_HOME, any(handle_dav))
+        .route(paths::DAV_RESOURCE, any(handle_dav))
+        // See RFC 6764, a temporary redirect keeps the method
+        .route(
+            paths::CALDAV_DISCOVERY,
+            any(|| async { Redirect::temporary(HOME) }),
+        )
+}
+
+/// A calendar app, authenticated with HTTP Basic or an API token.
+#[derive(Debug, Clone, Copy)]
+struct DavUser {
+    user_id: UserId,
+    scope: Scope,
+}
+
+impl DavUser {
+    fn require(self, scope: Scope) -> Result<Self, StatusCode> {
+        if self.scope.allows(scope) {
+            Ok(self)
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for DavUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let basic =
+            TypedHeader::<headers::Authorization<Basic>>::from_request_parts(parts, state).await;
+        if let Ok(TypedHeader(headers::Authorization(basic))) = basic {
+            // A token already says whose it is, so the username doesn't matter
+            let token = state.users.lock().await.use_token(basic.password());
+            if let Some((_, user_id, scope)) = token {
+                return Ok(DavUser { user_id, scope });
+            }
+            let password = basic.password().to_string();
+            if let Some(user_id) = verify_login(state, basic.username(), password).await {
+                return Ok(DavUser {
+                    user_id,
+                    scope: Scope::Admin,
+                });
+            }
+        } else if let Ok(token) = TokenUser::from_request_parts(parts, state).await {
+            return Ok(DavUser {
+                user_id: token.user_id,
+                scope: token.scope,
+            });
+        }
+        Err((
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                "Basic realm=\"tasks\", charset=\"UTF-8\"",
+            )],
+        )
+            .into_response())
+    }
+}
+
+/// What a path under `/dav/` refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Resource {
+    /// The user's principal, which is also their calendar home
+    Home,
+    Calendar,
+    /// A task, by the name of its resource: its id, or the name a client created it with
+    Task(String),
+}
+
+impl Resource {
+    fn from_path(path: &str) -> Option<Self> {
+        match path.strip_prefix("/dav")?.trim_end_matches('/') {
+            "" => Some(Self::Home),
+            "/tasks" => Some(Self::Calendar),
+            path => path
+                .strip_prefix("/tasks/")?
+                .strip_suffix(".ics")
+                .filter(|name| !name.is_empty() && !name.contains('/'))
+                .map(|name| Self::Task(name.to_string())),
+        }
+    }
+
+    /// Clients may send full URLs in a `calendar-multiget`.
+    fn from_href(href: &str) -> Option<Self> {
+        let uri = href.parse::<Uri>().ok()?;
+        Self::from_path(uri.path())
+    }
+
+    fn find<'a>(&self, tasks: &'a Tasks, names: &ResourceNames) -> Option<&'a Task> {
+        match self {
+            Self::Task(name) => {
+                let id = match names.id(name) {
+                    Some(id) => id,
+                    None => name.parse().ok()?,
+                };
+                tasks.get(id)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The names clients created tasks with, for one user. Tasks without one are named by their id.
+#[derive(Debug, Clone, Default)]
+pub(super) struct ResourceNames(HashMap<TaskId, String>);
+
+impl ResourceNames {
+    fn id(&self, name: &str) -> Option<TaskId> {
+        self.0
+            .iter()
+            .find_map(|(id, named)| (named == name).then_some(*id))
+    }
+
+    /// Only names that can't be mistaken for another task's id are kept.
+    fn insert(&mut self, id: TaskId, name: &str) {
+        if name.parse::<TaskId>().is_err() {
+            self.0.insert(id, name.to_string());
+        }
+    }
+
+    /// Forgets the names of tasks that are gone, however they went.
+    pub(super) fn retain(&mut self, tasks: &Tasks) {
+        self.0.retain(|id, _| tasks.get(*id).is_some());
+    }
+
+    fn href(&self, task: &Task) -> String {
+        match self.0.get(&task.id) {
+            Some(name) => format!("{CALENDAR}{name}.ics"),
+            None => format!("{CALENDAR}{}.ics", task.id),
+        }
+    }
+}
+
+/// A property, by its namespace and name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Property {
+    namespace: String,
+    name: String,
+}
+
+impl Property {
+    fn new(namespace: &str, name: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// The property as an element, using the prefixes declared on the `multistatus`.
+    fn element(&self, content: &str) -> String {
+        let (name, declaration) = match self.namespace.as_str() {
+            DAV => (format!("d:{}", self.name), String::new()),
+            CALDAV => (format!("c:{}", self.name), String::new()),
+            CALENDARSERVER => (format!("cs:{}", self.name), String::new()),
+            "" => (self.name.clone(), " xmlns=\"\"".to_string()),
+            namespace => (
+                format!("x:{}", self.name),
+                format!(" xmlns:x=\"{}\"", escape(namespace)),
+            ),
+        };
+        if content.is_empty() {
+            format!("<{name}{declaration}/>")
+        } else {
+            format!("<{name}{declaration}>{content}</{name}>")
+        }
+    }
+}
+
+/// The properties sent for `allprop`, if the resource has them.
+fn all_properties() -> Vec<Property> {
+    vec![
+        Property::new(DAV, "resourcetype"),
+        Property::new(DAV, "displayname"),
+        Property::new(DAV, "getetag"),
+        Property::new(DAV, "getcontenttype"),
+        Property::new(DAV, "current-user-principal"),
+        Property::new(CALDAV, "calendar-home-set"),
+        Property::new(CALDAV, "supported-calendar-component-set"),
+        Property::new(CALENDARSERVER, "getctag"),
+    ]
+}
+
+/// What the body of a `PROPFIND` or `REPORT` asks for.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DavRequest {
+    /// The root element, like `propfind` or `calendar-query`
+    kind: String,
+    /// `None` for `allprop`
+    properties: Option<Vec<Property>>,
+    /// The resources a `calendar-multiget` wants
+    hrefs: Vec<String>,
+    /// The components a `calendar-query` is filtered to, like `VCALENDAR` and `VTODO`
+    components: Vec<String>,
+}
+
+impl DavRequest {
+    /// An empty body is the same as asking for `allprop`, see RFC 4918 section 9.1.
+    fn parse(body: &str) -> Result<Self, quick_xml::Error> {
+        let mut request = DavRequest::default();
+        if body.trim().is_empty() {
+            request.kind = "propfind".to_string();
+            return Ok(request);
+        }
+        let mut reader = NsReader::from_str(body);
+        reader.config_mut().trim_text(true);
+        // The elements the reader is inside of
+        let mut open: Vec<Property> = Vec::new();
+        loop {
+            let (namespace, event) = reader.read_resolved_event()?;
+            let namespace = match namespace {
+                ResolveResult::Bound(namespace) => {
+                    String::from_utf8_lossy(namespace.0).into_owned()
+                }
+                _ => String::new(),
+            };
+            let (start, empty) = match event {
+                Event::Start(start) => (start, false),
+                Event::Empty(start) => (start, true),
+                Event::Text(text) => {
+                    if open.last().is_some_and(|parent| parent.is(DAV, "href")) {
+                        request.hrefs.push(text.decode()?.trim().to_string());
+                    }
+                    continue;
+                }
+                Event::End(_) => {
+                    open.pop();
+                    continue;
+                }
+                Event::Eof => break,
+                _ => continue,
+            };
+            let element = Property::new(
+                &namespace,
+                &String::from_utf8_lossy(start.local_name().as_ref()),
+            );
+            match open.as_slice() {
+                [] => request.kind = element.name.clone(),
+                [_] if element.is(DAV, "prop") => request.properties = Some(Vec::new()),
+                [_] if element.is(DAV, "allprop") => request.properties = None,
+                [_, parent] if parent.is(DAV, "prop") => {
+                    request
+                        .properties
+                        .get_or_insert_default()
+                        .push(element.clone());
+                }
+                _ if element.is(CALDAV, "comp-filter") => {
+                    if let Some(name) = start.try_get_attribute("name").ok().flatten() {
+                        let name = name.unescape_value()?;
+                        request.components.push(name.to_ascii_uppercase());
+                    }
+                }
+                _ => {}
+            }
+            if !empty {
+                open.push(element);
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// A resource, with what's needed to describe it.
+enum Node<'a> {
+    Home,
+    Calendar(&'a Tasks),
+    Task(&'a Task),
+}
+
+impl Node<'_> {
+    /// The content of a property of the resource, or `None` if it doesn't have it.
+    fn property(&self, property: &Property, user: DavUser) -> Option<String> {
+        let href = |href: &str| format!("<d:href>{href}</d:href>");
+        let value = match (property.namespace.as_str(), property.name.as_str(), self) {
+            (DAV, "resourcetype", Node::Home) => "<d:collection/><d:principal/>".to_string(),
+            (DAV, "resourcetype", Node::Calendar(_)) => "<d:collection/><c:calendar/>".to_string(),
+            (DAV, "resourcetype", Node::Task(_)) => String::new(),
+            (DAV, "displayname", Node::Home) => "Home".to_string(),
+            (DAV, "displayname", Node::Calendar(_)) => "Tasks".to_string(),
+            (DAV, "getetag", Node::Calendar(tasks)) => escape(etag_value(tasks)).into_owned(),
+            (DAV, "getetag", Node::Task(task)) => escape(etag_value(task)).into_owned(),
+            (CALENDARSERVER, "getctag", Node::Calendar(tasks)) => {
+                escape(etag_value(tasks)).into_owned()
+            }
+            (DAV, "getcontenttype", Node::Task(_)) => {
+                "text/calendar; charset=utf-8; component=VTODO".to_string()
+            }
+            (DAV, "current-user-principal", _) => href(HOME),
+            (DAV, "principal-URL", Node::Home) => href(HOME),
+            (CALDAV, "calendar-home-set", Node::Home) => href(HOME),
+            (DAV, "current-user-privilege-set", _) => {
+                let mut privileges = vec!["read"];
+                if user.scope.allows(Scope::Write) {
+                    privileges.extend(["write", "write-content", "bind", "unbind"]);
+                }
+                privileges
+                    .iter()
+                    .map(|privilege| format!("<d:privilege><d:{privilege}/></d:privilege>"))
+                    .collect()
+            }
+            (DAV, "supported-report-set", Node::Calendar(_)) => [
+                "calendar-query",
+                "calendar-multiget",
+            ]
+            .iter()
+            .map(|report| {
+                format!(
+                    "<d:supported-report><d:report><c:{report}/></d:report></d:supported-report>"
+                )
+            })
+            .collect(),
+            (CALDAV, "supported-calendar-component-set", Node::Calendar(_)) => {
+                "<c:comp name=\"VTODO\"/>".to_string()
+            }
+            (CALDAV, "calendar-data", Node::Task(task)) => {
+                let calendar =
+                    render_calendar(user.user_id, std::slice::from_ref(task), false, unix_now());
+                escape(calendar).into_owned()
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// A `207 Multi-Status` response, built up one resource at a time.
+struct Multistatus {
+    user: DavUser,
+    properties: Option<Vec<Property>>,
+    body: String,
+}
+
+impl Multistatus {
+    fn new(user: DavUser, properties: Option<Vec<Property>>) -> Self {
+        Self {
+            user,
+            properties,
+            body: format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                 <d:multistatus xmlns:d=\"{DAV}\" xmlns:c=\"{CALDAV}\" xmlns:cs=\"{CALENDARSERVER}\">"
+            ),
+        }
+    }
+
+    /// Adds a resource's properties. Ones it doesn't have are listed as not found, unless the
+    /// client asked for `allprop`.
+    fn add(&mut self, href: &str, node: &Node) {
+        let (properties, allprop) = match &self.properties {
+            Some(properties) => (properties.clone(), false),
+            None => (all_properties(), true),
+        };
+        let mut found = String::new();
+        let mut missing = String::new();
+        for property in &properties {
+            match node.property(property, self.user) {
+                Some(value) => found.push_str(&property.element(&value)),
+                None => missing.push_str(&property.element("")),
+            }
+        }
+        self.body
+            .push_str(&format!("<d:response><d:href>{}</d:href>", escape(href)));
+        self.push_propstat(&found, "200 OK");
+        if !allprop {
+            self.push_propstat(&missing, "404 Not Found");
+        }
+        self.body.push_str("</d:response>");
+    }
+
+    fn push_propstat(&mut self, properties: &str, status: &str) {
+        if !properties.is_empty() {
+            self.body.push_str(&format!(
+                "<d:propstat><d:prop>{properties}</d:prop>\
+                 <d:status>HTTP/1.1 {status}</d:status></d:propstat>"
+            ));
+        }
+    }
+
+    fn not_found(&mut self, href: &str) {
+        self.body.push_str(&format!(
+            "<d:response><d:href>{}</d:href><d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+            escape(href)
+        ));
+    }
+}
+
+impl IntoResponse for Multistatus {
+    fn into_response(mut self) -> Response {
+        self.body.push_str("</d:multistatus>\n");
+        (
+            StatusCode::MULTI_STATUS,
+            [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+/// `If-Match` only passes if the resource exists, and `If-None-Match: *` only if it doesn't.
+fn check_preconditions(headers: &HeaderMap, current: Option<&ETag>) -> Result<(), StatusCode> {
+    let if_match = typed_header::<IfMatch>(headers)
+        .is_none_or(|if_match| current.is_some_and(|etag| if_match.precondition_passes(etag)));
+    let if_none_match = typed_header::<IfNoneMatch>(headers).is_none_or(|if_none_match| {
+        current.is_none_or(|etag| if_none_match.precondition_passes(etag))
+    });
+    if if_match && if_none_match {
+        Ok(())
+    } else {
+        Err(StatusCode::PRECONDITION_FAILED)
+    }
+}
+
+async fn user_tasks(state: &AppState, user_id: UserId) -> (Tasks, ResourceNames) {
+    let shard = state.store.lock(user_id).await;
+    (shard.tasks.clone(), shard.dav_names.clone())
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all, fields(%method, %uri))]
+async fn handle_dav(
+    State(state): State<AppState>,
+    user: DavUser,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let Some(resource) = Resource::from_path(uri.path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let result = match method.as_str() {
+        "OPTIONS" => Ok((
+            [
+                (HeaderName::from_static("dav"), "1, 3, calendar-access"),
+                (header::ALLOW, ALLOW),
+            ],
+            (),
+        )
+            .into_response()),
+        "PROPFIND" => propfind(&state, user, &resource, &headers, &body).await,
+        "REPORT" => report(&state, user, &resource, &body).await,
+        "GET" | "HEAD" => get(&state, user, &resource).await,
+        "PUT" => put(&state, user, &resource, &headers, &body).await,
+        "DELETE" => delete(&state, user, &resource, &headers).await,
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    match result {
+        Ok(response) => response,
+        Err(StatusCode::METHOD_NOT_ALLOWED) => {
+            (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()
+        }
+        Err(status) => status.into_response(),
+    }
+}
+
+async fn propfind(
+    state: &AppState,
+    user: DavUser,
+    resource: &Resource,
+    headers: &HeaderMap,
+    body: &str,
+) -> Result<Response, StatusCode> {
+    let user = user.require(Scope::Read)?;
+    let request = DavRequest::parse(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    if request.kind != "propfind" {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Nothing is nested more than one level deep, so `infinity` is the same as `1`
+    let children = headers
+        .get("depth")
+        .is_none_or(|depth| depth.as_bytes() != b"0");
+    let (tasks, names) = user_tasks(state, user.user_id).await;
+
+    let mut multistatus = Multistatus::new(user, request.properties);
+    match resource {
+        Resource::Home => {
+            multistatus.add(HOME, &Node::Home);
+            if children {
+                multistatus.add(CALENDAR, &Node::Calendar(&tasks));
+            }
+        }
+        Resource::Calendar => {
+            multistatus.add(CALENDAR, &Node::Calendar(&tasks));
+            if children {
+                for task in &tasks.tasks {
+                    multistatus.add(&names.href(task), &Node::Task(task));
+                }
+            }
+        }
+        Resource::Task(_) => {
+            let task = resource.find(&tasks, &names).ok_or(StatusCode::NOT_FOUND)?;
+            multistatus.add(&names.href(task), &Node::Task(task));
+        }
+    }
+    Ok(multistatus.into_response())
+}
+
+async fn report(
+    state: &AppState,
+    user: DavUser,
+    resource: &Resource,
+    body: &str,
+) -> Result<Response, StatusCode> {
+    let user = user.require(Scope::Read)?;
+    let request = DavRequest::parse(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    if *resource != Resource::Calendar {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let (tasks, names) = user_tasks(state, user.user_id).await;
+
+    let mut multistatus = Multistatus::new(user, request.properties);
+    match request.kind.as_str() {
+        // Only todos are stored, so a query for events finds nothing. Other filters, like time
+        // ranges, are ignored and everything is sent.
+        "calendar-query" => {
+            let todos = request
+                .components
+                .iter()
+                .all(|component| component == "VCALENDAR" || component == "VTODO");
+            for task in tasks.tasks.iter().filter(|_| todos) {
+                multistatus.add(&names.href(task), &Node::Task(task));
+            }
+        }
+        "calendar-multiget" => {
+            for href in &request.hrefs {
+                match Resource::from_href(href).and_then(|resource| resource.find(&tasks, &names)) {
+                    Some(task) => multistatus.add(href, &Node::Task(task)),
+                    None => multistatus.not_found(href),
+                }
+            }
+        }
+        _ => return Err(StatusCode::FORBIDDEN),
+    }
+    Ok(multistatus.into_response())
+}
+
+async fn get(state: &AppState, user: DavUser, resource: &Resource) -> Result<Response, StatusCode> {
+    let user = user.require(Scope::Read)?;
+    let (tasks, names) = user_tasks(state, user.user_id).await;
+    let (etag, calendar) = match resource {
+        Resource::Home => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        Resource::Calendar => (etag_of(&tasks), &tasks.tasks[..]),
+        Resource::Task(_) => {
+            let task = resource.find(&tasks, &names).ok_or(StatusCode::NOT_FOUND)?;
+            (etag_of(task), std::slice::from_ref(task))
+        }
+    };
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        TypedHeader(etag),
+        render_calendar(user.user_id, calendar, false, unix_now()),
+    )
+        .into_response())
+}
+
+async fn put(
+    state: &AppState,
+    user: DavUser,
+    resource: &Resource,
+    headers: &HeaderMap,
+    body: &str,
+) -> Result<Response, StatusCode> {
+    let user = user.require(Scope::Write)?;
+    let Resource::Task(name) = resource else {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    };
+    let CalendarTodo { details, done } = parse_todo(body).ok_or(StatusCode::BAD_REQUEST)?;
+    let input = TaskInput {
+        summary: details.to_summary(),
+    };
+    let created = {
+        let mut guard = state.store.lock(user.user_id).await;
+        let shard = &mut *guard;
+        let current = resource
+            .find(&shard.tasks, &shard.dav_names)
+            .map(|task| (task.id, etag_of(task)));
+        check_preconditions(headers, current.as_ref().map(|(_, etag)| etag))?;
+        let created = match current {
+            Some((id, _)) if done => {
+                shard.tasks.delete(id);
+                None
+            }
+            Some((id, _)) => {
+                shard.tasks.update(id, input, &state.message_limits)?;
+                None
+            }
+            // Tasks are deleted once they're done, so there's nothing to keep
+            None if done => return Ok(StatusCode::NO_CONTENT.into_response()),
+            None => {
+                let task = shard.tasks.create(input, &state.message_limits)?;
+                shard.dav_names.insert(task.id, name);
+                Some(task)
+            }
+        };
+        shard.broadcast();
+        created.map(|task| shard.dav_names.href(&task))
+    };
+    // No ETag, as what's stored isn't exactly what was sent. Clients fetch it again instead.
+    Ok(match created {
+        Some(href) => (StatusCode::CREATED, [(header::LOCATION, href)]).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+async fn delete(
+    state: &AppState,
+    user: DavUser,
+    resource: &Resource,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let user = user.require(Scope::Write)?;
+    if !matches!(resource, Resource::Task(_)) {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    {
+        let mut shard = state.store.lock(user.user_id).await;
+        let current = resource
+            .find(&shard.tasks, &shard.dav_names)
+            .ok_or(StatusCode::NOT_FOUND)?;
+        check_preconditions(headers, Some(&etag_of(current)))?;
+        let id = current.id;
+        shard.tasks.delete(id);
+        shard.broadcast();
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderValue, StatusCode};
+    use axum_extra::headers::HeaderMapExt;
+    use axum_test::{TestResponse, TestServer};
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+
+    use super::super::tests::*;
+    use super::super::{InMsg, OutMsg, Task, Tasks};
+    use super::*;
+
+    /// What a client asks for when it first connects, to find the calendar.
+    const DISCOVERY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<propfind xmlns="DAV:" xmlns:CAL="urn:ietf:params:xml:ns:caldav" xmlns:CS="http://calendarserver.org/ns/">
+  <prop>
+    <resourcetype/>
+    <displayname/>
+    <current-user-principal/>
+    <CAL:calendar-home-set/>
+    <CAL:supported-calendar-component-set/>
+    <CS:getctag/>
+    <A:calendar-color xmlns:A="http://apple.com/ns/ical/"/>
+  </prop>
+</propfind>"#;
+
+    const TODO_QUERY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop>
+    <D:getetag/>
+    <C:calendar-data/>
+  </D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VTODO"/>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#;
+
+    const EVENT_QUERY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop><D:getetag/></D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT">
+        <C:time-range start="20250101T000000Z" end="20250201T000000Z"/>
+      </C:comp-filter>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#;
+
+    const MULTIGET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop>
+    <D:getetag/>
+    <C:calendar-data/>
+  </D:prop>
+  <D:href>/dav/tasks/0.ics</D:href>
+  <D:href>http://localhost/dav/tasks/99.ics</D:href>
+</C:calendar-multiget>"#;
+
+    /// A new todo as a phone's task app sends it.
+    const NEW_TODO: &str = "BEGIN:VCALENDAR\r\n\
+VERSION:2.0\r\n\
+PRODID:+//IDN bitfire.at//ical4android (org.tasks)\r\n\
+BEGIN:VTODO\r\n\
+DTSTAMP:20250528T091500Z\r\n\
+UID:6d1fd1f5-1a55-4b8e-8b14-6f2a0e9e0b3c\r\n\
+CREATED:20250528T091455Z\r\n\
+LAST-MODIFIED:20250528T091455Z\r\n\
+SUMMARY:water the plants\\, both\r\n  balconies\r\n\
+PRIORITY:1\r\n\
+DUE;VALUE=DATE:20250601\r\n\
+RRULE:FREQ=WEEKLY;BYDAY=SA\r\n\
+STATUS:NEEDS-ACTION\r\n\
+END:VTODO\r\n\
+END:VCALENDAR\r\n";
+
+    fn basic(username: &str, password: &str) -> HeaderValue {
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(headers::Authorization::basic(username, password));
+        headers[header::AUTHORIZATION].clone()
+    }
+
+    fn dav(server: &TestServer, method: &str, path: &str, token: &str) -> axum_test::TestRequest {
+        server
+            .method(Method::from_bytes(method.as_bytes()).unwrap(), path)
+            .add_header(header::AUTHORIZATION, basic("testuser", token))
+    }
+
+    async fn create_task(server: &TestServer, login: &TestResponse, summary: &str) -> Task {
+        let response = server
+            .post("/api/tasks")
+            .with_csrf(login)
+            .json(&json!({ "summary": summary }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        response.json()
+    }
+
+    #[test]
+    fn unit_parse_propfind() {
+        let request = DavRequest::parse(DISCOVERY).unwrap();
+
+        assert_eq!(request.kind, "propfind");
+        let properties = request.properties.unwrap();
+        assert_eq!(properties.len(), 7);
+        assert!(properties[3].is(CALDAV, "calendar-home-set"));
+        assert!(properties[5].is(CALENDARSERVER, "getctag"));
+        assert!(properties[6].is("http://apple.com/ns/ical/", "calendar-color"));
+
+        let allprop = DavRequest::parse("").unwrap();
+        assert_eq!(allprop.kind, "propfind");
+        assert_eq!(allprop.properties, None);
+    }
+
+    #[test]
+    fn unit_parse_reports() {
+        let query = DavRequest::parse(EVENT_QUERY).unwrap();
+        assert_eq!(query.kind, "calendar-query");
+        assert_eq!(query.components, vec!["VCALENDAR", "VEVENT"]);
+        assert_eq!(query.properties, Some(vec![Property::new(DAV, "getetag")]));
+
+        let multiget = DavRequest::parse(MULTIGET).unwrap();
+        assert_eq!(multiget.kind, "calendar-multiget");
+        assert_eq!(
+            multiget.hrefs,
+            vec!["/dav/tasks/0.ics", "http://localhost/dav/tasks/99.ics"]
+        );
+    }
+
+    #[test]
+    fn unit_resource_paths() {
+        assert_eq!(Resource::from_path("/dav"), Some(Resource::Home));
+        assert_eq!(Resource::from_path("/dav/tasks"), Some(Resource::Calendar));
+        assert_eq!(
+            Resource::from_href("https://example.com/dav/tasks/7.ics"),
+            Some(Resource::Task("7".to_string()))
+        );
+        assert_eq!(Resource::from_path("/dav/tasks/a/b.ics"), None);
+        assert_eq!(Resource::from_path("/dav/other/"), None);
+    }
+
+    #[tokio::test]
+    async fn unit_dav_requires_credentials() {
+        let server = test_server_http();
+        login_test_user(&server).await;
+
+        let anonymous = server
+            .method(Method::from_bytes(b"PROPFIND").unwrap(), "/dav/")
+            .await;
+        let wrong = dav(&server, "PROPFIND", "/dav/", "wrongpass").await;
+
+        anonymous.assert_status(StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            anonymous.header(header::WWW_AUTHENTICATE),
+            "Basic realm=\"tasks\", charset=\"UTF-8\""
+        );
+        wrong.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unit_dav_discovery() {
+        let server = test_server_http();
+        login_test_user(&server).await;
+
+        server
+            .get("/.well-known/caldav")
+            .await
+            .assert_status(StatusCode::TEMPORARY_REDIRECT);
+        let options = dav(&server, "OPTIONS", "/dav/", "testpass").await;
+        options.assert_status_ok();
+        assert!(
+            options
+                .header("dav")
+                .to_str()
+                .unwrap()
+                .contains("calendar-access")
+        );
+
+        let response = dav(&server, "PROPFIND", "/dav/", "testpass")
+            .add_header("depth", "1")
+            .text(DISCOVERY)
+            .await;
+
+        response.assert_status(StatusCode::MULTI_STATUS);
+        let body = response.text();
+        assert!(body.contains(
+            "<d:response><d:href>/dav/</d:href><d:propstat><d:prop>\
+             <d:resourcetype><d:collection/><d:principal/></d:resourcetype>\
+             <d:displayname>Home</d:displayname>\
+             <d:current-user-principal><d:href>/dav/</d:href></d:current-user-principal>\
+             <c:calendar-home-set><d:href>/dav/</d:href></c:calendar-home-set>\
+             </d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>"
+        ));
+        assert!(body.contains(
+            "<d:response><d:href>/dav/tasks/</d:href><d:propstat><d:prop>\
+             <d:resourcetype><d:collection/><c:calendar/></d:resourcetype>"
+        ));
+        assert!(body.contains("<c:supported-calendar-component-set><c:comp name=\"VTODO\"/>"));
+        assert!(body.contains(
+            "<x:calendar-color xmlns:x=\"http://apple.com/ns/ical/\"/></d:prop>\
+             <d:status>HTTP/1.1 404 Not Found</d:status>"
+        ));
+    }
+
+    #[tokio::test]
+    async fn unit_dav_reports() {
+        let server = test_server_http();
+        let (login, token) = logged_in_token(&server, "read").await;
+        let task = create_task(&server, &login, "(B) pay rent due:2025-06-01").await;
+        let etag = server
+            .get(&format!("/api/tasks/{}", task.id))
+            .await
+            .header(header::ETAG);
+
+        let todos = dav(&server, "REPORT", "/dav/tasks/", &token)
+            .text(TODO_QUERY)
+            .await;
+        let events = dav(&server, "REPORT", "/dav/tasks/", &token)
+            .text(EVENT_QUERY)
+            .await;
+        let multiget = dav(&server, "REPORT", "/dav/tasks/", &token)
+            .text(MULTIGET)
+            .await;
+
+        todos.assert_status(StatusCode::MULTI_STATUS);
+        let todos = todos.text();
+        let etag = escape(etag.to_str().unwrap()).into_owned();
+        assert!(todos.contains(&format!("<d:getetag>{etag}</d:getetag>")));
+        assert!(todos.contains("SUMMARY:pay rent\r\nSTATUS:NEEDS-ACTION\r\nPRIORITY:2\r\n"));
+        assert!(!events.text().contains("<d:response>"));
+        let multiget = multiget.text();
+        assert!(multiget.contains("<d:href>/dav/tasks/0.ics</d:href><d:propstat>"));
+        assert!(multiget.contains(
+            "<d:href>http://localhost/dav/tasks/99.ics</d:href>\
+             <d:status>HTTP/1.1 404 Not Found</d:status>"
+        ));
+    }
+
+    #[tokio::test]
+    async fn unit_dav_edits_reach_websockets() {
+        let server = test_server_http();
+        let (_, token) = logged_in_token(&server, "write").await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        websocket.receive_outmsg().await;
+
+        let created = dav(&server, "PUT", "/dav/tasks/6d1fd1f5.ics", &token)
+            .add_header(header::IF_NONE_MATCH, "*")
+            .text(NEW_TODO)
+            .await;
+
+        created.assert_status(StatusCode::CREATED);
+        assert_eq!(created.header(header::LOCATION), "/dav/tasks/6d1fd1f5.ics");
+        let OutMsg::NewTasks(tasks) = websocket.receive_outmsg().await else {
+            panic!("expected tasks");
+        };
+        assert_eq!(
+            tasks.tasks,
+            vec![Task {
+                id: 0,
+                summary: "(A) water the plants, both balconies due:2025-06-01 rec:1w".to_string(),
+            }]
+        );
+
+        let stale = dav(&server, "DELETE", "/dav/tasks/6d1fd1f5.ics", &token)
+            .add_header(header::IF_MATCH, "\"stale\"")
+            .await;
+        stale.assert_status(StatusCode::PRECONDITION_FAILED);
+
+        let completed = NEW_TODO.replace("STATUS:NEEDS-ACTION", "STATUS:COMPLETED");
+        dav(&server, "PUT", "/dav/tasks/6d1fd1f5.ics", &token)
+            .text(completed)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        let OutMsg::NewTasks(tasks) = websocket.receive_outmsg().await else {
+            panic!("expected tasks");
+        };
+        assert_eq!(tasks.tasks, vec![]);
+        dav(&server, "GET", "/dav/tasks/6d1fd1f5.ics", &token)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unit_dav_keeps_the_clients_names() {
+        let server = test_server_http();
+        let (login, token) = logged_in_token(&server, "write").await;
+        let other = create_task(&server, &login, "buy milk").await;
+
+        for summary in ["first", "second"] {
+            dav(&server, "PUT", "/dav/tasks/6d1fd1f5.ics", &token)
+                .text(NEW_TODO.replace("water the plants\\, both\r\n  balconies", summary))
+                .await
+                .assert_status_success();
+        }
+
+        let tasks = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(tasks.len(), 2);
+        assert!(tasks[1].summary.starts_with("(A) second "), "{tasks:?}");
+        let listing = dav(&server, "PROPFIND", "/dav/tasks/", &token)
+            .add_header(HeaderName::from_static("depth"), "1")
+            .text(DISCOVERY)
+            .await
+            .text();
+        assert!(listing.contains("<d:href>/dav/tasks/6d1fd1f5.ics</d:href>"));
+        assert!(listing.contains(&format!("<d:href>/dav/tasks/{}.ics</d:href>", other.id)));
+    }
+
+    #[tokio::test]
+    async fn unit_dav_forgets_names_of_tasks_deleted_elsewhere() {
+        let server = test_server_http();
+        let (login, token) = logged_in_token(&server, "write").await;
+        dav(&server, "PUT", "/dav/tasks/6d1fd1f5.ics", &token)
+            .text(NEW_TODO)
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        server
+            .delete("/api/tasks/0")
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        // Clients pick the ids of the tasks they create, so a new task can have the old one's id
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        websocket.receive_outmsg().await;
+        let tasks = Tasks {
+            tasks: vec![Task {
+                id: 0,
+                summary: "call mom".to_string(),
+            }],
+            next_id: 1,
+        };
+        websocket.send_inmsg(InMsg::Tasks(tasks)).await;
+        websocket.receive_outmsg().await;
+
+        let listing = dav(&server, "PROPFIND", "/dav/tasks/", &token)
+            .add_header(HeaderName::from_static("depth"), "1")
+            .text(DISCOVERY)
+            .await
+            .text();
+        assert!(!listing.contains("6d1fd1f5"), "{listing}");
+        assert!(listing.contains("<d:href>/dav/tasks/0.ics</d:href>"));
+        dav(&server, "DELETE", "/dav/tasks/6d1fd1f5.ics", &token)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unit_dav_ignores_non_ascii_dates() {
+        let server = test_server_http();
+        let (_, token) = logged_in_token(&server, "write").await;
+        // A two-byte character where the year ends
+        let todo = NEW_TODO.replace("DUE;VALUE=DATE:20250601", "DUE:202é0101");
+
+        let response = dav(&server, "PUT", "/dav/tasks/6d1fd1f5.ics", &token)
+            .text(todo)
+            .await;
+
+        response.assert_status(StatusCode::CREATED);
+        let tasks = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(
+            tasks[0].summary,
+            "(A) water the plants, both balconies rec:1w"
+        );
+    }
+
+    #[tokio::test]
+    async fn unit_dav_delete() {
+        let server = test_server_http();
+        let (login, token) = logged_in_token(&server, "write").await;
+        let task = create_task(&server, &login, "buy milk").await;
+        let href = format!("/dav/tasks/{}.ics", task.id);
+        let get = dav(&server, "GET", &href, &token).await;
+        get.assert_status_ok();
+        assert!(get.text().contains("SUMMARY:buy milk\r\n"));
+
+        let response = dav(&server, "DELETE", &href, &token)
+            .add_header(header::IF_MATCH, get.header(header::ETAG))
+            .await;
+
+        response.assert_status(StatusCode::NO_CONTENT);
+        let tasks = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(tasks, vec![]);
+    }
+
+    #[tokio::test]
+    async fn unit_dav_read_token_cannot_write() {
+        let server = test_server_http();
+        let (_, token) = logged_in_token(&server, "read").await;
+
+        let response = dav(&server, "PUT", "/dav/tasks/new.ics", &token)
+            .text(NEW_TODO)
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,447 @@
+//! Generates the Elm side of the websocket messages from their JSON Schemas, so the Elm types,
+//! decoders and encoders always match what serde sends and expects.
+//!
+//! Run `cargo run -- generate-elm` after changing any of the message types, the test below fails
+//! until the checked-in module is regenerated.
+
+use schemars::generate::SchemaSettings;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fmt::Write, path::PathBuf};
+
+use super::{InMsg, OutMsg};
+
+/// Where the generated module is checked in, relative to the crate root.
+const ELM_MODULE_PATH: &str = "elm-src/Messages.elm";
+const ELM_MODULE_NAME: &str = "Messages";
+
+pub fn elm_module_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(ELM_MODULE_PATH)
+}
+
+/// The Elm module for every type sent over the websocket.
+pub fn elm_module() -> String {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|settings| settings.meta_schema = None)
+        .into_generator();
+    generator.subschema_for::<InMsg>();
+    generator.subschema_for::<OutMsg>();
+    let definitions: BTreeMap<String, Value> =
+        generator.take_definitions(true).into_iter().collect();
+
+    let mut exposing = Vec::new();
+    let mut body = String::new();
+    for (name, schema) in &definitions {
+        let definition = Definition::new(name, schema);
+        exposing.extend(definition.exposing());
+        body.push_str(&definition.code());
+    }
+    exposing.sort();
+
+    let mut module = format!("module {ELM_MODULE_NAME} exposing\n");
+    for (i, item) in exposing.iter().enumerate() {
+        let separator = if i == 0 { '(' } else { ',' };
+        writeln!(module, "    {separator} {item}").unwrap();
+    }
+    module.push_str("    )\n\n");
+    module.push_str(
+        "{-| The messages sent over the websocket.\n\n\
+         Generated from the Rust types by `cargo run -- generate-elm`, don't edit it by hand.\n\n\
+         -}\n\n",
+    );
+    module.push_str(
+        "import Json.Decode as Decode exposing (Decoder)\n\
+         import Json.Encode as Encode exposing (Value)\n",
+    );
+    module.push_str(&body);
+    module.push_str(
+        "\n\nandMap : Decoder a -> Decoder (a -> b) -> Decoder b\n\
+         andMap =\n    Decode.map2 (|>)\n",
+    );
+    module
+}
+
+enum Definition<'a> {
+    Record {
+        name: &'a str,
+        fields: Vec<(&'a str, Codec)>,
+    },
+    /// Adjacently tagged enums, like `#[serde(tag = "action", content = "payload")]`
+    Tagged {
+        name: &'a str,
+        tag: &'a str,
+        variants: Vec<(&'a str, Option<(&'a str, Codec)>)>,
+    },
+    /// Enums with only unit variants, which serde sends as strings
+    Strings {
+        name: &'a str,
+        variants: Vec<&'a str>,
+    },
+}
+
+impl<'a> Definition<'a> {
+    fn new(name: &'a str, schema: &'a Value) -> Self {
+        if let Some(variants) = schema.get("oneOf").and_then(Value::as_array) {
+            let tag = variants
+                .first()
+                .and_then(|variant| tag_of(variant))
+                .unwrap_or_else(|| panic!("`{name}` isn't an adjacently tagged enum"));
+            let variants = variants
+                .iter()
+                .map(|variant| {
+                    let properties = properties_of(name, variant);
+                    let value = properties[tag]["const"]
+                        .as_str()
+                        .unwrap_or_else(|| panic!("a variant of `{name}` has no `{tag}`"));
+                    let content = properties
+                        .iter()
+                        .find(|(field, _)| *field != tag)
+                        .map(|(field, schema)| (field.as_str(), Codec::new(schema)));
+                    (value, content)
+                })
+                .collect();
+            Self::Tagged {
+                name,
+                tag,
+                variants,
+            }
+        } else if let Some(variants) = schema.get("enum").and_then(Value::as_array) {
+            let variants = variants
+                .iter()
+                .map(|variant| {
+                    variant
+                        .as_str()
+                        .unwrap_or_else(|| panic!("`{name}` has a variant that isn't a string"))
+                })
+                .collect();
+            Self::Strings { name, variants }
+        } else {
+            let required = schema["required"].as_array().cloned().unwrap_or_default();
+            let fields = properties_of(name, schema)
+                .iter()
+                .map(|(field, schema)| {
+                    assert!(
+                        required.contains(&Value::from(field.as_str())),
+                        "`{name}.{field}` is optional, which isn't supported"
+                    );
+                    (field.as_str(), Codec::new(schema))
+                })
+                .collect();
+            Self::Record { name, fields }
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Self::Record { name, .. } | Self::Tagged { name, .. } | Self::Strings { name, .. } => {
+                name
+            }
+        }
+    }
+
+    fn exposing(&self) -> [String; 3] {
+        let name = self.name();
+        let ty = match self {
+            Self::Record { .. } => name.to_string(),
+            Self::Tagged { .. } | Self::Strings { .. } => format!("{name}(..)"),
+        };
+        [ty, format!("decode{name}"), format!("encode{name}")]
+    }
+
+    fn code(&self) -> String {
+        let name = self.name();
+        let mut code = String::new();
+        match self {
+            Self::Record { fields, .. } => {
+                write!(code, "\n\ntype alias {name} =\n").unwrap();
+                if fields.is_empty() {
+                    code.push_str("    {}\n");
+                }
+                for (i, (field, codec)) in fields.iter().enumerate() {
+                    let separator = if i == 0 { '{' } else { ',' };
+                    writeln!(code, "    {separator} {} : {}", camel_case(field), codec.ty).unwrap();
+                }
+                if !fields.is_empty() {
+                    code.push_str("    }\n");
+                }
+
+                write!(code, "\n\ndecode{name} : Decoder {name}\ndecode{name} =\n").unwrap();
+                writeln!(code, "    Decode.succeed {name}").unwrap();
+                for (field, codec) in fields {
+                    writeln!(
+                        code,
+                        "        |> andMap (Decode.field \"{field}\" {})",
+                        parenthesize(&codec.decoder)
+                    )
+                    .unwrap();
+                }
+
+                write!(
+                    code,
+                    "\n\nencode{name} : {name} -> Value\nencode{name} value =\n    Encode.object\n"
+                )
+                .unwrap();
+                if fields.is_empty() {
+                    code.push_str("        []\n");
+                }
+                for (i, (field, codec)) in fields.iter().enumerate() {
+                    let separator = if i == 0 { '[' } else { ',' };
+                    writeln!(
+                        code,
+                        "        {separator} ( \"{field}\", {} )",
+                        codec.encode(&format!("value.{}", camel_case(field)))
+                    )
+                    .unwrap();
+                }
+                if !fields.is_empty() {
+                    code.push_str("        ]\n");
+                }
+            }
+            Self::Tagged { tag, variants, .. } => {
+                write!(code, "\n\ntype {name}\n").unwrap();
+                for (i, (value, content)) in variants.iter().enumerate() {
+                    let separator = if i == 0 { '=' } else { '|' };
+                    let constructor = constructor(name, value);
+                    match content {
+                        Some((_, codec)) => writeln!(
+                            code,
+                            "    {separator} {constructor} {}",
+                            parenthesize(&codec.ty)
+                        )
+                        .unwrap(),
+                        None => writeln!(code, "    {separator} {constructor}").unwrap(),
+                    }
+                }
+
+                write!(code, "\n\ndecode{name} : Decoder {name}\ndecode{name} =\n").unwrap();
+                writeln!(code, "    Decode.field \"{tag}\" Decode.string").unwrap();
+                code.push_str("        |> Decode.andThen\n            (\\tag ->\n                case tag of\n");
+                for (value, content) in variants {
+                    let constructor = constructor(name, value);
+                    writeln!(code, "                    \"{value}\" ->").unwrap();
+                    match content {
+                        Some((field, codec)) => writeln!(
+                            code,
+                            "                        Decode.map {constructor} (Decode.field \"{field}\" {})\n",
+                            parenthesize(&codec.decoder)
+                        ),
+                        None => writeln!(code, "                        Decode.succeed {constructor}\n"),
+                    }
+                    .unwrap();
+                }
+                writeln!(
+                    code,
+                    "                    _ ->\n                        Decode.fail (\"Unknown {tag}: \" ++ tag)\n            )"
+                )
+                .unwrap();
+
+                write!(
+                    code,
+                    "\n\nencode{name} : {name} -> Value\nencode{name} value =\n    case value of\n"
+                )
+                .unwrap();
+                for (i, (value, content)) in variants.iter().enumerate() {
+                    if i > 0 {
+                        code.push('\n');
+                    }
+                    let constructor = constructor(name, value);
+                    let tag_field = format!("( \"{tag}\", Encode.string \"{value}\" )");
+                    match content {
+                        Some((field, codec)) => writeln!(
+                            code,
+                            "        {constructor} content ->\n            Encode.object\n                [ {tag_field}\n                , ( \"{field}\", {} )\n                ]",
+                            codec.encode("content")
+                        ),
+                        None => writeln!(
+                            code,
+                            "        {constructor} ->\n            Encode.object [ {tag_field} ]"
+                        ),
+                    }
+                    .unwrap();
+                }
+            }
+            Self::Strings { variants, .. } => {
+                write!(code, "\n\ntype {name}\n").unwrap();
+                for (i, value) in variants.iter().enumerate() {
+                    let separator = if i == 0 { '=' } else { '|' };
+                    writeln!(code, "    {separator} {}", constructor(name, value)).unwrap();
+                }
+
+                write!(code, "\n\ndecode{name} : Decoder {name}\ndecode{name} =\n").unwrap();
+                code.push_str("    Decode.string\n        |> Decode.andThen\n            (\\value ->\n                case value of\n");
+                for value in variants {
+                    writeln!(
+                        code,
+                        "                    \"{value}\" ->\n                        Decode.succeed {}\n",
+                        constructor(name, value)
+                    )
+                    .unwrap();
+                }
+                writeln!(
+                    code,
+                    "                    _ ->\n                        Decode.fail (\"Unknown {name}: \" ++ value)\n            )"
+                )
+                .unwrap();
+
+                write!(
+                    code,
+                    "\n\nencode{name} : {name} -> Value\nencode{name} value =\n    Encode.string <|\n        case value of\n"
+                )
+                .unwrap();
+                for (i, value) in variants.iter().enumerate() {
+                    if i > 0 {
+                        code.push('\n');
+                    }
+                    writeln!(
+                        code,
+                        "            {} ->\n                \"{value}\"",
+                        constructor(name, value)
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        code
+    }
+}
+
+/// The Elm type of a schema, and the functions that decode and encode it.
+struct Codec {
+    ty: String,
+    decoder: String,
+    encoder: String,
+}
+
+impl Codec {
+    fn new(schema: &Value) -> Self {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.rsplit('/').next().unwrap_or_default();
+            return Self {
+                ty: name.to_string(),
+                decoder: format!("decode{name}"),
+                encoder: format!("encode{name}"),
+            };
+        }
+        let ty = match &schema["type"] {
+            Value::Array(types) if types.len() == 2 && types.contains(&"null".into()) => {
+                let ty = types.iter().find(|ty| *ty != "null").unwrap();
+                let mut inner = schema.clone();
+                inner["type"] = ty.clone();
+                let inner = Self::new(&inner);
+                return Self {
+                    ty: format!("Maybe {}", parenthesize(&inner.ty)),
+                    decoder: format!("Decode.nullable {}", parenthesize(&inner.decoder)),
+                    encoder: format!(
+                        "Maybe.map {} >> Maybe.withDefault Encode.null",
+                        parenthesize(&inner.encoder)
+                    ),
+                };
+            }
+            Value::String(ty) => ty.as_str(),
+            _ => panic!("unsupported schema {schema}"),
+        };
+        let simple = |ty: &str, codec: &str| Self {
+            ty: ty.to_string(),
+            decoder: format!("Decode.{codec}"),
+            encoder: format!("Encode.{codec}"),
+        };
+        match ty {
+            "integer" => simple("Int", "int"),
+            "number" => simple("Float", "float"),
+            "string" => simple("String", "string"),
+            "boolean" => simple("Bool", "bool"),
+            "array" => {
+                let items = Self::new(&schema["items"]);
+                Self {
+                    ty: format!("List {}", parenthesize(&items.ty)),
+                    decoder: format!("Decode.list {}", parenthesize(&items.decoder)),
+                    encoder: format!("Encode.list {}", parenthesize(&items.encoder)),
+                }
+            }
+            _ => panic!("unsupported schema {schema}"),
+        }
+    }
+
+    /// An expression encoding `value`.
+    fn encode(&self, value: &str) -> String {
+        if self.encoder.contains(">>") {
+            format!("({}) {value}", self.encoder)
+        } else {
+            format!("{} {value}", self.encoder)
+        }
+    }
+}
+
+fn properties_of<'a>(name: &str, schema: &'a Value) -> &'a Map<String, Value> {
+    schema["properties"]
+        .as_object()
+        .unwrap_or_else(|| panic!("`{name}` isn't an object, an enum or a tagged enum"))
+}
+
+/// The property of a tagged enum's variant that names the variant.
+fn tag_of(variant: &Value) -> Option<&str> {
+    variant["properties"]
+        .as_object()?
+        .iter()
+        .find(|(_, schema)| schema.get("const").is_some())
+        .map(|(field, _)| field.as_str())
+}
+
+/// Constructors are prefixed with their type, since `InMsg::Tasks` would clash with `Tasks`.
+fn constructor(name: &str, variant: &str) -> String {
+    format!("{name}{}", pascal_case(variant))
+}
+
+fn pascal_case(snake: &str) -> String {
+    let mut pascal = String::new();
+    for word in snake.split('_') {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            pascal.extend(first.to_uppercase());
+            pascal.push_str(chars.as_str());
+        }
+    }
+    pascal
+}
+
+fn camel_case(snake: &str) -> String {
+    let pascal = pascal_case(snake);
+    let mut chars = pascal.chars();
+    chars
+        .next()
+        .map(|first| first.to_lowercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn parenthesize(ty: &str) -> String {
+    if ty.contains(' ') {
+        format!("({ty})")
+    } else {
+        ty.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+
+    use super::*;
+
+    #[test]
+    fn unit_elm_module_is_up_to_date() {
+        let checked_in = std::fs::read_to_string(elm_module_path()).unwrap_or_default();
+
+        assert_str_eq!(
+            checked_in,
+            elm_module(),
+            "{ELM_MODULE_PATH} is out of date, run `cargo run -- generate-elm`"
+        );
+    }
+
+    #[test]
+    fn unit_names() {
+        assert_eq!(constructor("OutMsg", "new_tasks"), "OutMsgNewTasks");
+        assert_eq!(camel_case("next_id"), "nextId");
+        assert_eq!(camel_case("summary"), "summary");
+    }
+}
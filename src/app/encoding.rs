@@ -0,0 +1,370 @@
+//! How messages on a websocket are encoded.
+//!
+//! Clients pick an encoding when they connect, with the websocket subprotocol. JSON text frames are
+//! the default, and `msgpack` switches the server's messages to MessagePack binary frames, which
+//! are smaller and quicker to parse for long task lists. Either kind of frame is accepted from any
+//! client. MessagePack messages have the same shape as the JSON ones, so the schemas describe both.
+//!
+//! tungstenite doesn't support permessage-deflate, so compression is a subprotocol as well:
+//! `json.deflate` and `msgpack.deflate` send the server's messages in binary frames, compressed
+//! with raw deflate (what browsers' `DecompressionStream("deflate-raw")` reads). Clients still
+//! send uncompressed frames. The server's messages are the big ones, and inflating what clients
+//! send would let a small message turn into a huge one.
+
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use flate2::{Compression, write::DeflateEncoder};
+use std::io::Write;
+
+use super::{InMsg, OutMsg};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(super) enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    DeflateJson,
+    DeflateMessagePack,
+}
+
+impl Encoding {
+    /// The subprotocols clients can ask for, the ones the server prefers first. Ones that don't
+    /// ask get JSON.
+    const PROTOCOLS: [(&str, Self); 4] = [
+        ("msgpack.deflate", Self::DeflateMessagePack),
+        ("json.deflate", Self::DeflateJson),
+        ("msgpack", Self::MessagePack),
+        ("json", Self::Json),
+    ];
+
+    /// Agrees on an encoding with the client, which is answered with the subprotocol it chose.
+    pub(super) fn negotiate(ws: WebSocketUpgrade) -> (WebSocketUpgrade, Self) {
+        let ws = ws.protocols(Self::PROTOCOLS.map(|(protocol, _)| protocol));
+        let encoding = ws
+            .selected_protocol()
+            .and_then(|selected| {
+                Self::PROTOCOLS
+                    .iter()
+                    .find(|(protocol, _)| selected == protocol)
+            })
+            .map(|(_, encoding)| *encoding)
+            .unwrap_or_default();
+        (ws, encoding)
+    }
+
+    pub(super) fn encode(self, msg: &OutMsg) -> Message {
+        tracing::debug!(?msg);
+        match self {
+            Self::Json => Message::Text(serde_json::to_string(msg).unwrap().into()),
+            // With field names, so they look just like the JSON messages
+            Self::MessagePack => Message::Binary(rmp_serde::to_vec_named(msg).unwrap().into()),
+            Self::DeflateJson => Message::Binary(deflate(&serde_json::to_vec(msg).unwrap()).into()),
+            Self::DeflateMessagePack => {
+                Message::Binary(deflate(&rmp_serde::to_vec_named(msg).unwrap()).into())
+            }
+        }
+    }
+
+    /// Reads a message from the contents of a text frame for JSON, or a binary one for MessagePack.
+    /// What clients send is never compressed.
+    pub(super) fn decode(self, data: &[u8]) -> Result<InMsg, String> {
+        match self {
+            Self::Json | Self::DeflateJson => {
+                serde_json::from_slice(data).map_err(|e| e.to_string())
+            }
+            Self::MessagePack | Self::DeflateMessagePack => {
+                rmp_serde::from_slice(data).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec doesn't fail");
+    encoder.finish().expect("writing to a Vec doesn't fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_test::{TestServer, TestWebSocket, WsMessage};
+    use flate2::read::DeflateDecoder;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+    use std::io::Read;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    use super::super::tests::*;
+    use super::super::{AppState, MessageError, MessageLimits, Task, Tasks};
+    use super::*;
+    use axum::extract::ws::close_code;
+
+    /// Every test in the suite runs with each of these, which should behave the same.
+    const ENCODINGS: [Encoding; 4] = [
+        Encoding::Json,
+        Encoding::MessagePack,
+        Encoding::DeflateJson,
+        Encoding::DeflateMessagePack,
+    ];
+
+    /// A websocket that talks in one encoding.
+    struct TestClient {
+        websocket: TestWebSocket,
+        encoding: Encoding,
+    }
+
+    impl TestClient {
+        async fn connect(server: &TestServer, encoding: Encoding) -> Self {
+            let (protocol, _) = Encoding::PROTOCOLS
+                .into_iter()
+                .find(|(_, protocol_encoding)| *protocol_encoding == encoding)
+                .unwrap();
+            let response = server
+                .get_websocket("/ws")
+                .add_header("sec-websocket-protocol", protocol)
+                .await;
+            response.assert_header("sec-websocket-protocol", protocol);
+            Self {
+                websocket: response.into_websocket().await,
+                encoding,
+            }
+        }
+
+        async fn send_raw(&mut self, msg: &impl serde::Serialize) {
+            let message = match self.encoding {
+                Encoding::Json | Encoding::DeflateJson => {
+                    WsMessage::Text(serde_json::to_string(msg).unwrap().into())
+                }
+                Encoding::MessagePack | Encoding::DeflateMessagePack => {
+                    WsMessage::Binary(rmp_serde::to_vec_named(msg).unwrap().into())
+                }
+            };
+            self.websocket.send_message(message).await;
+        }
+
+        async fn send(&mut self, msg: InMsg) {
+            self.send_raw(&msg).await;
+        }
+
+        async fn receive(&mut self) -> OutMsg {
+            let message = timeout(Duration::from_millis(100), self.websocket.receive_message())
+                .await
+                .unwrap();
+            match (self.encoding, message) {
+                (Encoding::Json, WsMessage::Text(text)) => serde_json::from_str(&text).unwrap(),
+                (Encoding::MessagePack, WsMessage::Binary(data)) => {
+                    rmp_serde::from_slice(&data).unwrap()
+                }
+                (Encoding::DeflateJson, WsMessage::Binary(data)) => {
+                    serde_json::from_slice(&inflate(&data)).unwrap()
+                }
+                (Encoding::DeflateMessagePack, WsMessage::Binary(data)) => {
+                    rmp_serde::from_slice(&inflate(&data)).unwrap()
+                }
+                (encoding, other) => panic!("expected a {encoding:?} message, got {other:?}"),
+            }
+        }
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut inflated = Vec::new();
+        DeflateDecoder::new(data)
+            .read_to_end(&mut inflated)
+            .unwrap();
+        inflated
+    }
+
+    fn some_tasks() -> Tasks {
+        Tasks {
+            tasks: vec![
+                Task {
+                    id: 0,
+                    summary: "(A) call mom +family @phone due:2025-07-01".to_string(),
+                },
+                Task {
+                    id: 1,
+                    summary: "café ☕".to_string(),
+                },
+            ],
+            next_id: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_sends_current_tasks() {
+        for encoding in ENCODINGS {
+            let server = test_server_http();
+            login_test_user(&server).await;
+            let mut first = TestClient::connect(&server, encoding).await;
+            assert_eq!(first.receive().await, OutMsg::NewTasks(Tasks::default()));
+
+            first.send(InMsg::Tasks(some_tasks())).await;
+            assert_eq!(first.receive().await, OutMsg::NewTasks(some_tasks()));
+
+            let mut second = TestClient::connect(&server, encoding).await;
+            assert_eq!(second.receive().await, OutMsg::NewTasks(some_tasks()));
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_migrates_old_messages() {
+        for encoding in ENCODINGS {
+            let server = test_server_http();
+            login_test_user(&server).await;
+            let mut client = TestClient::connect(&server, encoding).await;
+            client.receive().await;
+
+            let unversioned = json!({
+                "action": "tasks",
+                "payload": { "tasks": [{ "id": 0, "summary": "buy milk" }], "next_id": 1 }
+            });
+            client.send_raw(&unversioned).await;
+
+            let expected = Tasks {
+                tasks: vec![Task {
+                    id: 0,
+                    summary: "buy milk".to_string(),
+                }],
+                next_id: 1,
+            };
+            assert_eq!(client.receive().await, OutMsg::NewTasks(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_ignores_invalid_messages() {
+        for encoding in ENCODINGS {
+            let server = test_server_http();
+            login_test_user(&server).await;
+            let mut client = TestClient::connect(&server, encoding).await;
+            client.receive().await;
+
+            client.send_raw(&json!({ "action": "unknown" })).await;
+            client.send(InMsg::Tasks(some_tasks())).await;
+
+            assert_eq!(client.receive().await, OutMsg::NewTasks(some_tasks()));
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_refuses_messages_over_the_limits() {
+        let limits = MessageLimits {
+            max_message_size: 300,
+            max_tasks: 2,
+            max_summary_length: 50,
+        };
+        let too_many = Tasks {
+            tasks: (0..3)
+                .map(|id| Task {
+                    id,
+                    summary: "buy milk".to_string(),
+                })
+                .collect(),
+            next_id: 3,
+        };
+        let too_long = Tasks {
+            tasks: vec![Task {
+                id: 0,
+                summary: "ü".repeat(51),
+            }],
+            next_id: 1,
+        };
+        let too_large = Tasks {
+            tasks: vec![Task {
+                id: 0,
+                summary: "x".repeat(400),
+            }],
+            next_id: 1,
+        };
+        let cases = [
+            (too_many, MessageError::TooManyTasks, close_code::POLICY),
+            (too_long, MessageError::SummaryTooLong, close_code::POLICY),
+            (too_large, MessageError::TooLarge, close_code::SIZE),
+        ];
+
+        for encoding in ENCODINGS {
+            for (tasks, error, code) in cases.clone() {
+                let app_state = AppState::new([42; 64]).with_message_limits(limits);
+                let server = test_server_http_with_state(app_state);
+                login_test_user(&server).await;
+                let mut client = TestClient::connect(&server, encoding).await;
+                client.receive().await;
+
+                client.send(InMsg::Tasks(tasks)).await;
+
+                let OutMsg::Rejected(rejection) = client.receive().await else {
+                    panic!("expected {error:?} to be refused with {encoding:?}");
+                };
+                assert_eq!(rejection.error, error);
+                match client.websocket.receive_message().await {
+                    WsMessage::Close(Some(frame)) => {
+                        assert_eq!(u16::from(frame.code), code)
+                    }
+                    other => panic!("expected a close frame, got {other:?}"),
+                }
+                let mut other = TestClient::connect(&server, encoding).await;
+                assert_eq!(other.receive().await, OutMsg::NewTasks(Tasks::default()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_clients_in_different_encodings_sync() {
+        let server = test_server_http();
+        login_test_user(&server).await;
+        let mut json = TestClient::connect(&server, Encoding::Json).await;
+        let mut msgpack = TestClient::connect(&server, Encoding::MessagePack).await;
+        json.receive().await;
+        msgpack.receive().await;
+
+        msgpack.send(InMsg::Tasks(some_tasks())).await;
+
+        assert_eq!(json.receive().await, OutMsg::NewTasks(some_tasks()));
+        assert_eq!(msgpack.receive().await, OutMsg::NewTasks(some_tasks()));
+    }
+
+    #[test]
+    fn unit_deflate_makes_long_lists_smaller() {
+        let tasks = Tasks {
+            tasks: (0..100)
+                .map(|id| Task {
+                    id,
+                    summary: "(B) water the plants +home @balcony".to_string(),
+                })
+                .collect(),
+            next_id: 100,
+        };
+        let msg = OutMsg::NewTasks(tasks);
+        let size = |encoding: Encoding| match encoding.encode(&msg) {
+            Message::Text(text) => text.len(),
+            Message::Binary(data) => data.len(),
+            other => panic!("expected a message, got {other:?}"),
+        };
+
+        assert!(size(Encoding::DeflateJson) * 10 < size(Encoding::Json));
+        assert!(size(Encoding::DeflateMessagePack) * 10 < size(Encoding::MessagePack));
+    }
+
+    #[tokio::test]
+    async fn unit_json_is_the_default() {
+        let server = test_server_http();
+        login_test_user(&server).await;
+
+        for protocol in [None, Some("cbor")] {
+            let mut request = server.get_websocket("/ws");
+            if let Some(protocol) = protocol {
+                request = request.add_header("sec-websocket-protocol", protocol);
+            }
+            let response = request.await;
+            assert!(response.maybe_header("sec-websocket-protocol").is_none());
+            let mut client = TestClient {
+                websocket: response.into_websocket().await,
+                encoding: Encoding::Json,
+            };
+            client.receive().await;
+        }
+    }
+}
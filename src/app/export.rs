@@ -0,0 +1,306 @@
+//! Downloads of a user's tasks in formats other tools understand.
+//!
+//! JSON is the same shape the websocket sends, so it keeps everything. The other formats are for
+//! people and other todo apps: a CSV table, a Markdown checklist, and todo.txt. Tasks don't have
+//! priorities, contexts or projects of their own, but summaries written in todo.txt syntax, like
+//! `(A) call mom +family @phone`, carry them over as they are. CSV cells that would start a
+//! spreadsheet formula get a `'` in front, which importing the file takes off again.
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use futures_util::stream;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::convert::Infallible;
+use tracing::instrument;
+
+use super::paths;
+use super::{AppState, Caller, Task, Tasks};
+use crate::auth::Scope;
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new().route(paths::EXPORT, get(handle_export))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+    #[serde(alias = "md")]
+    Markdown,
+    #[serde(alias = "todo.txt")]
+    Todotxt,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Todotxt => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Json => "tasks.json",
+            Self::Csv => "tasks.csv",
+            Self::Markdown => "tasks.md",
+            Self::Todotxt => "todo.txt",
+        }
+    }
+
+    /// Renders the snapshot one task at a time, as the body is sent, so the whole file is never
+    /// held in memory next to it. JSON is the exception: it's a single document.
+    pub(super) fn render(self, tasks: Tasks) -> Box<dyn Iterator<Item = String> + Send> {
+        match self {
+            Self::Json => Box::new(std::iter::once(
+                serde_json::to_string_pretty(&tasks).unwrap_or_default(),
+            )),
+            Self::Csv => Box::new(
+                std::iter::once(csv_row(&["id", "summary"])).chain(
+                    tasks
+                        .tasks
+                        .into_iter()
+                        .map(|task| csv_row(&[&task.id.to_string(), &task.summary])),
+                ),
+            ),
+            Self::Markdown => Box::new(
+                std::iter::once("# Tasks\n\n".to_string())
+                    .chain(tasks.tasks.into_iter().map(|task| markdown_item(&task))),
+            ),
+            Self::Todotxt => Box::new(tasks.tasks.into_iter().map(|task| todo_txt_line(&task))),
+        }
+    }
+}
+
+/// What a spreadsheet cell starts with to be read as a formula.
+const FORMULA_STARTS: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+/// Cells that look like formulas get a `'` in front, so spreadsheets show them instead of running
+/// them.
+fn csv_cell(value: &str) -> String {
+    if value.starts_with(FORMULA_STARTS) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Takes off the `'` [`csv_cell`] puts in front of formulas, so exports can be imported again.
+pub(super) fn csv_text(cell: &str) -> &str {
+    cell.strip_prefix('\'')
+        .filter(|rest| rest.starts_with(FORMULA_STARTS))
+        .unwrap_or(cell)
+}
+
+fn csv_row(fields: &[&str]) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    // Writing to memory can't fail
+    writer
+        .write_record(fields.iter().map(|field| csv_cell(field)))
+        .unwrap_or_default();
+    let row = writer.into_inner().unwrap_or_default();
+    String::from_utf8(row).unwrap_or_default()
+}
+
+/// Summaries are a single line everywhere else, but nothing stops a client sending newlines.
+pub(super) fn one_line(summary: &str) -> String {
+    summary.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn markdown_item(task: &Task) -> String {
+    let mut escaped = String::new();
+    for c in one_line(&task.summary).chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    format!("- [ ] {escaped}\n")
+}
+
+/// The id is kept as a `key:value` tag, so the file can be imported again without duplicates.
+fn todo_txt_line(task: &Task) -> String {
+    format!("{} id:{}\n", one_line(&task.summary), task.id)
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_export(
+    State(state): State<AppState>,
+    caller: Caller,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    let caller = caller.require(Scope::Read)?;
+    let tasks = state.store.lock(caller.user_id).await.tasks.clone();
+
+    let format = query.format;
+    let chunks = format.render(tasks).map(Ok::<_, Infallible>);
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", format.file_name()),
+            ),
+        ],
+        Body::from_stream(stream::iter(chunks)),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+
+    use super::super::tests::*;
+    use super::*;
+
+    fn tasks() -> Tasks {
+        Tasks {
+            tasks: vec![
+                Task {
+                    id: 1,
+                    summary: "(A) call mom +family @phone".to_string(),
+                },
+                Task {
+                    id: 2,
+                    summary: "buy milk, eggs\nand *bread*".to_string(),
+                },
+            ],
+            next_id: 3,
+        }
+    }
+
+    #[test]
+    fn unit_export_csv() {
+        let csv = ExportFormat::Csv.render(tasks()).collect::<String>();
+
+        assert_str_eq!(
+            csv,
+            "id,summary\n1,(A) call mom +family @phone\n2,\"buy milk, eggs\nand *bread*\"\n"
+        );
+
+        let formulas = Tasks {
+            tasks: [
+                "=HYPERLINK(\"http://example.com\")",
+                "@SUM(A1)",
+                "-1+2",
+                "\tx",
+            ]
+            .into_iter()
+            .zip(3..)
+            .map(|(summary, id)| Task {
+                id,
+                summary: summary.to_string(),
+            })
+            .collect(),
+            next_id: 7,
+        };
+        let csv = ExportFormat::Csv.render(formulas).collect::<String>();
+
+        assert_str_eq!(
+            csv,
+            "id,summary\n3,\"'=HYPERLINK(\"\"http://example.com\"\")\"\n4,'@SUM(A1)\n5,'-1+2\n6,'\tx\n"
+        );
+    }
+
+    #[test]
+    fn unit_export_markdown() {
+        let markdown = ExportFormat::Markdown.render(tasks()).collect::<String>();
+
+        assert_str_eq!(
+            markdown,
+            "# Tasks\n\n- [ ] (A) call mom +family @phone\n- [ ] buy milk, eggs and \\*bread\\*\n"
+        );
+    }
+
+    #[test]
+    fn unit_export_todo_txt() {
+        let todo_txt = ExportFormat::Todotxt.render(tasks()).collect::<String>();
+
+        assert_str_eq!(
+            todo_txt,
+            "(A) call mom +family @phone id:1\nbuy milk, eggs and *bread* id:2\n"
+        );
+    }
+
+    #[test]
+    fn unit_export_json_round_trips() {
+        let json = ExportFormat::Json.render(tasks()).collect::<String>();
+
+        assert_eq!(serde_json::from_str::<Tasks>(&json).unwrap(), tasks());
+    }
+
+    #[tokio::test]
+    async fn unit_export_download() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        let response = server
+            .get("/api/export")
+            .add_query_param("format", "md")
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!(
+            response.header(header::CONTENT_TYPE),
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(
+            response.header(header::CONTENT_DISPOSITION),
+            "attachment; filename=\"tasks.md\""
+        );
+        assert_str_eq!(response.text(), "# Tasks\n\n- [ ] buy milk\n");
+    }
+
+    #[tokio::test]
+    async fn unit_export_requires_login() {
+        let server = test_server_http();
+
+        let response = server.get("/api/export").await;
+
+        response.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unit_export_unknown_format() {
+        let server = test_server_http();
+        login_test_user(&server).await;
+
+        let response = server
+            .get("/api/export")
+            .add_query_param("format", "docx")
+            .await;
+
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,131 @@
+//! Endpoints for load balancers, uptime monitors and people to check on the server.
+//!
+//! They don't need a login, and they're left out of the request logs, since they're polled every
+//! few seconds.
+
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::AppState;
+use super::migrations::TASKS_VERSION;
+use super::paths;
+
+/// How long the storage can take to answer before the server isn't ready.
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Routes with their own state, so they can go outside the request logging.
+pub(super) fn routes(state: AppState) -> Router {
+    Router::new()
+        .route(paths::HEALTH, get(handle_health))
+        .route(paths::READY, get(handle_ready))
+        .route(paths::VERSION, get(handle_version))
+        .with_state(state)
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Readiness {
+    Ready,
+    ShuttingDown,
+    /// Users or tasks are locked up for too long to serve requests
+    StorageUnavailable,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, JsonSchema)]
+pub(super) struct Version {
+    version: String,
+    git_sha: String,
+    /// Seconds since the unix epoch
+    build_time: u64,
+    /// The version of the tasks in websocket messages, see `Tasks`
+    protocol_version: u64,
+}
+
+/// The process is up and answering requests.
+async fn handle_health() -> &'static str {
+    "ok"
+}
+
+/// Whether the server should be sent traffic.
+async fn handle_ready(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    if state.restarting.borrow().is_some() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Readiness::ShuttingDown),
+        );
+    }
+    let storage = async {
+        drop(state.users.lock().await);
+        state.store.reachable().await;
+    };
+    if tokio::time::timeout(STORAGE_TIMEOUT, storage)
+        .await
+        .is_err()
+    {
+        tracing::warn!("Not ready, the storage took over {STORAGE_TIMEOUT:?} to answer");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Readiness::StorageUnavailable),
+        );
+    }
+    (StatusCode::OK, Json(Readiness::Ready))
+}
+
+async fn handle_version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("GIT_SHA").to_string(),
+        build_time: env!("BUILD_TIME").parse().unwrap_or_default(),
+        protocol_version: TASKS_VERSION,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+
+    use super::super::tests::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn unit_probes_dont_need_a_login() {
+        let server = test_server();
+
+        server.get("/healthz").await.assert_status_ok();
+        let ready = server.get("/readyz").await;
+        ready.assert_status_ok();
+        assert_eq!(ready.json::<Readiness>(), Readiness::Ready);
+        let version = server.get("/version").await.json::<Version>();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(version.protocol_version, TASKS_VERSION);
+    }
+
+    #[tokio::test]
+    async fn unit_not_ready_while_shutting_down() {
+        let app_state = AppState::new([42; 64]);
+        let server = test_server_http_with_state(app_state.clone());
+        app_state
+            .restarting
+            .send_replace(Some(Duration::from_secs(5)));
+
+        let ready = server.get("/readyz").await;
+        ready.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ready.json::<Readiness>(), Readiness::ShuttingDown);
+        server.get("/healthz").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn unit_not_ready_while_storage_is_stuck() {
+        let app_state = AppState::new([42; 64]);
+        let server = test_server_http_with_state(app_state.clone());
+        let _stuck = app_state.users.lock().await;
+
+        let ready = server.get("/readyz").await;
+        ready.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ready.json::<Readiness>(), Readiness::StorageUnavailable);
+    }
+}
@@ -0,0 +1,683 @@
+//! A calendar feed of a user's tasks, for calendar apps to subscribe to.
+//!
+//! Calendar apps can't log in, so the feed lives at a secret URL instead. The secret is separate
+//! from sessions and API tokens: logging out doesn't break the subscription, and revoking or
+//! replacing the secret doesn't log anyone out.
+//!
+//! Tasks are rendered as RFC 5545 `VTODO`s. Summaries written in todo.txt syntax carry over their
+//! priority, `due:YYYY-MM-DD` date and `rec:` recurrence. With `?events=true`, tasks with a due
+//! date are also added as all-day `VEVENT`s, for calendars that don't show todos.
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::{get, post},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::export::one_line;
+use super::paths;
+use super::{AppState, Caller, Task};
+use crate::auth::{Scope, UserId, unix_now};
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            paths::FEED_SECRET,
+            post(handle_create_feed).delete(handle_revoke_feed),
+        )
+        .route(paths::FEED, get(handle_feed))
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct FeedResponse {
+    /// The only copy of the secret URL, relative to the server
+    url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FeedQuery {
+    #[serde(default)]
+    events: bool,
+}
+
+/// The parts of a todo.txt style summary a calendar understands.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(super) struct Details {
+    pub(super) summary: String,
+    /// 1 is the most urgent, as in RFC 5545
+    pub(super) priority: Option<u8>,
+    pub(super) due: Option<(u32, u32, u32)>,
+    pub(super) rrule: Option<String>,
+}
+
+impl Details {
+    pub(super) fn of(task: &Task) -> Self {
+        let mut details = Self::default();
+        let mut words = Vec::new();
+        for (i, word) in task.summary.split_whitespace().enumerate() {
+            if let Some(priority) = priority(word).filter(|_| i == 0) {
+                details.priority = Some(priority);
+            } else if let Some(due) = word.strip_prefix("due:").and_then(parse_date) {
+                details.due = Some(due);
+            } else if let Some(rrule) = word.strip_prefix("rec:").and_then(recurrence) {
+                details.rrule = Some(rrule);
+            } else {
+                words.push(word);
+            }
+        }
+        details.summary = words.join(" ");
+        details
+    }
+
+    /// Writes the details back as a todo.txt style summary, the way [`Details::of`] reads them.
+    pub(super) fn to_summary(&self) -> String {
+        let mut words = Vec::new();
+        if let Some(priority) = self.priority {
+            words.push(format!("({})", char::from(b'A' + priority - 1)));
+        }
+        if !self.summary.is_empty() {
+            words.push(self.summary.clone());
+        }
+        if let Some((year, month, day)) = self.due {
+            words.push(format!("due:{year:04}-{month:02}-{day:02}"));
+        }
+        if let Some(rec) = self.rrule.as_deref().and_then(rec_of) {
+            words.push(format!("rec:{rec}"));
+        }
+        words.join(" ")
+    }
+}
+
+/// A todo sent by a calendar app, cut down to what fits in a task.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct CalendarTodo {
+    pub(super) details: Details,
+    /// Calendar apps mark todos as done, where tasks are deleted instead
+    pub(super) done: bool,
+}
+
+/// todo.txt priorities go from `(A)` to `(Z)`, calendars from 1 to 9.
+fn priority(word: &str) -> Option<u8> {
+    match word.as_bytes() {
+        [b'(', letter @ b'A'..=b'Z', b')'] => Some((letter - b'A' + 1).min(9)),
+        _ => None,
+    }
+}
+
+fn parse_date(date: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = date.split('-');
+    let year = parts.next().filter(|year| year.len() == 4)?.parse().ok()?;
+    let month = parts
+        .next()
+        .filter(|month| month.len() == 2)?
+        .parse()
+        .ok()?;
+    let day = parts.next().filter(|day| day.len() == 2)?.parse().ok()?;
+    let valid = parts.next().is_none() && (1..=12).contains(&month) && (1..=31).contains(&day);
+    valid.then_some((year, month, day))
+}
+
+/// Turns a todo.txt `rec:` value like `2w` or `+1m` into an `RRULE`. The `+` means "from the due
+/// date rather than the completion date", which a calendar can't tell apart.
+fn recurrence(rec: &str) -> Option<String> {
+    let rec = rec.trim_start_matches('+');
+    let unit = rec.chars().last()?;
+    let interval = &rec[..rec.len() - unit.len_utf8()];
+    let interval: u32 = if interval.is_empty() {
+        1
+    } else {
+        interval.parse().ok().filter(|interval| *interval > 0)?
+    };
+    let frequency = match unit {
+        'd' => "DAILY",
+        'w' => "WEEKLY",
+        'm' => "MONTHLY",
+        'y' => "YEARLY",
+        _ => return None,
+    };
+    Some(format!("FREQ={frequency};INTERVAL={interval}"))
+}
+
+/// Turns an `RRULE` back into a todo.txt `rec:` value. Only the frequency and interval are kept,
+/// anything more specific like `BYDAY` can't be written in todo.txt.
+fn rec_of(rrule: &str) -> Option<String> {
+    let mut unit = None;
+    let mut interval = 1;
+    for part in rrule.split(';') {
+        match part.split_once('=')? {
+            ("FREQ", "DAILY") => unit = Some('d'),
+            ("FREQ", "WEEKLY") => unit = Some('w'),
+            ("FREQ", "MONTHLY") => unit = Some('m'),
+            ("FREQ", "YEARLY") => unit = Some('y'),
+            ("FREQ", _) => return None,
+            ("INTERVAL", value) => interval = value.parse().ok().filter(|n: &u32| *n > 0)?,
+            _ => {}
+        }
+    }
+    Some(format!("{interval}{}", unit?))
+}
+
+/// Escapes a `TEXT` value, see RFC 5545 section 3.3.11.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undoes [`escape`].
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Folds a content line so no line is longer than 75 octets, without splitting a character.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+fn date(year: u32, month: u32, day: u32) -> String {
+    format!("{year:04}{month:02}{day:02}")
+}
+
+/// A UTC `DATE-TIME`, like `19700101T000000Z`.
+fn timestamp(unix: u64) -> String {
+    let (year, month, day) = civil_from_days((unix / 86_400) as i64);
+    let seconds = unix % 86_400;
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        date(year, month, day),
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// The date a number of days after the unix epoch falls on.
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (u32, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year as u32, month as u32, day as u32)
+}
+
+pub(super) fn render_calendar(user_id: UserId, tasks: &[Task], events: bool, now: u64) -> String {
+    let stamp = timestamp(now);
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:-//{}//Tasks//EN", env!("CARGO_PKG_NAME")),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Tasks".to_string(),
+    ];
+    for task in tasks {
+        let details = Details::of(task);
+        let uid = format!("{}-{user_id:016x}@{}", task.id, env!("CARGO_PKG_NAME"));
+
+        lines.push("BEGIN:VTODO".to_string());
+        lines.push(format!("UID:todo-{uid}"));
+        lines.push(format!("DTSTAMP:{stamp}"));
+        lines.push(format!("SUMMARY:{}", escape(&details.summary)));
+        lines.push("STATUS:NEEDS-ACTION".to_string());
+        if let Some(priority) = details.priority {
+            lines.push(format!("PRIORITY:{priority}"));
+        }
+        if let Some((year, month, day)) = details.due {
+            // Recurrence is counted from `DTSTART`, so recurring todos start on their due date
+            if let Some(rrule) = &details.rrule {
+                lines.push(format!("DTSTART;VALUE=DATE:{}", date(year, month, day)));
+                lines.push(format!("RRULE:{rrule}"));
+            }
+            lines.push(format!("DUE;VALUE=DATE:{}", date(year, month, day)));
+        }
+        lines.push("END:VTODO".to_string());
+
+        if let Some((year, month, day)) = details.due.filter(|_| events) {
+            lines.push("BEGIN:VEVENT".to_string());
+            lines.push(format!("UID:event-{uid}"));
+            lines.push(format!("DTSTAMP:{stamp}"));
+            lines.push(format!("DTSTART;VALUE=DATE:{}", date(year, month, day)));
+            lines.push(format!("SUMMARY:{}", escape(&details.summary)));
+            lines.push("TRANSP:TRANSPARENT".to_string());
+            if let Some(rrule) = &details.rrule {
+                lines.push(format!("RRULE:{rrule}"));
+            }
+            lines.push("END:VEVENT".to_string());
+        }
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// Splits an unfolded content line like `DUE;VALUE=DATE:20250601` into its name and value,
+/// skipping any parameters. Parameter values can contain `:` if they are quoted.
+fn content_line(line: &str) -> Option<(&str, &str)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let name = line[..colon].split(';').next()?;
+    Some((name, &line[colon + 1..]))
+}
+
+/// A `DATE` or the date part of a `DATE-TIME`, like `20250601` or `20250601T090000Z`.
+fn parse_basic_date(value: &str) -> Option<(u32, u32, u32)> {
+    let date = value
+        .get(..8)
+        .filter(|date| date.bytes().all(|b| b.is_ascii_digit()))?;
+    parse_date(&format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]))
+        .filter(|_| value.len() == 8 || value.as_bytes()[8] == b'T')
+}
+
+/// Reads the first `VTODO` in an iCalendar object. Properties tasks have no room for are ignored.
+pub(super) fn parse_todo(calendar: &str) -> Option<CalendarTodo> {
+    let unfolded = calendar
+        .replace("\r\n ", "")
+        .replace("\r\n\t", "")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+    // `Some(false)` while inside the todo, and `Some(true)` once it has ended
+    let mut todo = None;
+    let mut details = Details::default();
+    let mut done = false;
+    for line in unfolded.lines() {
+        let Some((name, value)) = content_line(line.trim_end_matches('\r')) else {
+            continue;
+        };
+        let name = name.to_ascii_uppercase();
+        let in_todo = todo == Some(false);
+        match name.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VTODO") && todo.is_none() => todo = Some(false),
+            "END" if value.eq_ignore_ascii_case("VTODO") && in_todo => todo = Some(true),
+            "SUMMARY" if in_todo => details.summary = one_line(&unescape(value)),
+            "PRIORITY" if in_todo => {
+                details.priority = value.trim().parse().ok().filter(|p| (1..=9).contains(p));
+            }
+            "DUE" if in_todo => details.due = parse_basic_date(value.trim()),
+            "RRULE" if in_todo => {
+                details.rrule = rec_of(value.trim()).and_then(|rec| recurrence(&rec));
+            }
+            "STATUS" if in_todo => {
+                done = matches!(value.trim(), "COMPLETED" | "CANCELLED");
+            }
+            "COMPLETED" if in_todo => done = true,
+            _ => {}
+        }
+    }
+    todo.filter(|finished| *finished)
+        .map(|_| CalendarTodo { details, done })
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_create_feed(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<impl IntoResponse, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    let feed = state.users.lock().await.create_feed(caller.user_id);
+    let response = FeedResponse {
+        url: format!("/feed/{feed}.ics"),
+    };
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_revoke_feed(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<StatusCode, StatusCode> {
+    let caller = caller.require(Scope::Admin)?;
+    if state.users.lock().await.revoke_feed(caller.user_id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_feed(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    Query(query): Query<FeedQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let feed = file.strip_suffix(".ics").ok_or(StatusCode::NOT_FOUND)?;
+    let user_id = state
+        .users
+        .lock()
+        .await
+        .use_feed(feed)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let tasks = state.store.lock(user_id).await.tasks.clone();
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        render_calendar(user_id, &tasks.tasks, query.events, unix_now()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::{Value, json};
+
+    use super::super::tests::*;
+    use super::*;
+
+    fn task(summary: &str) -> Task {
+        Task {
+            id: 1,
+            summary: summary.to_string(),
+        }
+    }
+
+    #[test]
+    fn unit_todo_txt_details() {
+        assert_eq!(
+            Details::of(&task("(B) pay rent due:2025-03-01 rec:+1m +home")),
+            Details {
+                summary: "pay rent +home".to_string(),
+                priority: Some(2),
+                due: Some((2025, 3, 1)),
+                rrule: Some("FREQ=MONTHLY;INTERVAL=1".to_string()),
+            }
+        );
+        assert_eq!(
+            Details::of(&task("call (A) due:someday rec:often")),
+            Details {
+                summary: "call (A) due:someday rec:often".to_string(),
+                ..Details::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unit_details_to_summary() {
+        let summary = "(B) pay rent +home due:2025-03-01 rec:1m";
+
+        assert_eq!(Details::of(&task(summary)).to_summary(), summary);
+        assert_eq!(Details::default().to_summary(), "");
+    }
+
+    #[test]
+    fn unit_parse_todo() {
+        let calendar = "BEGIN:VCALENDAR\r\n\
+                        BEGIN:VTIMEZONE\r\n\
+                        TZID:Europe/Berlin\r\n\
+                        END:VTIMEZONE\r\n\
+                        BEGIN:VTODO\r\n\
+                        UID:abc\r\n\
+                        SUMMARY;LANGUAGE=en;ALTREP=\"http://x\":call\\; then\r\n  write\r\n\
+                        PRIORITY:0\r\n\
+                        DUE;TZID=Europe/Berlin:20250102T090000\r\n\
+                        RRULE:FREQ=DAILY;INTERVAL=3;COUNT=5\r\n\
+                        STATUS:IN-PROCESS\r\n\
+                        END:VTODO\r\n\
+                        END:VCALENDAR\r\n";
+
+        assert_eq!(
+            parse_todo(calendar),
+            Some(CalendarTodo {
+                details: Details {
+                    summary: "call; then write".to_string(),
+                    priority: None,
+                    due: Some((2025, 1, 2)),
+                    rrule: Some("FREQ=DAILY;INTERVAL=3".to_string()),
+                },
+                done: false,
+            })
+        );
+        assert_eq!(parse_todo("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"), None);
+        assert_eq!(parse_todo("BEGIN:VTODO\r\nSUMMARY:unfinished\r\n"), None);
+    }
+
+    #[test]
+    fn unit_escape_and_fold() {
+        assert_eq!(escape("a, b; c\\d\ne"), "a\\, b\\; c\\\\d\\ne");
+
+        let line = format!("SUMMARY:{}", "é".repeat(50));
+        let folded = fold(&line);
+        assert!(folded.ends_with("\r\n"));
+        for part in folded.trim_end_matches("\r\n").split("\r\n") {
+            assert!(part.len() <= 75, "{part:?} is {} octets", part.len());
+        }
+        assert_eq!(folded.replace("\r\n ", "").trim_end(), line);
+    }
+
+    #[test]
+    fn unit_timestamp() {
+        assert_eq!(timestamp(0), "19700101T000000Z");
+        assert_eq!(timestamp(1_700_000_000), "20231114T221320Z");
+        assert_eq!(timestamp(951_825_600), "20000229T120000Z");
+    }
+
+    #[test]
+    fn unit_render_calendar() {
+        let tasks = [Task {
+            id: 3,
+            summary: "(A) water plants, daily due:2025-06-01 rec:2d".to_string(),
+        }];
+
+        let calendar = render_calendar(0xab, &tasks, true, 0);
+
+        assert_str_eq!(
+            calendar,
+            "BEGIN:VCALENDAR\r\n\
+             VERSION:2.0\r\n\
+             PRODID:-//rust-elm//Tasks//EN\r\n\
+             CALSCALE:GREGORIAN\r\n\
+             X-WR-CALNAME:Tasks\r\n\
+             BEGIN:VTODO\r\n\
+             UID:todo-3-00000000000000ab@rust-elm\r\n\
+             DTSTAMP:19700101T000000Z\r\n\
+             SUMMARY:water plants\\, daily\r\n\
+             STATUS:NEEDS-ACTION\r\n\
+             PRIORITY:1\r\n\
+             DTSTART;VALUE=DATE:20250601\r\n\
+             RRULE:FREQ=DAILY;INTERVAL=2\r\n\
+             DUE;VALUE=DATE:20250601\r\n\
+             END:VTODO\r\n\
+             BEGIN:VEVENT\r\n\
+             UID:event-3-00000000000000ab@rust-elm\r\n\
+             DTSTAMP:19700101T000000Z\r\n\
+             DTSTART;VALUE=DATE:20250601\r\n\
+             SUMMARY:water plants\\, daily\r\n\
+             TRANSP:TRANSPARENT\r\n\
+             RRULE:FREQ=DAILY;INTERVAL=2\r\n\
+             END:VEVENT\r\n\
+             END:VCALENDAR\r\n"
+        );
+    }
+
+    async fn create_feed(
+        server: &axum_test::TestServer,
+        login: &axum_test::TestResponse,
+    ) -> String {
+        let response = server.post("/api/feed").with_csrf(login).await;
+        response.assert_status(StatusCode::CREATED);
+        response.json::<Value>()["url"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn unit_feed_outlives_session() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk due:2025-01-02" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let url = create_feed(&server, &login).await;
+
+        server
+            .post("/api/logout")
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        let response = server.get(&url).await;
+
+        response.assert_status_ok();
+        assert_eq!(
+            response.header(header::CONTENT_TYPE),
+            "text/calendar; charset=utf-8"
+        );
+        let calendar = response.text();
+        assert!(calendar.contains("SUMMARY:buy milk\r\n"));
+        assert!(calendar.contains("DUE;VALUE=DATE:20250102\r\n"));
+        assert!(!calendar.contains("BEGIN:VEVENT"));
+    }
+
+    #[tokio::test]
+    async fn unit_feed_can_be_revoked_and_replaced() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let old = create_feed(&server, &login).await;
+
+        let new = create_feed(&server, &login).await;
+        server.get(&old).await.assert_status(StatusCode::NOT_FOUND);
+        server.get(&new).await.assert_status_ok();
+
+        server
+            .delete("/api/feed")
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server.get(&new).await.assert_status(StatusCode::NOT_FOUND);
+        server
+            .delete("/api/feed")
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unit_feed_rejects_bad_secrets() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let url = create_feed(&server, &login).await;
+
+        let tampered = url.replace(".ics", "0.ics");
+        server
+            .get(&tampered)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        let without_suffix = url.trim_end_matches(".ics");
+        server
+            .get(without_suffix)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    /// Collects everything a subscriber writes, to check what would end up in the logs.
+    #[derive(Clone, Default)]
+    struct Logs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_feed_secret_stays_out_of_logs() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let url = create_feed(&server, &login).await;
+        let secret = url
+            .trim_start_matches("/feed/")
+            .trim_end_matches(".ics")
+            .to_string();
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let guard = tracing::subscriber::set_default(subscriber);
+        server.get(&url).await.assert_status_ok();
+        drop(guard);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(!logs.contains(&secret), "{logs}");
+        assert!(logs.contains(r#"route="/feed/{file}""#), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn unit_feed_needs_admin() {
+        let server = test_server_http();
+        let (_, token) = logged_in_token(&server, "write").await;
+
+        let response = server.post("/api/feed").authorization_bearer(token).await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,944 @@
+//! Uploads of tasks from other todo apps, so moving off Todoist or Nozbe doesn't mean retyping
+//! everything.
+//!
+//! An upload is only previewed unless `confirm=true` is sent: the response lists the tasks that
+//! would be added and warnings about anything that can't be carried over. Tasks only have a
+//! summary, so priorities, projects and labels are written into it in todo.txt syntax, like
+//! `(A) call mom +family @phone`, the same way the export keeps them. Completed tasks are skipped,
+//! and so are tasks that already exist with the same summary, so importing twice is harmless.
+
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use tracing::instrument;
+
+use super::export::{csv_text, one_line};
+use super::paths;
+use super::tasks_api::{TaskError, TaskInput};
+use super::{AppState, Caller, ErrorResponse, MessageLimits};
+use crate::auth::Scope;
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new().route(paths::IMPORT, post(handle_import))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum ImportFormat {
+    #[serde(alias = "todo.txt")]
+    Todotxt,
+    Csv,
+    /// Todoist's CSV project templates, or the JSON its sync API returns
+    Todoist,
+    /// Nozbe's JSON or CSV exports
+    Nozbe,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    format: ImportFormat,
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum ImportError {
+    InvalidCsv,
+    InvalidJson,
+    NoSummaryColumn,
+    /// A task has the biggest id there is, so no new task can get one
+    NoIdsLeft,
+    /// There would be more tasks than the server keeps for a user
+    TooManyTasks,
+    /// A summary is longer than the server allows
+    SummaryTooLong,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub(super) struct ImportWarning {
+    /// The line, row or item the warning is about, counting from 1
+    item: usize,
+    message: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct ImportResponse {
+    /// Whether the tasks were added, or this is only a preview
+    imported: bool,
+    tasks: Vec<TaskInput>,
+    warnings: Vec<ImportWarning>,
+}
+
+/// The tasks found in an upload, with the item each came from.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Parsed {
+    tasks: Vec<(usize, String)>,
+    warnings: Vec<ImportWarning>,
+}
+
+impl Parsed {
+    fn warn(&mut self, item: usize, message: impl Into<String>) {
+        self.warnings.push(ImportWarning {
+            item,
+            message: message.into(),
+        });
+    }
+
+    fn add(&mut self, item: usize, entry: Entry) {
+        let summary = entry.summary();
+        if entry.text.trim().is_empty() {
+            self.warn(item, "Tasks without a summary aren't imported");
+        } else {
+            self.tasks.push((item, summary));
+        }
+    }
+}
+
+type ParseResult = Result<Parsed, ErrorResponse<ImportError>>;
+
+fn parse(format: ImportFormat, input: &str) -> ParseResult {
+    let is_json = input.trim_start().starts_with(['{', '[']);
+    match format {
+        ImportFormat::Todotxt => Ok(parse_todo_txt(input)),
+        ImportFormat::Csv => parse_csv(input, letter_priority),
+        ImportFormat::Todoist if is_json => parse_todoist_json(input),
+        ImportFormat::Todoist => parse_csv(input, todoist_priority),
+        ImportFormat::Nozbe if is_json => parse_nozbe_json(input),
+        ImportFormat::Nozbe => parse_csv(input, letter_priority),
+    }
+}
+
+/// A task on its way to becoming a todo.txt style summary.
+#[derive(Debug, Default)]
+struct Entry {
+    text: String,
+    priority: Option<char>,
+    projects: Vec<String>,
+    contexts: Vec<String>,
+}
+
+impl Entry {
+    fn summary(&self) -> String {
+        let mut words = Vec::new();
+        if let Some(priority) = self.priority {
+            words.push(format!("({priority})"));
+        }
+        words.push(one_line(&self.text));
+        words.extend(self.projects.iter().filter_map(|project| tag('+', project)));
+        words.extend(self.contexts.iter().filter_map(|context| tag('@', context)));
+        words.retain(|word| !word.is_empty());
+        words.join(" ")
+    }
+}
+
+/// todo.txt tags can't have spaces, so they are joined with dashes.
+fn tag(sigil: char, name: &str) -> Option<String> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join("-");
+    let name = name.trim_start_matches(sigil);
+    (!name.is_empty()).then(|| format!("{sigil}{name}"))
+}
+
+fn is_date(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, byte)| match i {
+            4 | 7 => *byte == b'-',
+            _ => byte.is_ascii_digit(),
+        })
+}
+
+fn parse_todo_txt(input: &str) -> Parsed {
+    let mut parsed = Parsed::default();
+    for (i, line) in input.lines().enumerate() {
+        let item = i + 1;
+        let mut words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words[0] == "x" {
+            parsed.warn(item, "Completed tasks aren't imported");
+            continue;
+        }
+        let priority = letter_priority(words[0].trim_start_matches('(').trim_end_matches(')'))
+            .filter(|_| words[0].len() == 3 && words[0].starts_with('('));
+        if priority.is_some() {
+            words.remove(0);
+        }
+        if words.first().is_some_and(|word| is_date(word)) {
+            words.remove(0);
+            parsed.warn(item, "Creation dates aren't kept");
+        }
+        // Ids from our own export, the server picks new ones
+        words.retain(|word| !word.starts_with("id:"));
+        let entry = Entry {
+            text: words.join(" "),
+            priority,
+            ..Entry::default()
+        };
+        parsed.add(item, entry);
+    }
+    parsed
+}
+
+fn letter_priority(value: &str) -> Option<char> {
+    let mut chars = value.trim().chars();
+    match (chars.next(), chars.next()) {
+        (Some(letter), None) if letter.is_ascii_alphabetic() => Some(letter.to_ascii_uppercase()),
+        _ => None,
+    }
+}
+
+/// Todoist numbers priorities backwards, 4 is its most urgent `p1`.
+fn todoist_priority(value: &str) -> Option<char> {
+    match value.trim() {
+        "4" => Some('A'),
+        "3" => Some('B'),
+        "2" => Some('C'),
+        _ => None,
+    }
+}
+
+fn truthy(value: &str) -> bool {
+    matches!(
+        value.trim().to_lowercase().as_str(),
+        "1" | "true" | "yes" | "x" | "done" | "completed"
+    )
+}
+
+fn truthy_json(value: &Value) -> bool {
+    match value {
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64().is_some_and(|number| number != 0.0),
+        Value::String(value) => truthy(value),
+        _ => false,
+    }
+}
+
+fn invalid(error: ImportError, message: String) -> ErrorResponse<ImportError> {
+    ErrorResponse { error, message }
+}
+
+/// Why an item can't be added, which fails the whole import.
+fn wont_fit(item: usize, error: TaskError, limits: &MessageLimits) -> Response {
+    let status = StatusCode::from(error);
+    let (error, message) = match error {
+        TaskError::TooManyTasks => (
+            ImportError::TooManyTasks,
+            format!(
+                "Item {item} would make more than {} tasks",
+                limits.max_tasks
+            ),
+        ),
+        TaskError::SummaryTooLong => (
+            ImportError::SummaryTooLong,
+            format!(
+                "Item {item} is longer than {} characters",
+                limits.max_summary_length
+            ),
+        ),
+        // Creating a task never looks one up, so it can't be missing
+        TaskError::NoIdsLeft | TaskError::NotFound => (
+            ImportError::NoIdsLeft,
+            format!("There's no id left for item {item}"),
+        ),
+    };
+    (status, Json(invalid(error, message))).into_response()
+}
+
+fn parse_csv(input: &str, priority: fn(&str) -> Option<char>) -> ParseResult {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|error| invalid(ImportError::InvalidCsv, error.to_string()))?
+        .clone();
+    let column = |names: &[&str]| {
+        headers.iter().position(|header| {
+            names
+                .iter()
+                .any(|name| header.trim().eq_ignore_ascii_case(name))
+        })
+    };
+    let summary_column =
+        column(&["summary", "content", "title", "name", "task"]).ok_or_else(|| {
+            invalid(
+                ImportError::NoSummaryColumn,
+                "None of the columns look like a task summary, name one `summary`".to_string(),
+            )
+        })?;
+    let type_column = column(&["type"]);
+    let priority_column = column(&["priority"]);
+    let project_column = column(&["project", "list"]);
+    let context_column = column(&["context", "contexts", "labels", "tags"]);
+    let done_column = column(&["completed", "done", "checked"]);
+    // Ids from our own export are dropped without a warning, the server picks new ones
+    let id_column = column(&["id"]);
+    let used = [
+        id_column,
+        Some(summary_column),
+        type_column,
+        priority_column,
+        project_column,
+        context_column,
+        done_column,
+    ];
+
+    let mut parsed = Parsed::default();
+    // The first row each unused column had a value in
+    let mut unused = BTreeMap::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                let item = error
+                    .position()
+                    .map_or(0, |position| position.line() as usize);
+                parsed.warn(item, format!("Skipped a row that isn't valid CSV: {error}"));
+                continue;
+            }
+        };
+        let item = record
+            .position()
+            .map_or(0, |position| position.line() as usize);
+        let field =
+            |column: Option<usize>| column.and_then(|column| record.get(column)).unwrap_or("");
+        for (column, value) in record.iter().enumerate() {
+            if !used.contains(&Some(column)) && !value.trim().is_empty() {
+                unused.entry(column).or_insert(item);
+            }
+        }
+
+        let kind = field(type_column).trim();
+        if !kind.is_empty() && !kind.eq_ignore_ascii_case("task") {
+            parsed.warn(item, format!("`{kind}` rows aren't imported"));
+            continue;
+        }
+        if truthy(field(done_column)) {
+            parsed.warn(item, "Completed tasks aren't imported");
+            continue;
+        }
+        let priority_value = field(priority_column).trim();
+        let task_priority = priority(priority_value);
+        if task_priority.is_none() && !priority_value.is_empty() && priority_value != "1" {
+            parsed.warn(
+                item,
+                format!("The priority `{priority_value}` isn't understood"),
+            );
+        }
+        let entry = Entry {
+            text: csv_text(field(Some(summary_column))).to_string(),
+            priority: task_priority,
+            projects: vec![field(project_column).to_string()],
+            contexts: field(context_column)
+                .split(',')
+                .map(str::to_string)
+                .collect(),
+        };
+        parsed.add(item, entry);
+    }
+    for (column, item) in unused {
+        let name = headers.get(column).unwrap_or_default();
+        parsed.warn(item, format!("The `{name}` column isn't imported"));
+    }
+    parsed.warnings.sort_by_key(|warning| warning.item);
+    Ok(parsed)
+}
+
+/// Parses either a whole backup object, or just its list of tasks.
+fn parse_json<B: DeserializeOwned + Default, T: DeserializeOwned>(
+    input: &str,
+    tasks_of: fn(&mut B) -> &mut Vec<T>,
+) -> Result<B, ErrorResponse<ImportError>> {
+    let invalid_json =
+        |error: serde_json::Error| invalid(ImportError::InvalidJson, error.to_string());
+    if input.trim_start().starts_with('[') {
+        let mut backup = B::default();
+        *tasks_of(&mut backup) = serde_json::from_str(input).map_err(invalid_json)?;
+        Ok(backup)
+    } else {
+        serde_json::from_str(input).map_err(invalid_json)
+    }
+}
+
+/// An id in a backup, which some versions send as numbers and others as strings.
+fn id_key(id: &Value) -> String {
+    match id {
+        Value::String(id) => id.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Named {
+    #[serde(default)]
+    id: Value,
+    #[serde(default)]
+    name: String,
+}
+
+fn names(items: &[Named]) -> BTreeMap<String, &str> {
+    items
+        .iter()
+        .map(|item| (id_key(&item.id), item.name.as_str()))
+        .collect()
+}
+
+/// The parts of Todoist's sync API data that fit in a task.
+#[derive(Debug, Default, Deserialize)]
+struct TodoistBackup {
+    #[serde(default)]
+    items: Vec<TodoistItem>,
+    #[serde(default)]
+    projects: Vec<Named>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TodoistItem {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    priority: Value,
+    #[serde(default)]
+    checked: Value,
+    #[serde(default)]
+    is_deleted: Value,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(default)]
+    project_id: Value,
+    #[serde(default)]
+    due: Value,
+}
+
+fn parse_todoist_json(input: &str) -> ParseResult {
+    let backup = parse_json::<TodoistBackup, _>(input, |backup| &mut backup.items)?;
+    let projects = names(&backup.projects);
+    let mut parsed = Parsed::default();
+    for (i, task) in backup.items.into_iter().enumerate() {
+        let item = i + 1;
+        if truthy_json(&task.is_deleted) {
+            continue;
+        }
+        if truthy_json(&task.checked) {
+            parsed.warn(item, "Completed tasks aren't imported");
+            continue;
+        }
+        if !task.due.is_null() {
+            parsed.warn(item, "Due dates aren't kept");
+        }
+        let project = projects.get(&id_key(&task.project_id));
+        let entry = Entry {
+            text: task.content,
+            priority: todoist_priority(&id_key(&task.priority)),
+            projects: project.map(|name| name.to_string()).into_iter().collect(),
+            contexts: task.labels,
+        };
+        parsed.add(item, entry);
+    }
+    Ok(parsed)
+}
+
+/// The parts of a Nozbe export that fit in a task.
+#[derive(Debug, Default, Deserialize)]
+struct NozbeBackup {
+    #[serde(default)]
+    tasks: Vec<NozbeTask>,
+    #[serde(default)]
+    projects: Vec<Named>,
+    #[serde(default, alias = "categories")]
+    contexts: Vec<Named>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NozbeTask {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    completed: Value,
+    #[serde(default)]
+    star: Value,
+    #[serde(default)]
+    project_id: Value,
+    #[serde(default, alias = "con_list", alias = "category_ids")]
+    context_ids: Vec<Value>,
+    #[serde(default, alias = "datetime")]
+    due: Value,
+}
+
+fn parse_nozbe_json(input: &str) -> ParseResult {
+    let backup = parse_json::<NozbeBackup, _>(input, |backup| &mut backup.tasks)?;
+    let projects = names(&backup.projects);
+    let contexts = names(&backup.contexts);
+    let mut parsed = Parsed::default();
+    for (i, task) in backup.tasks.into_iter().enumerate() {
+        let item = i + 1;
+        if truthy_json(&task.completed) {
+            parsed.warn(item, "Completed tasks aren't imported");
+            continue;
+        }
+        if !task.due.is_null() {
+            parsed.warn(item, "Due dates aren't kept");
+        }
+        let project = projects.get(&id_key(&task.project_id));
+        let entry = Entry {
+            text: task.name,
+            // Starred is the only priority Nozbe has
+            priority: truthy_json(&task.star).then_some('A'),
+            projects: project.map(|name| name.to_string()).into_iter().collect(),
+            contexts: task
+                .context_ids
+                .iter()
+                .filter_map(|id| contexts.get(&id_key(id)))
+                .map(|name| name.to_string())
+                .collect(),
+        };
+        parsed.add(item, entry);
+    }
+    Ok(parsed)
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_import(
+    State(state): State<AppState>,
+    caller: Caller,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> Result<Response, Response> {
+    let caller = caller
+        .require(Scope::Write)
+        .map_err(IntoResponse::into_response)?;
+    let mut parsed = parse(query.format, &body)
+        .map_err(|error| (StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response())?;
+
+    let tasks = {
+        let mut shard = state.store.lock(caller.user_id).await;
+        let mut seen: HashSet<String> = shard
+            .tasks
+            .tasks
+            .iter()
+            .map(|task| task.summary.clone())
+            .collect();
+        let mut new_tasks = Vec::new();
+        // Added to a copy even for a preview, so it fails the same way the import would. All or
+        // nothing, in case the ids or the room for tasks run out part way.
+        let mut tasks = shard.tasks.clone();
+        for (item, summary) in std::mem::take(&mut parsed.tasks) {
+            if !seen.insert(summary.clone()) {
+                parsed.warn(item, "A task with the same summary already exists");
+                continue;
+            }
+            let input = TaskInput { summary };
+            tasks
+                .create(input.clone(), &state.message_limits)
+                .map_err(|error| wont_fit(item, error, &state.message_limits))?;
+            new_tasks.push(input);
+        }
+        if query.confirm && !new_tasks.is_empty() {
+            shard.tasks = tasks;
+            shard.broadcast();
+        }
+        new_tasks
+    };
+    parsed.warnings.sort_by_key(|warning| warning.item);
+    Ok(Json(ImportResponse {
+        imported: query.confirm,
+        tasks,
+        warnings: parsed.warnings,
+    })
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::super::export::ExportFormat;
+    use super::super::tests::*;
+    use super::super::{MessageLimits, OutMsg, Task, Tasks};
+    use super::*;
+
+    const FORMATS: [ImportFormat; 4] = [
+        ImportFormat::Todotxt,
+        ImportFormat::Csv,
+        ImportFormat::Todoist,
+        ImportFormat::Nozbe,
+    ];
+
+    fn summaries(parsed: &Parsed) -> Vec<&str> {
+        parsed
+            .tasks
+            .iter()
+            .map(|(_, summary)| summary.as_str())
+            .collect()
+    }
+
+    fn messages(parsed: &Parsed) -> Vec<(usize, &str)> {
+        parsed
+            .warnings
+            .iter()
+            .map(|warning| (warning.item, warning.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn unit_import_todo_txt() {
+        let input =
+            "(A) 2024-01-02 call mom +family @phone\n\nx 2024-01-01 done already\nbuy milk id:7\n";
+
+        let parsed = parse(ImportFormat::Todotxt, input).unwrap();
+
+        assert_eq!(
+            summaries(&parsed),
+            vec!["(A) call mom +family @phone", "buy milk"]
+        );
+        assert_eq!(
+            messages(&parsed),
+            vec![
+                (1, "Creation dates aren't kept"),
+                (3, "Completed tasks aren't imported"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unit_import_round_trips_export() {
+        let tasks = Tasks {
+            tasks: vec![
+                Task {
+                    id: 1,
+                    summary: "(B) water plants +home".to_string(),
+                },
+                Task {
+                    id: 2,
+                    summary: "buy milk".to_string(),
+                },
+                Task {
+                    id: 3,
+                    summary: "-20 push-ups".to_string(),
+                },
+            ],
+            next_id: 4,
+        };
+
+        for (export, import) in [
+            (ExportFormat::Todotxt, ImportFormat::Todotxt),
+            (ExportFormat::Csv, ImportFormat::Csv),
+        ] {
+            let exported = export.render(tasks.clone()).collect::<String>();
+            let parsed = parse(import, &exported).unwrap();
+
+            assert_eq!(
+                summaries(&parsed),
+                vec!["(B) water plants +home", "buy milk", "-20 push-ups"]
+            );
+            assert_eq!(messages(&parsed), vec![]);
+        }
+    }
+
+    #[test]
+    fn unit_import_csv() {
+        let input = "Title,Project,Tags,Done,Notes\nbuy milk,Home Stuff,\"shop, errand\",,semi skimmed\nold task,,,yes,\n";
+
+        let parsed = parse(ImportFormat::Csv, input).unwrap();
+
+        assert_eq!(
+            summaries(&parsed),
+            vec!["buy milk +Home-Stuff @shop @errand"]
+        );
+        assert_eq!(
+            messages(&parsed),
+            vec![
+                (2, "The `Notes` column isn't imported"),
+                (3, "Completed tasks aren't imported"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unit_import_csv_needs_a_summary() {
+        let error = parse(ImportFormat::Csv, "a,b\n1,2\n").unwrap_err();
+
+        assert_eq!(error.error, ImportError::NoSummaryColumn);
+    }
+
+    #[test]
+    fn unit_import_todoist_csv() {
+        let input = "TYPE,CONTENT,DESCRIPTION,PRIORITY,INDENT,AUTHOR,RESPONSIBLE,DATE,DATE_LANG,TIMEZONE\n\
+                     section,Groceries,,,,,,,,\n\
+                     task,buy milk @shop,,4,1,Me (1),,every day,en,\n\
+                     task,call mom,,1,1,Me (1),,,en,\n";
+
+        let parsed = parse(ImportFormat::Todoist, input).unwrap();
+
+        assert_eq!(summaries(&parsed), vec!["(A) buy milk @shop", "call mom"]);
+        assert_eq!(
+            messages(&parsed),
+            vec![
+                (2, "`section` rows aren't imported"),
+                (3, "The `INDENT` column isn't imported"),
+                (3, "The `AUTHOR` column isn't imported"),
+                (3, "The `DATE` column isn't imported"),
+                (3, "The `DATE_LANG` column isn't imported"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unit_import_todoist_json() {
+        let input = json!({
+            "projects": [{ "id": "220", "name": "Home" }],
+            "items": [
+                { "content": "water plants", "priority": 3, "project_id": "220", "labels": ["garden"], "checked": false },
+                { "content": "done", "checked": true },
+                { "content": "deleted", "is_deleted": true },
+                { "content": "pay rent", "due": { "date": "2025-01-01" } }
+            ]
+        })
+        .to_string();
+
+        let parsed = parse(ImportFormat::Todoist, &input).unwrap();
+
+        assert_eq!(
+            summaries(&parsed),
+            vec!["(B) water plants +Home @garden", "pay rent"]
+        );
+        assert_eq!(
+            messages(&parsed),
+            vec![
+                (2, "Completed tasks aren't imported"),
+                (4, "Due dates aren't kept"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unit_import_nozbe_json() {
+        let input = json!({
+            "projects": [{ "id": "p1", "name": "Work" }],
+            "contexts": [{ "id": "c1", "name": "Computer" }],
+            "tasks": [
+                { "name": "send report", "star": true, "project_id": "p1", "con_list": ["c1"] },
+                { "name": "", "completed": false },
+                { "name": "old", "completed": true }
+            ]
+        })
+        .to_string();
+
+        let parsed = parse(ImportFormat::Nozbe, &input).unwrap();
+
+        assert_eq!(summaries(&parsed), vec!["(A) send report +Work @Computer"]);
+        assert_eq!(
+            messages(&parsed),
+            vec![
+                (2, "Tasks without a summary aren't imported"),
+                (3, "Completed tasks aren't imported"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unit_import_invalid_json() {
+        let error = parse(ImportFormat::Nozbe, "{ \"tasks\": [").unwrap_err();
+
+        assert_eq!(error.error, ImportError::InvalidJson);
+    }
+
+    proptest! {
+        #[test]
+        fn unit_parsers_accept_any_text(input in any::<String>()) {
+            for format in FORMATS {
+                let _ = parse(format, &input);
+            }
+        }
+
+        #[test]
+        fn unit_parsers_accept_malformed_structure(
+            input in r#"[\[\]{}",:\n ()+@xA-Z0-9a-z_-]{0,200}"#
+        ) {
+            for format in FORMATS {
+                let _ = parse(format, &input);
+            }
+        }
+
+        #[test]
+        fn unit_parsed_summaries_are_one_line(input in any::<String>()) {
+            for format in FORMATS {
+                if let Ok(parsed) = parse(format, &input) {
+                    for (_, summary) in &parsed.tasks {
+                        prop_assert!(!summary.contains('\n'));
+                        prop_assert!(!summary.trim().is_empty());
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_import_preview_then_confirm() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let upload = "buy milk\ncall mom\n";
+
+        let preview = server
+            .post("/api/import")
+            .add_query_param("format", "todo.txt")
+            .with_csrf(&login)
+            .text(upload)
+            .await;
+        preview.assert_status_ok();
+        assert_eq!(
+            preview.json::<Value>(),
+            json!({
+                "imported": false,
+                "tasks": [{ "summary": "call mom" }],
+                "warnings": [{ "item": 1, "message": "A task with the same summary already exists" }]
+            })
+        );
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all.len(), 1);
+
+        let confirmed = server
+            .post("/api/import")
+            .add_query_param("format", "todo.txt")
+            .add_query_param("confirm", true)
+            .with_csrf(&login)
+            .text(upload)
+            .await;
+        confirmed.assert_status_ok();
+        assert_eq!(confirmed.json::<Value>()["imported"], json!(true));
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        let summaries: Vec<_> = all.iter().map(|task| task.summary.as_str()).collect();
+        assert_eq!(summaries, vec!["buy milk", "call mom"]);
+    }
+
+    #[tokio::test]
+    async fn unit_import_confirm_broadcasts() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = websocket.receive_outmsg().await;
+
+        server
+            .post("/api/import")
+            .add_query_param("format", "csv")
+            .add_query_param("confirm", true)
+            .with_csrf(&login)
+            .text("summary\ncall mom\n")
+            .await
+            .assert_status_ok();
+
+        let OutMsg::NewTasks(tasks) = websocket.receive_outmsg().await else {
+            panic!("expected tasks");
+        };
+        assert_eq!(tasks.tasks.len(), 1);
+        assert_eq!(tasks.tasks[0].summary, "call mom");
+    }
+
+    #[tokio::test]
+    async fn unit_import_keeps_to_the_limits() {
+        let limits = MessageLimits {
+            max_tasks: 1,
+            ..MessageLimits::default()
+        };
+        let server =
+            test_server_http_with_state(AppState::new([42; 64]).with_message_limits(limits));
+        let login = login_test_user(&server).await;
+
+        server
+            .post("/api/import")
+            .add_query_param("format", "csv")
+            .add_query_param("confirm", true)
+            .with_csrf(&login)
+            .text("summary\ncall mom\nwater the plants\n")
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+        let tasks = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(tasks, vec![]);
+    }
+
+    #[tokio::test]
+    async fn unit_import_preview_fails_like_the_import() {
+        let limits = MessageLimits {
+            max_summary_length: 10,
+            ..MessageLimits::default()
+        };
+        let server =
+            test_server_http_with_state(AppState::new([42; 64]).with_message_limits(limits));
+        let login = login_test_user(&server).await;
+        let upload = "call mom\nwater the plants on the balcony\n";
+
+        for confirm in [false, true] {
+            let response = server
+                .post("/api/import")
+                .add_query_param("format", "todo.txt")
+                .add_query_param("confirm", confirm)
+                .with_csrf(&login)
+                .text(upload)
+                .await;
+
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(
+                response.json::<Value>(),
+                json!({
+                    "error": "summary_too_long",
+                    "message": "Item 2 is longer than 10 characters"
+                })
+            );
+        }
+        let tasks = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(tasks, vec![]);
+    }
+
+    #[tokio::test]
+    async fn unit_import_rejects_unparseable_files() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+
+        let response = server
+            .post("/api/import")
+            .add_query_param("format", "todoist")
+            .with_csrf(&login)
+            .text("{ not json")
+            .await;
+
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.json::<Value>()["error"], json!("invalid_json"));
+    }
+
+    #[tokio::test]
+    async fn unit_import_needs_write_scope() {
+        let server = test_server_http();
+        let (_, token) = logged_in_token(&server, "read").await;
+
+        let response = server
+            .post("/api/import")
+            .add_query_param("format", "csv")
+            .authorization_bearer(token)
+            .text("summary\ncall mom\n")
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+}
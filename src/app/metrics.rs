@@ -0,0 +1,320 @@
+//! Prometheus metrics, served at `/metrics` in the OpenMetrics text format.
+//!
+//! Operators can ask for a bearer token with `METRICS_TOKEN`, or serve the endpoint on an address
+//! of its own with `METRICS_ADDRESS`, one that isn't reachable from outside, say.
+
+use axum::{
+    Router,
+    extract::{MatchedPath, Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+
+use super::AppState;
+use super::paths;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub(super) struct RequestLabels {
+    /// The route's pattern, so ids don't make a series each
+    route: String,
+    method: &'static str,
+    status: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub(super) struct MessageLabels {
+    /// The `InMsg` action, or `invalid` for ones that couldn't be read and `rejected` for ones over
+    /// the limits
+    pub(super) kind: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub(super) struct LoginLabels {
+    pub(super) success: bool,
+}
+
+type HistogramFamily<S> = Family<S, Histogram, fn() -> Histogram>;
+
+pub(super) struct Metrics {
+    registry: Registry,
+    requests: HistogramFamily<RequestLabels>,
+    pub(super) messages: Family<MessageLabels, Counter>,
+    pub(super) logins: Family<LoginLabels, Counter>,
+    /// Shared with the store, which times its broadcasts
+    pub(super) broadcasts: Histogram,
+    /// Read from the store whenever the metrics are
+    connections: Gauge,
+    tasks: Gauge,
+}
+
+/// From a millisecond to about 16 seconds.
+pub(super) fn duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 2.0, 15))
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::with_prefix("rust_elm");
+        let requests = HistogramFamily::new_with_constructor(duration_histogram as fn() -> _);
+        registry.register(
+            "http_request_duration_seconds",
+            "HTTP requests, and how long they took to answer",
+            requests.clone(),
+        );
+        let messages = Family::default();
+        registry.register(
+            "websocket_messages",
+            "Messages received over websockets",
+            messages.clone(),
+        );
+        let logins = Family::default();
+        registry.register("logins", "Attempts to log in", logins.clone());
+        let broadcasts = duration_histogram();
+        registry.register(
+            "broadcast_duration_seconds",
+            "How long queueing a user's tasks for all their websockets took",
+            broadcasts.clone(),
+        );
+        let connections = Gauge::default();
+        registry.register(
+            "websocket_connections",
+            "Open websockets",
+            connections.clone(),
+        );
+        let tasks = Gauge::default();
+        registry.register("tasks", "Tasks stored, for every user", tasks.clone());
+        Self {
+            registry,
+            requests,
+            messages,
+            logins,
+            broadcasts,
+            connections,
+            tasks,
+        }
+    }
+}
+
+/// Routes with their own state, so they can be served on an address of their own.
+pub(super) fn routes(state: AppState) -> Router {
+    Router::new()
+        .route(paths::METRICS, get(handle_metrics))
+        .with_state(state)
+}
+
+/// Counts and times every request, by the route that answered it.
+pub(super) async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        // Static files
+        .unwrap_or_else(|| "fallback".to_string());
+    let method = method_label(request.method());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let labels = RequestLabels {
+        route,
+        method,
+        status: response.status().as_u16(),
+    };
+    state
+        .metrics
+        .requests
+        .get_or_create(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// The methods the server answers, and `other` for the rest. DAV routes take any method, and
+/// they're counted before anyone logs in, so made-up ones mustn't make a series each.
+fn method_label(method: &Method) -> &'static str {
+    match method.as_str() {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "PATCH" => "PATCH",
+        "DELETE" => "DELETE",
+        "OPTIONS" => "OPTIONS",
+        "PROPFIND" => "PROPFIND",
+        "REPORT" => "REPORT",
+        _ => "other",
+    }
+}
+
+async fn handle_metrics(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Response, StatusCode> {
+    if let Some(token) = state.metrics_token {
+        let TypedHeader(Authorization(bearer)) = bearer.ok_or(StatusCode::UNAUTHORIZED)?;
+        // Comparing hashes, so how long it takes doesn't give away how much of the token was right
+        if <[u8; 32]>::from(Sha256::digest(bearer.token())) != token {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let metrics = &state.metrics;
+    metrics
+        .connections
+        .set(state.store.connections().await as i64);
+    metrics.tasks.set(state.store.task_count().await as i64);
+    let mut body = String::new();
+    encode(&mut body, &metrics.registry).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_test::TestServer;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+
+    use super::super::tests::*;
+    use super::super::{InMsg, Tasks, make_app};
+    use super::*;
+
+    fn server_with(app_state: AppState) -> TestServer {
+        let app = make_app(std::env::temp_dir(), app_state.clone()).merge(routes(app_state));
+        let mut config = axum_test::TestServerConfig::new();
+        config.save_cookies = true;
+        config.transport = Some(axum_test::Transport::HttpRandomPort);
+        TestServer::new_with_config(app, config).unwrap()
+    }
+
+    /// The value of a sample, by its name and labels as they're written out.
+    fn sample(metrics: &str, series: &str) -> Option<f64> {
+        metrics.lines().find_map(|line| {
+            let value = line.strip_prefix(series)?.strip_prefix(' ')?;
+            value.parse().ok()
+        })
+    }
+
+    #[tokio::test]
+    async fn unit_metrics_are_counted() {
+        let server = server_with(AppState::new([42; 64]));
+        server
+            .post("/api/login")
+            .json(&json!({ "username": "nobody", "password": "wrong" }))
+            .await;
+        let login = login_test_user(&server).await;
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        websocket.receive_outmsg().await;
+        websocket
+            .send_inmsg(InMsg::Tasks(Tasks::single_task()))
+            .await;
+        websocket.receive_outmsg().await;
+        websocket.send_text("not a message").await;
+
+        let response = server.get("/metrics").await;
+        response.assert_status_ok();
+        let metrics = response.text();
+
+        let count = |series: &str| sample(&metrics, series).unwrap_or_default();
+        assert_eq!(count("rust_elm_logins_total{success=\"false\"}"), 1.0);
+        assert_eq!(count("rust_elm_logins_total{success=\"true\"}"), 1.0);
+        assert_eq!(count("rust_elm_websocket_connections"), 1.0);
+        assert_eq!(count("rust_elm_tasks"), 1.0);
+        assert_eq!(
+            count("rust_elm_websocket_messages_total{kind=\"tasks\"}"),
+            1.0
+        );
+        assert_eq!(
+            count("rust_elm_websocket_messages_total{kind=\"invalid\"}"),
+            1.0
+        );
+        assert_eq!(
+            count(
+                "rust_elm_http_request_duration_seconds_count{route=\"/api/tasks\",method=\"POST\",status=\"201\"}"
+            ),
+            1.0
+        );
+        // For the task created over HTTP, and the ones sent over the websocket
+        assert!(count("rust_elm_broadcast_duration_seconds_count") >= 2.0);
+    }
+
+    #[tokio::test]
+    async fn unit_metrics_bound_request_methods() {
+        let server = server_with(AppState::new([42; 64]));
+        for method in ["RANDOM1", "RANDOM2"] {
+            let method = Method::from_bytes(method.as_bytes()).unwrap();
+            server.method(method, "/dav/x").await;
+        }
+        server.method(Method::PUT, "/dav/x").await;
+
+        let metrics = server.get("/metrics").await.text();
+
+        assert!(!metrics.contains("RANDOM"), "{metrics}");
+        assert_eq!(
+            sample(
+                &metrics,
+                "rust_elm_http_request_duration_seconds_count{route=\"/dav/{*path}\",method=\"other\",status=\"401\"}"
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            sample(
+                &metrics,
+                "rust_elm_http_request_duration_seconds_count{route=\"/dav/{*path}\",method=\"PUT\",status=\"401\"}"
+            ),
+            Some(1.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn unit_metrics_can_need_a_token() {
+        let server =
+            server_with(AppState::new([42; 64]).with_metrics_token(Some("scraper".to_string())));
+
+        server
+            .get("/metrics")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/metrics")
+            .authorization_bearer("wrong")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/metrics")
+            .authorization_bearer("scraper")
+            .await
+            .assert_status_ok();
+    }
+}
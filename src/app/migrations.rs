@@ -0,0 +1,197 @@
+//! Versions of the format tasks are stored and sent in, and how to bring old ones up to date.
+//!
+//! Tasks are always written in the current version, with a `version` field saying which one that
+//! is. Whenever they're read, whether from a backup or from a client that hasn't reloaded since a
+//! deploy, older versions are migrated one version at a time until they're current. Tasks from
+//! before there were versions have no `version` field, and count as version 0.
+//!
+//! To change the format, bump [`TASKS_VERSION`], add a migration from the previous version to
+//! [`MIGRATIONS`], and add a fixture of the previous version to the tests.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{Task, TaskId, Tasks};
+
+/// The version tasks are written in.
+pub(super) const TASKS_VERSION: u64 = 1;
+
+/// Changes the fields of tasks in one version into the next. The version number is updated after.
+type Migration = fn(Value) -> Result<Value, String>;
+
+/// `MIGRATIONS[n]` turns version `n` into version `n + 1`.
+const MIGRATIONS: [Migration; TASKS_VERSION as usize] = [unversioned];
+
+/// Version 1 only added the `version` field.
+fn unversioned(tasks: Value) -> Result<Value, String> {
+    Ok(tasks)
+}
+
+/// The tasks, as they are stored and sent.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) struct CurrentTasks {
+    /// The version of the format. The server migrates older versions, and always sends the latest.
+    version: u64,
+    tasks: Vec<Task>,
+    next_id: TaskId,
+}
+
+impl From<Tasks> for CurrentTasks {
+    fn from(tasks: Tasks) -> Self {
+        Self {
+            version: TASKS_VERSION,
+            tasks: tasks.tasks,
+            next_id: tasks.next_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum MigrationError {
+    NotAnObject,
+    InvalidVersion,
+    /// From a newer server, which there's no way to migrate back from
+    NewerVersion(u64),
+    Failed {
+        from: u64,
+        reason: String,
+    },
+    Invalid(String),
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAnObject => write!(f, "tasks must be an object"),
+            Self::InvalidVersion => write!(f, "the tasks' version isn't a whole number"),
+            Self::NewerVersion(version) => write!(
+                f,
+                "the tasks are in version {version}, but this server only understands up to \
+                 version {TASKS_VERSION}"
+            ),
+            Self::Failed { from, reason } => write!(
+                f,
+                "couldn't migrate tasks from version {from} to {}: {reason}",
+                from + 1
+            ),
+            Self::Invalid(reason) => write!(f, "invalid tasks: {reason}"),
+        }
+    }
+}
+
+impl TryFrom<Value> for Tasks {
+    type Error = MigrationError;
+
+    fn try_from(mut tasks: Value) -> Result<Self, Self::Error> {
+        let object = tasks.as_object().ok_or(MigrationError::NotAnObject)?;
+        let version = match object.get("version") {
+            Some(version) => version.as_u64().ok_or(MigrationError::InvalidVersion)?,
+            None => 0,
+        };
+        if version > TASKS_VERSION {
+            return Err(MigrationError::NewerVersion(version));
+        }
+        for (from, migration) in (version..).zip(&MIGRATIONS[version as usize..]) {
+            tasks = migration(tasks).map_err(|reason| MigrationError::Failed { from, reason })?;
+            let object = tasks.as_object_mut().ok_or(MigrationError::NotAnObject)?;
+            object.insert("version".to_string(), json!(from + 1));
+        }
+        let current: CurrentTasks = serde_json::from_value(tasks)
+            .map_err(|error| MigrationError::Invalid(error.to_string()))?;
+        Ok(Tasks {
+            tasks: current.tasks,
+            next_id: current.next_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+
+    use super::super::tests::*;
+    use super::super::{InMsg, OutMsg};
+    use super::*;
+
+    /// Tasks as every version of the server wrote them, oldest first.
+    const FIXTURES: [&str; TASKS_VERSION as usize + 1] = [
+        include_str!("fixtures/tasks-v0.json"),
+        include_str!("fixtures/tasks-v1.json"),
+    ];
+
+    fn expected() -> Tasks {
+        Tasks {
+            tasks: vec![
+                Task {
+                    id: 3,
+                    summary: "(A) call mom +family @phone".to_string(),
+                },
+                Task {
+                    id: 7,
+                    summary: "buy milk".to_string(),
+                },
+            ],
+            next_id: 8,
+        }
+    }
+
+    #[test]
+    fn unit_every_version_loads() {
+        for (version, fixture) in FIXTURES.iter().enumerate() {
+            let tasks: Tasks = serde_json::from_str(fixture)
+                .unwrap_or_else(|error| panic!("version {version} didn't load: {error}"));
+
+            assert_eq!(tasks, expected(), "version {version}");
+        }
+    }
+
+    #[test]
+    fn unit_every_version_round_trips_to_the_latest() {
+        let latest: Value = serde_json::from_str(FIXTURES[TASKS_VERSION as usize]).unwrap();
+
+        for fixture in FIXTURES {
+            let tasks: Tasks = serde_json::from_str(fixture).unwrap();
+            let written = serde_json::to_value(&tasks).unwrap();
+
+            assert_eq!(written, latest);
+            assert_eq!(serde_json::from_value::<Tasks>(written).unwrap(), tasks);
+        }
+    }
+
+    #[test]
+    fn unit_refuses_unknown_versions() {
+        let newer = json!({ "version": TASKS_VERSION + 1, "tasks": [], "next_id": 0 });
+        let invalid = json!({ "version": "one", "tasks": [], "next_id": 0 });
+
+        assert_eq!(
+            Tasks::try_from(newer),
+            Err(MigrationError::NewerVersion(TASKS_VERSION + 1))
+        );
+        assert_eq!(
+            Tasks::try_from(invalid),
+            Err(MigrationError::InvalidVersion)
+        );
+        assert_eq!(Tasks::try_from(json!([])), Err(MigrationError::NotAnObject));
+    }
+
+    #[tokio::test]
+    async fn unit_old_websocket_messages_are_migrated() {
+        let server = test_server_http();
+        login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        websocket.receive_outmsg().await;
+
+        let old_message = format!(r#"{{"action":"tasks","payload":{}}}"#, FIXTURES[0]);
+        websocket.send_text(&old_message).await;
+
+        assert_eq!(
+            websocket.receive_outmsg().await,
+            OutMsg::NewTasks(expected())
+        );
+        let InMsg::Tasks(tasks) = serde_json::from_str(&old_message).unwrap();
+        assert_eq!(tasks, expected());
+    }
+}
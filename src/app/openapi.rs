@@ -0,0 +1,654 @@
+//! An OpenAPI 3.1 description of the HTTP API, served at `/api/openapi.json`.
+//!
+//! The schemas are generated from the same types the handlers use, so they can't drift. The paths
+//! are written out by hand, keyed by the same [`paths`] the router uses, and a test checks every
+//! route and method the app serves is documented.
+//! The websocket messages aren't part of OpenAPI, so their schemas are listed in `components` too
+//! and pointed to from the `/ws` operation.
+
+use axum::{Json, Router, routing::get};
+use schemars::{JsonSchema, Schema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Value, json};
+use std::sync::OnceLock;
+
+use super::export::ExportFormat;
+use super::health::{Readiness, Version};
+use super::ical::FeedResponse;
+use super::import::{ImportError, ImportFormat, ImportResponse};
+use super::paths;
+use super::tasks_api::{BulkRequest, BulkResponse, TaskInput};
+use super::{
+    AppState, CreateTokenRequest, ErrorResponse, InMsg, OutMsg, RegisterRequest, SessionResponse,
+    Task, TaskId, Tasks, TokenResponse,
+};
+use crate::policy::AccountCreationError;
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new().route(paths::OPENAPI, get(handle_openapi))
+}
+
+async fn handle_openapi() -> Json<Value> {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    Json(DOCUMENT.get_or_init(openapi).clone())
+}
+
+/// Collects schemas for types into `#/components/schemas`.
+struct Schemas(SchemaGenerator);
+
+impl Schemas {
+    fn new() -> Self {
+        let settings = SchemaSettings::draft2020_12().with(|settings| {
+            settings.definitions_path = "/components/schemas".into();
+            settings.meta_schema = None;
+        });
+        Self(settings.into_generator())
+    }
+
+    fn of<T: JsonSchema>(&mut self) -> Schema {
+        self.0.subschema_for::<T>()
+    }
+
+    fn body<T: JsonSchema>(&mut self) -> Value {
+        json!({
+            "required": true,
+            "content": { "application/json": { "schema": self.of::<T>() } }
+        })
+    }
+
+    fn response<T: JsonSchema>(&mut self, description: &str) -> Value {
+        json!({
+            "description": description,
+            "content": { "application/json": { "schema": self.of::<T>() } }
+        })
+    }
+}
+
+fn empty(description: &str) -> Value {
+    json!({ "description": description })
+}
+
+fn id_parameter(description: &str, schema: Schema) -> Value {
+    json!([{
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": schema
+    }])
+}
+
+pub(super) fn openapi() -> Value {
+    let mut schemas = Schemas::new();
+    let unauthorized = empty("Not logged in, or the API token isn't valid");
+    let forbidden = empty("The API token's scope doesn't allow this, or the CSRF check failed");
+    let not_found = empty("Not found");
+    let precondition_failed = empty("`If-Match` didn't match the current ETag");
+    let no_ids_left = empty("A task has the biggest id there is, so no new task can get one");
+    let too_many_tasks = empty("There would be more tasks than the server keeps for a user");
+    let summary_too_long = empty("A summary is longer than the server allows");
+
+    let account_error = schemas.response::<ErrorResponse<AccountCreationError>>(
+        "The account was rejected, with a code saying why",
+    );
+    let operations = json!({
+        (paths::WEBSOCKET): {
+            "get": {
+                "summary": "Open a websocket to sync tasks",
+                "description": "Client messages follow `#/components/schemas/InMsg` and server messages follow `#/components/schemas/OutMsg`, both sent as JSON text frames. Ask for the `msgpack` subprotocol to have the server send MessagePack binary frames instead, which clients can also send. `json.deflate` and `msgpack.deflate` compress the server's messages with raw deflate, in binary frames; clients' messages are never compressed. Before shutting down, the server sends a `restarting` message saying when to reconnect, and closes the connection with code 1012.",
+                "x-websocket-messages": {
+                    "client": { "$ref": "#/components/schemas/InMsg" },
+                    "server": { "$ref": "#/components/schemas/OutMsg" }
+                },
+                "responses": {
+                    "101": empty("Switching to the websocket protocol"),
+                    "401": unauthorized,
+                    "403": empty("The `Origin` isn't allowed")
+                }
+            }
+        },
+        (paths::REGISTER): {
+            "post": {
+                "summary": "Create an account",
+                "security": [],
+                "requestBody": schemas.body::<RegisterRequest>(),
+                "responses": {
+                    "201": empty("The account was created"),
+                    "409": account_error,
+                    "422": account_error
+                }
+            }
+        },
+        (paths::LOGIN): {
+            "post": {
+                "summary": "Log in, setting the `session` and `csrf` cookies",
+                "security": [],
+                "requestBody": schemas.body::<RegisterRequest>(),
+                "responses": {
+                    "200": empty("Logged in"),
+                    "401": empty("Wrong username or password")
+                }
+            }
+        },
+        (paths::LOGOUT): {
+            "post": {
+                "summary": "End the current session",
+                "security": [{ "session": [] }],
+                "responses": {
+                    "204": empty("Logged out"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        },
+        (paths::SESSIONS): {
+            "get": {
+                "summary": "List the user's sessions",
+                "responses": {
+                    "200": schemas.response::<Vec<SessionResponse>>("The sessions"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            },
+            "delete": {
+                "summary": "Log out everywhere",
+                "responses": {
+                    "204": empty("Every session was ended"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        },
+        (paths::SESSION): {
+            "delete": {
+                "summary": "End one of the user's sessions, closing its websocket",
+                "parameters": id_parameter("The session's id", schemas.of::<String>()),
+                "responses": {
+                    "204": empty("The session was ended"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_found
+                }
+            }
+        },
+        (paths::TOKENS): {
+            "get": {
+                "summary": "List the user's API tokens",
+                "responses": {
+                    "200": schemas.response::<Vec<TokenResponse>>("The tokens, without their secrets"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            },
+            "post": {
+                "summary": "Create an API token",
+                "requestBody": schemas.body::<CreateTokenRequest>(),
+                "responses": {
+                    "201": schemas.response::<TokenResponse>("The token, including the only copy of its secret"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        },
+        (paths::TOKEN): {
+            "delete": {
+                "summary": "Revoke an API token, closing its websockets",
+                "parameters": id_parameter("The token's id", schemas.of::<String>()),
+                "responses": {
+                    "204": empty("The token was revoked"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_found
+                }
+            }
+        },
+        (paths::TASKS): {
+            "get": {
+                "summary": "List the user's tasks",
+                "description": "The ETag covers the whole collection, for use with `If-Match` on `POST /api/tasks` and `POST /api/tasks/bulk`.",
+                "responses": {
+                    "200": schemas.response::<Vec<Task>>("The tasks"),
+                    "304": empty("Unchanged since the `If-None-Match` ETag"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            },
+            "post": {
+                "summary": "Create a task",
+                "requestBody": schemas.body::<TaskInput>(),
+                "responses": {
+                    "201": schemas.response::<Task>("The new task"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "409": no_ids_left,
+                    "412": precondition_failed,
+                    "413": too_many_tasks,
+                    "422": summary_too_long
+                }
+            }
+        },
+        (paths::BULK_TASKS): {
+            "post": {
+                "summary": "Create, update and delete tasks all at once, or not at all",
+                "requestBody": schemas.body::<BulkRequest>(),
+                "responses": {
+                    "200": schemas.response::<BulkResponse>("What changed"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": empty("A task to update or delete doesn't exist, so nothing changed"),
+                    "409": no_ids_left,
+                    "412": precondition_failed,
+                    "413": too_many_tasks,
+                    "422": summary_too_long
+                }
+            }
+        },
+        (paths::TASK): {
+            "get": {
+                "summary": "Get a task",
+                "parameters": id_parameter("The task's id", schemas.of::<TaskId>()),
+                "responses": {
+                    "200": schemas.response::<Task>("The task"),
+                    "304": empty("Unchanged since the `If-None-Match` ETag"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_found
+                }
+            },
+            "put": {
+                "summary": "Update a task",
+                "parameters": id_parameter("The task's id", schemas.of::<TaskId>()),
+                "requestBody": schemas.body::<TaskInput>(),
+                "responses": {
+                    "200": schemas.response::<Task>("The updated task"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_found,
+                    "412": precondition_failed,
+                    "422": summary_too_long
+                }
+            },
+            "delete": {
+                "summary": "Delete a task",
+                "parameters": id_parameter("The task's id", schemas.of::<TaskId>()),
+                "responses": {
+                    "204": empty("The task was deleted"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": not_found,
+                    "412": precondition_failed
+                }
+            }
+        },
+        (paths::EXPORT): {
+            "get": {
+                "summary": "Download the user's tasks",
+                "parameters": [{
+                    "name": "format",
+                    "in": "query",
+                    "description": "Defaults to `json`, which has everything. `md` and `todo.txt` are accepted too.",
+                    "schema": schemas.of::<ExportFormat>()
+                }],
+                "responses": {
+                    "200": {
+                        "description": "The tasks, as an attachment",
+                        "content": {
+                            "application/json": { "schema": schemas.of::<Tasks>() },
+                            "text/csv": {},
+                            "text/markdown": {},
+                            "text/plain": {}
+                        }
+                    },
+                    "400": empty("Unknown format"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            }
+        },
+        (paths::IMPORT): {
+            "post": {
+                "summary": "Preview or import tasks from another todo app",
+                "description": "Without `confirm=true` nothing is changed, the response shows what would be imported. A preview fails the same way the import would, if the tasks don't fit.",
+                "parameters": [
+                    {
+                        "name": "format",
+                        "in": "query",
+                        "required": true,
+                        "schema": schemas.of::<ImportFormat>()
+                    },
+                    {
+                        "name": "confirm",
+                        "in": "query",
+                        "schema": { "type": "boolean", "default": false }
+                    }
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": { "text/plain": { "schema": { "type": "string" } } }
+                },
+                "responses": {
+                    "200": schemas.response::<ImportResponse>("The tasks to import, and anything that couldn't be carried over"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "409": schemas.response::<ErrorResponse<ImportError>>("A task has the biggest id there is, so the tasks can't be added"),
+                    "413": schemas.response::<ErrorResponse<ImportError>>("There would be more tasks than the server keeps for a user"),
+                    "422": schemas.response::<ErrorResponse<ImportError>>("The file couldn't be read, or a summary is too long")
+                }
+            }
+        },
+        (paths::FEED_SECRET): {
+            "post": {
+                "summary": "Create a secret calendar feed URL, replacing any old one",
+                "responses": {
+                    "201": schemas.response::<FeedResponse>("The only copy of the feed's URL"),
+                    "401": unauthorized,
+                    "403": forbidden
+                }
+            },
+            "delete": {
+                "summary": "Revoke the calendar feed URL",
+                "responses": {
+                    "204": empty("The feed was revoked"),
+                    "401": unauthorized,
+                    "403": forbidden,
+                    "404": empty("There was no feed")
+                }
+            }
+        },
+        (paths::FEED): {
+            "get": {
+                "summary": "The user's tasks as an iCalendar feed",
+                "description": "The URL itself is the credential, it comes from `POST /api/feed`.",
+                "security": [],
+                "parameters": [
+                    {
+                        "name": "file",
+                        "in": "path",
+                        "required": true,
+                        "description": "The feed secret followed by `.ics`",
+                        "schema": { "type": "string" }
+                    },
+                    {
+                        "name": "events",
+                        "in": "query",
+                        "description": "Also add tasks with a due date as all-day events",
+                        "schema": { "type": "boolean", "default": false }
+                    }
+                ],
+                "responses": {
+                    "200": {
+                        "description": "`VTODO`s for every task",
+                        "content": { "text/calendar": {} }
+                    },
+                    "404": not_found
+                }
+            }
+        },
+        (paths::BACKUP): {
+            "get": {
+                "summary": "Download a backup of every user and their tasks",
+                "description": "For operators, and turned off unless the server has an `ADMIN_TOKEN`. Restore it by starting the server with `restore <file>`.",
+                "security": [{ "adminToken": [] }],
+                "parameters": [{
+                    "name": "sessions",
+                    "in": "query",
+                    "description": "Also back up logged in sessions, which only work again with the same cookie secret",
+                    "schema": { "type": "boolean", "default": false }
+                }],
+                "responses": {
+                    "200": {
+                        "description": "A versioned and checksummed JSON archive",
+                        "content": { "application/json": {} }
+                    },
+                    "401": unauthorized,
+                    "404": empty("The server has no admin token")
+                }
+            }
+        },
+        (paths::OPENAPI): {
+            "get": {
+                "summary": "This document",
+                "security": [],
+                "responses": { "200": empty("The OpenAPI document") }
+            }
+        },
+        (paths::HEALTH): {
+            "get": {
+                "summary": "Check the process is up",
+                "security": [],
+                "responses": {
+                    "200": {
+                        "description": "`ok`",
+                        "content": { "text/plain": {} }
+                    }
+                }
+            }
+        },
+        (paths::READY): {
+            "get": {
+                "summary": "Check the server should be sent traffic",
+                "description": "Not ready while the server is shutting down, or when its users or tasks take over a second to answer.",
+                "security": [],
+                "responses": {
+                    "200": schemas.response::<Readiness>("Ready"),
+                    "503": schemas.response::<Readiness>("Not ready, and why")
+                }
+            }
+        },
+        (paths::VERSION): {
+            "get": {
+                "summary": "Which build of the server this is",
+                "security": [],
+                "responses": {
+                    "200": schemas.response::<Version>("The crate version, commit, build time and websocket protocol version")
+                }
+            }
+        },
+        (paths::METRICS): {
+            "get": {
+                "summary": "Prometheus metrics",
+                "description": "Not served here when `METRICS_ADDRESS` is set, and only needs a token when `METRICS_TOKEN` is.",
+                "security": [{}, { "metricsToken": [] }],
+                "responses": {
+                    "200": {
+                        "description": "Requests, websocket connections and messages, broadcasts, logins and tasks",
+                        "content": { "application/openmetrics-text": {} }
+                    },
+                    "401": empty("The token is missing or wrong")
+                }
+            }
+        }
+    });
+    schemas.of::<InMsg>();
+    schemas.of::<OutMsg>();
+
+    json!({
+        "openapi": "3.1.0",
+        "jsonSchemaDialect": "https://json-schema.org/draft/2020-12/schema",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "license": { "name": env!("CARGO_PKG_LICENSE"), "identifier": env!("CARGO_PKG_LICENSE") }
+        },
+        "paths": operations,
+        "security": [{ "session": [] }, { "token": [] }],
+        "components": {
+            "schemas": schemas.0.take_definitions(true),
+            "securitySchemes": {
+                "session": {
+                    "type": "apiKey",
+                    "in": "cookie",
+                    "name": "session",
+                    "description": "Set by `/api/login`. Unsafe requests must also send the `csrf` cookie's value in `X-CSRF-Token`."
+                },
+                "token": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A personal API token from `/api/tokens`"
+                },
+                "adminToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The operator's `ADMIN_TOKEN`"
+                },
+                "metricsToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The operator's `METRICS_TOKEN`"
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use axum_test::TestServer;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use std::collections::BTreeSet;
+
+    use super::super::tests::*;
+    use super::super::{InMsg, OutMsg, Tasks, make_app, metrics};
+    use super::*;
+
+    /// Routes that aren't part of the JSON API: the Elm app and CalDAV, which has its own methods.
+    const UNDOCUMENTED: [&str; 5] = [
+        paths::INDEX,
+        paths::DAV,
+        paths::DAV_HOME,
+        paths::DAV_RESOURCE,
+        paths::CALDAV_DISCOVERY,
+    ];
+
+    #[tokio::test]
+    async fn unit_every_route_is_documented() {
+        let app_state = AppState::new([42; 64]);
+        let app =
+            make_app(std::env::temp_dir(), app_state.clone()).merge(metrics::routes(app_state));
+        let server = TestServer::new(app).unwrap();
+        let document = openapi();
+
+        let routed: BTreeSet<&str> = paths::ALL
+            .into_iter()
+            .filter(|path| !UNDOCUMENTED.contains(path))
+            .collect();
+        let documented: BTreeSet<&str> = document["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(documented, routed);
+        for path in routed {
+            let documented = &document["paths"][path];
+            let uri = path.replace("{id}", "1").replace("{file}", "x.ics");
+            let mut methods = Vec::new();
+            for method in [
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::PATCH,
+            ] {
+                let response = server.method(method.clone(), &uri).await;
+                if response.status_code() != StatusCode::METHOD_NOT_ALLOWED {
+                    methods.push(method.as_str().to_lowercase());
+                }
+            }
+            // Routes for any method, like `/ws`, only need the one they're used with
+            if methods.len() == 5 {
+                continue;
+            }
+            for method in methods {
+                assert!(
+                    documented[&method].is_object(),
+                    "{method} {path} isn't documented"
+                );
+            }
+        }
+    }
+
+    /// A validator for one of the component schemas, resolving references within the document.
+    fn validator(document: &Value, name: &str) -> jsonschema::Validator {
+        let schema = json!({
+            "$ref": format!("#/components/schemas/{name}"),
+            "components": document["components"]
+        });
+        jsonschema::validator_for(&schema).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unit_openapi_is_served() {
+        let server = test_server();
+
+        let response = server.get("/api/openapi.json").await;
+
+        response.assert_status(StatusCode::OK);
+        let document = response.json::<Value>();
+        assert_eq!(document["openapi"], json!("3.1.0"));
+        assert!(document["paths"]["/api/tasks/{id}"]["put"].is_object());
+        let id = &document["paths"]["/api/tasks/{id}"]["get"]["parameters"][0]["schema"];
+        assert_eq!(id["type"], json!("integer"));
+        let id = &document["paths"]["/api/tokens/{id}"]["delete"]["parameters"][0]["schema"];
+        assert_eq!(id["type"], json!("string"));
+    }
+
+    #[test]
+    fn unit_openapi_refs_resolve() {
+        let document = openapi();
+        let text = document.to_string();
+
+        for reference in text.split("\"$ref\":\"").skip(1) {
+            let reference = reference.split('"').next().unwrap();
+            let pointer = reference.trim_start_matches('#');
+            assert!(
+                document.pointer(pointer).is_some(),
+                "{reference} doesn't resolve"
+            );
+        }
+    }
+
+    #[test]
+    fn unit_websocket_messages_match_schema() {
+        let document = openapi();
+        let in_msg = validator(&document, "InMsg");
+        let out_msg = validator(&document, "OutMsg");
+
+        let sent = serde_json::to_value(InMsg::Tasks(Tasks::single_task())).unwrap();
+        let received = serde_json::to_value(OutMsg::NewTasks(Tasks::default())).unwrap();
+
+        assert!(in_msg.is_valid(&sent));
+        assert!(out_msg.is_valid(&received));
+    }
+
+    #[test]
+    fn unit_websocket_schema_rejects_bad_messages() {
+        let document = openapi();
+        let in_msg = validator(&document, "InMsg");
+
+        let unknown_action = json!({ "action": "delete_everything", "payload": {} });
+        let bad_payload = json!({ "action": "tasks", "payload": { "tasks": "nope" } });
+
+        assert!(!in_msg.is_valid(&unknown_action));
+        assert!(!in_msg.is_valid(&bad_payload));
+    }
+
+    #[tokio::test]
+    async fn unit_responses_match_schema() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let document = openapi();
+        let task = validator(&document, "Task");
+
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .json::<Value>();
+
+        assert!(task.is_valid(&created));
+    }
+}
@@ -0,0 +1,60 @@
+//! Every path the server routes. The router and the OpenAPI document both use these, so the
+//! document's test can check nothing is left out of it.
+
+pub(super) const INDEX: &str = "/";
+pub(super) const WEBSOCKET: &str = "/ws";
+pub(super) const REGISTER: &str = "/api/register";
+pub(super) const LOGIN: &str = "/api/login";
+pub(super) const LOGOUT: &str = "/api/logout";
+pub(super) const SESSIONS: &str = "/api/sessions";
+pub(super) const SESSION: &str = "/api/sessions/{id}";
+pub(super) const TOKENS: &str = "/api/tokens";
+pub(super) const TOKEN: &str = "/api/tokens/{id}";
+pub(super) const TASKS: &str = "/api/tasks";
+pub(super) const BULK_TASKS: &str = "/api/tasks/bulk";
+pub(super) const TASK: &str = "/api/tasks/{id}";
+pub(super) const EXPORT: &str = "/api/export";
+pub(super) const IMPORT: &str = "/api/import";
+pub(super) const FEED_SECRET: &str = "/api/feed";
+pub(super) const FEED: &str = "/feed/{file}";
+pub(super) const BACKUP: &str = "/api/admin/backup";
+pub(super) const OPENAPI: &str = "/api/openapi.json";
+pub(super) const HEALTH: &str = "/healthz";
+pub(super) const READY: &str = "/readyz";
+pub(super) const VERSION: &str = "/version";
+pub(super) const METRICS: &str = "/metrics";
+pub(super) const DAV: &str = "/dav";
+pub(super) const DAV_HOME: &str = "/dav/";
+pub(super) const DAV_RESOURCE: &str = "/dav/{*path}";
+pub(super) const CALDAV_DISCOVERY: &str = "/.well-known/caldav";
+
+/// Every path above. Routes for static files are left to the fallback.
+#[cfg(test)]
+pub(super) const ALL: [&str; 26] = [
+    INDEX,
+    WEBSOCKET,
+    REGISTER,
+    LOGIN,
+    LOGOUT,
+    SESSIONS,
+    SESSION,
+    TOKENS,
+    TOKEN,
+    TASKS,
+    BULK_TASKS,
+    TASK,
+    EXPORT,
+    IMPORT,
+    FEED_SECRET,
+    FEED,
+    BACKUP,
+    OPENAPI,
+    HEALTH,
+    READY,
+    VERSION,
+    METRICS,
+    DAV,
+    DAV_HOME,
+    DAV_RESOURCE,
+    CALDAV_DISCOVERY,
+];
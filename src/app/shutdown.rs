@@ -0,0 +1,221 @@
+//! Stopping the server without dropping anyone on the floor.
+//!
+//! When it's asked to stop (SIGTERM from a deploy, or Ctrl-C), the server stops accepting
+//! connections, tells every websocket it's restarting and when to reconnect, and closes them with
+//! code 1012. Once they've closed and their outboxes are written out, or the deadline passes, it
+//! saves everything to the state file, if it has one, and returns.
+
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use axum::Router;
+use axum::extract::ws::{CloseFrame, Message, close_code};
+use tokio::net::TcpListener;
+
+use super::{AppState, Encoding, OutMsg, Outbox, Restart, backup};
+
+/// How long the server takes to shut down, and how long clients wait before reconnecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shutdown {
+    /// How long to wait for connections to close before giving up on them
+    pub deadline: Duration,
+    /// Roughly how long a new server takes to come up, after a deploy
+    pub reconnect_in: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(5),
+            reconnect_in: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Resolves when the process is asked to stop, with Ctrl-C (SIGINT) or SIGTERM.
+pub(super) async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        signal(SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Serves the app until `signal` resolves, then shuts it down.
+pub(super) async fn serve(
+    listener: TcpListener,
+    app: Router,
+    app_state: AppState,
+    signal: impl Future<Output = ()> + Send + 'static,
+    shutdown: Shutdown,
+    state_file: Option<PathBuf>,
+) {
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        let _ = stopped.await;
+    });
+    let server = tokio::spawn(server.into_future());
+
+    signal.await;
+    let Shutdown {
+        deadline,
+        reconnect_in,
+    } = shutdown;
+    tracing::info!("Shutting down, giving connections {deadline:?} to close");
+    // Stops accepting connections, and lets the requests in flight finish
+    let _ = stop.send(());
+    restart_clients(&app_state, reconnect_in).await;
+    let finished = async {
+        let _ = server.await;
+        app_state.restarting.closed().await;
+    };
+    if tokio::time::timeout(deadline, finished).await.is_err() {
+        tracing::warn!("Connections were still open after {deadline:?}, shutting down anyway");
+    }
+
+    if let Some(path) = state_file {
+        match backup::save(&app_state, &path).await {
+            Ok(()) => tracing::info!("Saved users and tasks to {}", path.display()),
+            Err(e) => tracing::error!("Failed to save users and tasks to {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Tells every websocket the server is restarting, and turns away new ones.
+async fn restart_clients(app_state: &AppState, reconnect_in: Duration) {
+    // Set first, so websockets that connect while this goes through the store see it
+    app_state.restarting.send_replace(Some(reconnect_in));
+    app_state.store.restart(reconnect_in).await;
+}
+
+/// Tells a websocket the server is restarting, and closes it.
+pub(super) fn restart(outbox: &Outbox, encoding: Encoding, reconnect_in: Duration) {
+    let secs = reconnect_in.as_secs();
+    let msg = OutMsg::Restarting(Restart {
+        reconnect_in: secs,
+        message: format!("The server is restarting, reconnect in {secs} s"),
+    });
+    outbox.send(encoding.encode(&msg));
+    // Queued after the message, unlike `Outbox::close`, so it's sent first
+    outbox.send(Message::Close(Some(CloseFrame {
+        code: close_code::RESTART,
+        reason: "server restarting".into(),
+    })));
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum_test::WsMessage;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::timeout;
+
+    use super::super::tests::*;
+    use super::super::{Backup, make_app};
+    use super::*;
+
+    #[tokio::test]
+    async fn unit_tells_websockets_to_reconnect() {
+        let app_state = AppState::new([42; 64]);
+        let server = test_server_http_with_state(app_state.clone());
+        login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _ = websocket.receive_json::<OutMsg>().await;
+
+        restart_clients(&app_state, Duration::from_secs(7)).await;
+
+        let OutMsg::Restarting(restart) = websocket.receive_json().await else {
+            panic!("expected a restart message");
+        };
+        assert_eq!(restart.reconnect_in, 7);
+        match websocket.receive_message().await {
+            WsMessage::Close(Some(frame)) => assert_eq!(u16::from(frame.code), close_code::RESTART),
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+        server
+            .get_websocket("/ws")
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+
+        drop(websocket);
+        timeout(Duration::from_secs(1), app_state.restarting.closed())
+            .await
+            .expect("the websocket should have finished");
+    }
+
+    #[tokio::test]
+    async fn unit_shuts_down_within_the_deadline() {
+        let app_state = AppState::new([42; 64]);
+        let seeding = test_server_http_with_state(app_state.clone());
+        let login = login_test_user(&seeding).await;
+        seeding
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        let state_file =
+            std::env::temp_dir().join(format!("rust-elm-state-{}.json", rand::random::<u64>()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (trigger, signal) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = Shutdown {
+            deadline: Duration::from_millis(200),
+            reconnect_in: Duration::from_secs(1),
+        };
+        let server = tokio::spawn(serve(
+            listener,
+            make_app(std::env::temp_dir(), app_state.clone()),
+            app_state,
+            async move {
+                let _ = signal.await;
+            },
+            shutdown,
+            Some(state_file.clone()),
+        ));
+
+        // A request that never finishes, which would hold up shutting down forever
+        let mut stuck = tokio::net::TcpStream::connect(address).await.unwrap();
+        stuck.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        trigger.send(()).unwrap();
+
+        timeout(Duration::from_secs(2), server)
+            .await
+            .expect("the server should have stopped by the deadline")
+            .unwrap();
+        let saved = Backup::from_slice(&std::fs::read(&state_file).unwrap()).unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+        let summaries: Vec<_> = saved
+            .tasks
+            .into_values()
+            .flat_map(|tasks| tasks.tasks)
+            .map(|task| task.summary)
+            .collect();
+        assert_eq!(summaries, vec!["buy milk".to_string()]);
+    }
+}
@@ -0,0 +1,256 @@
+//! Every user's tasks and open websockets, with a lock for each user.
+//!
+//! A user's changes, and the broadcasts of them, happen while holding that user's lock, so their
+//! websockets get them in order. Nothing here waits on a socket while holding a lock (see
+//! [`Outbox`]), and nobody holds two users' locks at once, so users never wait on each other.
+
+use std::collections::{HashMap, hash_map::Entry};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::ws::CloseFrame;
+use prometheus_client::metrics::histogram::Histogram;
+use rand::random;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+
+use super::dav::ResourceNames;
+use super::{ConnectionId, Encoding, OutMsg, Outbox, Tasks, metrics, shutdown};
+use crate::auth::{Credential, UserId};
+
+/// One user's tasks, and the websockets they have open.
+pub(super) struct Shard {
+    pub(super) tasks: Tasks,
+    clients: HashMap<ConnectionId, Client>,
+    /// What CalDAV clients named the tasks they created
+    pub(super) dav_names: ResourceNames,
+    /// How long broadcasts take, shared by every shard
+    broadcasts: Histogram,
+}
+
+/// An open websocket, and what it was opened with.
+struct Client {
+    credential: Credential,
+    encoding: Encoding,
+    outbox: Outbox,
+}
+
+impl Shard {
+    fn new(tasks: Tasks, broadcasts: Histogram) -> Self {
+        Self {
+            tasks,
+            clients: HashMap::new(),
+            dav_names: ResourceNames::default(),
+            broadcasts,
+        }
+    }
+
+    /// Registers a websocket and queues the current tasks for it, before any later changes.
+    pub(super) fn connect(
+        &mut self,
+        credential: Credential,
+        encoding: Encoding,
+        outbox: Outbox,
+    ) -> ConnectionId {
+        let connection_id = loop {
+            let id = random();
+            if let Entry::Vacant(e) = self.clients.entry(id) {
+                e.insert(Client {
+                    credential,
+                    encoding,
+                    outbox: outbox.clone(),
+                });
+                break id;
+            }
+        };
+        outbox.send(encoding.encode(&OutMsg::NewTasks(self.tasks.clone())));
+        connection_id
+    }
+
+    pub(super) fn disconnect(&mut self, connection_id: ConnectionId) {
+        self.clients.remove(&connection_id);
+    }
+
+    /// Queues the tasks for every one of the user's websockets. Every change to the tasks is
+    /// broadcast, so this is also where the DAV names of deleted tasks are forgotten.
+    pub(super) fn broadcast(&mut self) {
+        self.dav_names.retain(&self.tasks);
+        let started = Instant::now();
+        let msg = OutMsg::NewTasks(self.tasks.clone());
+        // Each encoding is only done once, however many clients use it
+        let mut messages = HashMap::new();
+        let mut sent = 0;
+        for client in self.clients.values() {
+            let message = messages
+                .entry(client.encoding)
+                .or_insert_with(|| client.encoding.encode(&msg));
+            if client.outbox.send(message.clone()) {
+                sent += 1;
+            } else {
+                tracing::warn!(
+                    "Not sending tasks to {}, which fell behind",
+                    client.credential
+                );
+            }
+        }
+        self.broadcasts.observe(started.elapsed().as_secs_f64());
+
+        tracing::debug!(
+            "Broadcasted tasks to {} clients: {} tasks, next_id: {}",
+            sent,
+            self.tasks.tasks.len(),
+            self.tasks.next_id
+        );
+    }
+
+    /// Closes the websockets opened with credentials that are no longer valid.
+    pub(super) fn close(&mut self, revoked: &[Credential], frame: CloseFrame) {
+        self.clients.retain(|_, client| {
+            if !revoked.contains(&client.credential) {
+                return true;
+            }
+            tracing::debug!("Closing websocket for {}", client.credential);
+            client.outbox.close(frame.clone());
+            false
+        });
+    }
+
+    /// Tells the user's websockets the server is restarting, and closes them.
+    fn restart(&self, reconnect_in: Duration) {
+        for client in self.clients.values() {
+            shutdown::restart(&client.outbox, client.encoding, reconnect_in);
+        }
+    }
+}
+
+/// Every user's [`Shard`].
+#[derive(Clone)]
+pub(super) struct Store {
+    shards: Arc<RwLock<HashMap<UserId, Arc<Mutex<Shard>>>>>,
+    broadcasts: Histogram,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(metrics::duration_histogram())
+    }
+}
+
+impl Store {
+    /// An empty store, which records how long broadcasts take in `broadcasts`.
+    pub(super) fn new(broadcasts: Histogram) -> Self {
+        Self::from_tasks(HashMap::new(), broadcasts)
+    }
+
+    pub(super) fn from_tasks(tasks: HashMap<UserId, Tasks>, broadcasts: Histogram) -> Self {
+        let shards = tasks
+            .into_iter()
+            .map(|(user_id, tasks)| {
+                let shard = Shard::new(tasks, broadcasts.clone());
+                (user_id, Arc::new(Mutex::new(shard)))
+            })
+            .collect();
+        Self {
+            shards: Arc::new(RwLock::new(shards)),
+            broadcasts,
+        }
+    }
+
+    /// Locks the user's shard, which is created empty if they don't have one yet.
+    pub(super) async fn lock(&self, user_id: UserId) -> OwnedMutexGuard<Shard> {
+        let existing = self.shards.read().await.get(&user_id).cloned();
+        let shard = match existing {
+            Some(shard) => shard,
+            None => self
+                .shards
+                .write()
+                .await
+                .entry(user_id)
+                .or_insert_with(|| {
+                    let shard = Shard::new(Tasks::default(), self.broadcasts.clone());
+                    Arc::new(Mutex::new(shard))
+                })
+                .clone(),
+        };
+        shard.lock_owned().await
+    }
+
+    /// Everyone's tasks, leaving out users who have never had any.
+    pub(super) async fn tasks(&self) -> HashMap<UserId, Tasks> {
+        let shards: Vec<_> = self
+            .shards
+            .read()
+            .await
+            .iter()
+            .map(|(user_id, shard)| (*user_id, shard.clone()))
+            .collect();
+        let mut tasks = HashMap::new();
+        for (user_id, shard) in shards {
+            let shard = shard.lock().await;
+            if shard.tasks != Tasks::default() {
+                tasks.insert(user_id, shard.tasks.clone());
+            }
+        }
+        tasks
+    }
+
+    /// Resolves once the store can be read, which only takes long if something is holding it up.
+    pub(super) async fn reachable(&self) {
+        drop(self.shards.read().await);
+    }
+
+    /// Tells every websocket the server is restarting, and closes them.
+    pub(super) async fn restart(&self, reconnect_in: Duration) {
+        let shards: Vec<_> = self.shards.read().await.values().cloned().collect();
+        for shard in shards {
+            shard.lock().await.restart(reconnect_in);
+        }
+    }
+
+    /// Open websockets, for every user.
+    pub(super) async fn connections(&self) -> usize {
+        let shards: Vec<_> = self.shards.read().await.values().cloned().collect();
+        let mut connections = 0;
+        for shard in shards {
+            connections += shard.lock().await.clients.len();
+        }
+        connections
+    }
+
+    /// Tasks, for every user.
+    pub(super) async fn task_count(&self) -> usize {
+        let shards: Vec<_> = self.shards.read().await.values().cloned().collect();
+        let mut count = 0;
+        for shard in shards {
+            count += shard.lock().await.tasks.tasks.len();
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unit_users_dont_wait_on_each_other() {
+        let store = Store::default();
+        let mut busy = store.lock(1).await;
+        busy.tasks.next_id = 1;
+
+        let mut other = timeout(Duration::from_millis(100), store.lock(2))
+            .await
+            .unwrap();
+        other.tasks.next_id = 2;
+        drop(other);
+        drop(busy);
+
+        let tasks = store.tasks().await;
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[&2].next_id, 2);
+    }
+}
@@ -0,0 +1,720 @@
+//! A REST interface to the same per-user tasks the websocket syncs, for scripts that don't want
+//! to speak the websocket protocol. Every write is broadcast to the user's open websockets.
+//!
+//! Responses carry strong ETags, so clients can use `If-None-Match` to skip unchanged reads and
+//! `If-Match` to avoid overwriting changes they haven't seen. Single tasks are compared against
+//! the task's ETag, and writes to the whole collection against the collection's ETag.
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{ETag, Header, HeaderMapExt, IfMatch, IfNoneMatch},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+
+use super::paths;
+use super::{AppState, Caller, MessageLimits, Task, TaskId, Tasks};
+use crate::auth::Scope;
+
+pub(super) fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            paths::TASKS,
+            get(handle_list_tasks).post(handle_create_task),
+        )
+        .route(paths::BULK_TASKS, post(handle_bulk_tasks))
+        .route(
+            paths::TASK,
+            get(handle_get_task)
+                .put(handle_update_task)
+                .delete(handle_delete_task),
+        )
+}
+
+/// The parts of a task a client is allowed to set. The server picks the id.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+pub(super) struct TaskInput {
+    pub(super) summary: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(default)]
+pub(super) struct BulkRequest {
+    create: Vec<TaskInput>,
+    update: Vec<Task>,
+    delete: Vec<TaskId>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+pub(super) struct BulkResponse {
+    created: Vec<Task>,
+    updated: Vec<Task>,
+    deleted: Vec<TaskId>,
+}
+
+/// A strong ETag that changes whenever the serialized value does, quoted as in the header.
+pub(super) fn etag_value<T: Serialize>(value: &T) -> String {
+    let json = serde_json::to_vec(value).unwrap_or_default();
+    let digest = Sha256::digest(json);
+    format!("\"{}\"", hex::encode(&digest[..16]))
+}
+
+pub(super) fn etag_of<T: Serialize>(value: &T) -> ETag {
+    etag_value(value).parse().expect("hex is a valid etag")
+}
+
+/// Decodes a header only if it was sent, as conditional headers decode an empty list otherwise.
+pub(super) fn typed_header<H: Header>(headers: &HeaderMap) -> Option<H> {
+    if headers.contains_key(H::name()) {
+        headers.typed_get()
+    } else {
+        None
+    }
+}
+
+fn check_if_match(headers: &HeaderMap, etag: &ETag) -> Result<(), StatusCode> {
+    match typed_header::<IfMatch>(headers) {
+        Some(if_match) if !if_match.precondition_passes(etag) => {
+            Err(StatusCode::PRECONDITION_FAILED)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Responds with just the ETag if the client already has the current version.
+fn conditional_get<T: Serialize>(headers: &HeaderMap, etag: ETag, value: T) -> Response {
+    match typed_header::<IfNoneMatch>(headers) {
+        Some(if_none_match) if !if_none_match.precondition_passes(&etag) => {
+            (StatusCode::NOT_MODIFIED, TypedHeader(etag)).into_response()
+        }
+        _ => (TypedHeader(etag), Json(value)).into_response(),
+    }
+}
+
+/// Why a change to the tasks was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TaskError {
+    NotFound,
+    /// A client has used the biggest id there is, so no new one can be picked
+    NoIdsLeft,
+    /// There would be more than `max_tasks`
+    TooManyTasks,
+    /// The summary is longer than `max_summary_length`
+    SummaryTooLong,
+}
+
+impl From<TaskError> for StatusCode {
+    fn from(error: TaskError) -> Self {
+        match error {
+            TaskError::NotFound => StatusCode::NOT_FOUND,
+            TaskError::NoIdsLeft => StatusCode::CONFLICT,
+            TaskError::TooManyTasks => StatusCode::PAYLOAD_TOO_LARGE,
+            TaskError::SummaryTooLong => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl Tasks {
+    pub(super) fn get(&self, id: TaskId) -> Option<&Task> {
+        self.tasks.iter().find(|task| task.id == id)
+    }
+
+    /// Adds a task with a fresh id, even if a client has used ids past `next_id`.
+    pub(super) fn create(
+        &mut self,
+        input: TaskInput,
+        limits: &MessageLimits,
+    ) -> Result<Task, TaskError> {
+        if self.tasks.len() >= limits.max_tasks {
+            return Err(TaskError::TooManyTasks);
+        }
+        if limits.summary_too_long(&input.summary) {
+            return Err(TaskError::SummaryTooLong);
+        }
+        let mut id = self.next_id;
+        for task in &self.tasks {
+            id = id.max(task.id.checked_add(1).ok_or(TaskError::NoIdsLeft)?);
+        }
+        let next_id = id.checked_add(1).ok_or(TaskError::NoIdsLeft)?;
+        let task = Task {
+            id,
+            summary: input.summary,
+        };
+        self.next_id = next_id;
+        self.tasks.push(task.clone());
+        Ok(task)
+    }
+
+    pub(super) fn update(
+        &mut self,
+        id: TaskId,
+        input: TaskInput,
+        limits: &MessageLimits,
+    ) -> Result<Task, TaskError> {
+        if limits.summary_too_long(&input.summary) {
+            return Err(TaskError::SummaryTooLong);
+        }
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.id == id)
+            .ok_or(TaskError::NotFound)?;
+        task.summary = input.summary;
+        Ok(task.clone())
+    }
+
+    pub(super) fn delete(&mut self, id: TaskId) -> bool {
+        let before = self.tasks.len();
+        self.tasks.retain(|task| task.id != id);
+        self.tasks.len() != before
+    }
+
+    /// Applies every change, or none of them if any can't be made.
+    fn apply_bulk(
+        &mut self,
+        request: BulkRequest,
+        limits: &MessageLimits,
+    ) -> Result<BulkResponse, TaskError> {
+        let mut result = self.clone();
+        let mut response = BulkResponse::default();
+        for id in request.delete {
+            if !result.delete(id) {
+                return Err(TaskError::NotFound);
+            }
+            response.deleted.push(id);
+        }
+        for task in request.update {
+            let input = TaskInput {
+                summary: task.summary,
+            };
+            response
+                .updated
+                .push(result.update(task.id, input, limits)?);
+        }
+        for input in request.create {
+            response.created.push(result.create(input, limits)?);
+        }
+        *self = result;
+        Ok(response)
+    }
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_list_tasks(
+    State(state): State<AppState>,
+    caller: Caller,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let caller = caller.require(Scope::Read)?;
+    let tasks = state.store.lock(caller.user_id).await.tasks.clone();
+    // The list is tagged with the whole collection, so it can be used with `If-Match` on writes
+    let etag = etag_of(&tasks);
+    Ok(conditional_get(&headers, etag, tasks.tasks))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_get_task(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<TaskId>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let caller = caller.require(Scope::Read)?;
+    let task = state
+        .store
+        .lock(caller.user_id)
+        .await
+        .tasks
+        .get(id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(conditional_get(&headers, etag_of(&task), task))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_create_task(
+    State(state): State<AppState>,
+    caller: Caller,
+    headers: HeaderMap,
+    Json(input): Json<TaskInput>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let caller = caller.require(Scope::Write)?;
+    let task = {
+        let mut shard = state.store.lock(caller.user_id).await;
+        check_if_match(&headers, &etag_of(&shard.tasks))?;
+        let task = shard.tasks.create(input, &state.message_limits)?;
+        shard.broadcast();
+        task
+    };
+    let location = format!("/api/tasks/{}", task.id);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        TypedHeader(etag_of(&task)),
+        Json(task),
+    ))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_update_task(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<TaskId>,
+    headers: HeaderMap,
+    Json(input): Json<TaskInput>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let caller = caller.require(Scope::Write)?;
+    let task = {
+        let mut shard = state.store.lock(caller.user_id).await;
+        let current = shard.tasks.get(id).ok_or(StatusCode::NOT_FOUND)?;
+        check_if_match(&headers, &etag_of(current))?;
+        let task = shard.tasks.update(id, input, &state.message_limits)?;
+        shard.broadcast();
+        task
+    };
+    Ok((TypedHeader(etag_of(&task)), Json(task)))
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_delete_task(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<TaskId>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    let caller = caller.require(Scope::Write)?;
+    {
+        let mut shard = state.store.lock(caller.user_id).await;
+        let current = shard.tasks.get(id).ok_or(StatusCode::NOT_FOUND)?;
+        check_if_match(&headers, &etag_of(current))?;
+        shard.tasks.delete(id);
+        shard.broadcast();
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler]
+#[instrument(skip_all)]
+async fn handle_bulk_tasks(
+    State(state): State<AppState>,
+    caller: Caller,
+    headers: HeaderMap,
+    Json(request): Json<BulkRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let caller = caller.require(Scope::Write)?;
+    let (response, etag) = {
+        let mut shard = state.store.lock(caller.user_id).await;
+        check_if_match(&headers, &etag_of(&shard.tasks))?;
+        let response = shard.tasks.apply_bulk(request, &state.message_limits)?;
+        shard.broadcast();
+        (response, etag_of(&shard.tasks))
+    };
+    Ok((TypedHeader(etag), Json(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use serde_json::json;
+
+    use super::super::tests::*;
+    use super::*;
+    use crate::app::{InMsg, OutMsg};
+
+    #[tokio::test]
+    async fn unit_create_and_get_task() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+
+        let response = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await;
+        response.assert_status(StatusCode::CREATED);
+        let created = response.json::<Task>();
+        assert_eq!(
+            response.header(header::LOCATION),
+            format!("/api/tasks/{}", created.id)
+        );
+
+        let fetched = server
+            .get(&format!("/api/tasks/{}", created.id))
+            .await
+            .json::<Task>();
+        assert_eq!(fetched, created);
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all, vec![created]);
+    }
+
+    #[tokio::test]
+    async fn unit_update_and_delete_task() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .json::<Task>();
+
+        let updated = server
+            .put(&format!("/api/tasks/{}", created.id))
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy oat milk" }))
+            .await
+            .json::<Task>();
+        assert_eq!(updated.summary, "buy oat milk");
+
+        server
+            .delete(&format!("/api/tasks/{}", created.id))
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .get(&format!("/api/tasks/{}", created.id))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .delete(&format!("/api/tasks/{}", created.id))
+            .with_csrf(&login)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unit_missing_task() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+
+        server
+            .get("/api/tasks/12")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .put("/api/tasks/12")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "nothing" }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unit_tasks_unauthenticated() {
+        let server = test_server();
+
+        server
+            .get("/api/tasks")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unit_bulk_tasks() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let first = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "first" }))
+            .await
+            .json::<Task>();
+        let second = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "second" }))
+            .await
+            .json::<Task>();
+
+        let response = server
+            .post("/api/tasks/bulk")
+            .with_csrf(&login)
+            .json(&json!({
+                "create": [{ "summary": "third" }],
+                "update": [{ "id": second.id, "summary": "second, again" }],
+                "delete": [first.id]
+            }))
+            .await
+            .json::<BulkResponse>();
+
+        assert_eq!(response.deleted, vec![first.id]);
+        assert_eq!(response.updated[0].summary, "second, again");
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all, [response.updated, response.created].concat());
+    }
+
+    #[tokio::test]
+    async fn unit_bulk_tasks_is_all_or_nothing() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+
+        server
+            .post("/api/tasks/bulk")
+            .with_csrf(&login)
+            .json(&json!({
+                "create": [{ "summary": "new" }],
+                "delete": [42]
+            }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all, vec![]);
+    }
+
+    #[tokio::test]
+    async fn unit_writes_keep_to_the_limits() {
+        let limits = MessageLimits {
+            max_tasks: 1,
+            max_summary_length: 10,
+            ..MessageLimits::default()
+        };
+        let server =
+            test_server_http_with_state(AppState::new([42; 64]).with_message_limits(limits));
+        let login = login_test_user(&server).await;
+        let long = json!({ "summary": "much too long to keep" });
+
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&long)
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await
+            .json::<Task>();
+        server
+            .put(&format!("/api/tasks/{}", created.id))
+            .with_csrf(&login)
+            .json(&long)
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy bread" }))
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        server
+            .post("/api/tasks/bulk")
+            .with_csrf(&login)
+            .json(&json!({
+                "create": [{ "summary": "buy bread" }],
+                "delete": [created.id]
+            }))
+            .await
+            .assert_status_ok();
+
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].summary, "buy bread");
+    }
+
+    #[tokio::test]
+    async fn unit_read_token_cannot_write_tasks() {
+        let server = test_server_http();
+        let (_login, token) = logged_in_token(&server, "read").await;
+
+        server
+            .get("/api/tasks")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .await
+            .assert_status_ok();
+        server
+            .post("/api/tasks")
+            .clear_cookies()
+            .authorization_bearer(&token)
+            .json(&json!({ "summary": "nope" }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn unit_rest_write_is_broadcast() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = websocket.receive_outmsg().await;
+
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "from a script" }))
+            .await
+            .json::<Task>();
+
+        let OutMsg::NewTasks(tasks) = websocket.receive_outmsg().await else {
+            panic!("expected tasks");
+        };
+        assert_eq!(tasks.tasks, vec![created]);
+    }
+
+    #[tokio::test]
+    async fn unit_create_after_websocket_ids() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = websocket.receive_outmsg().await;
+        let tasks = Tasks {
+            tasks: vec![Task {
+                id: 7,
+                summary: "from the browser".to_string(),
+            }],
+            next_id: 0,
+        };
+        websocket.send_inmsg(InMsg::Tasks(tasks)).await;
+        let _echo = websocket.receive_outmsg().await;
+
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "from a script" }))
+            .await
+            .json::<Task>();
+
+        assert_eq!(created.id, 8);
+    }
+
+    #[tokio::test]
+    async fn unit_create_after_the_last_id() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let mut websocket = server.get_websocket("/ws").await.into_websocket().await;
+        let _initial = websocket.receive_outmsg().await;
+        let tasks = Tasks {
+            tasks: vec![Task {
+                id: TaskId::MAX,
+                summary: "from the browser".to_string(),
+            }],
+            next_id: 0,
+        };
+        websocket.send_inmsg(InMsg::Tasks(tasks.clone())).await;
+        let _echo = websocket.receive_outmsg().await;
+
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "from a script" }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        server
+            .post("/api/tasks/bulk")
+            .with_csrf(&login)
+            .json(&json!({ "create": [{ "summary": "from a script" }] }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all, tasks.tasks);
+    }
+
+    #[tokio::test]
+    async fn unit_if_none_match_not_modified() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await;
+        let path = format!("/api/tasks/{}", created.json::<Task>().id);
+
+        let response = server.get(&path).await;
+        let etag = response.header(header::ETAG);
+        assert_eq!(created.header(header::ETAG), etag);
+
+        server
+            .get(&path)
+            .add_header(header::IF_NONE_MATCH, etag.clone())
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+        let list = server.get("/api/tasks").await;
+        server
+            .get("/api/tasks")
+            .add_header(header::IF_NONE_MATCH, list.header(header::ETAG))
+            .await
+            .assert_status(StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn unit_if_match_stale_task() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let created = server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .json(&json!({ "summary": "buy milk" }))
+            .await;
+        let etag = created.header(header::ETAG);
+        let path = format!("/api/tasks/{}", created.json::<Task>().id);
+
+        let updated = server
+            .put(&path)
+            .with_csrf(&login)
+            .add_header(header::IF_MATCH, etag.clone())
+            .json(&json!({ "summary": "buy oat milk" }))
+            .await;
+        updated.assert_status_ok();
+        assert_ne!(updated.header(header::ETAG), etag);
+
+        server
+            .put(&path)
+            .with_csrf(&login)
+            .add_header(header::IF_MATCH, etag.clone())
+            .json(&json!({ "summary": "buy cow milk" }))
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+        server
+            .delete(&path)
+            .with_csrf(&login)
+            .add_header(header::IF_MATCH, etag)
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+        let task = server.get(&path).await.json::<Task>();
+        assert_eq!(task.summary, "buy oat milk");
+    }
+
+    #[tokio::test]
+    async fn unit_if_match_stale_collection() {
+        let server = test_server_http();
+        let login = login_test_user(&server).await;
+        let etag = server.get("/api/tasks").await.header(header::ETAG);
+
+        server
+            .post("/api/tasks")
+            .with_csrf(&login)
+            .add_header(header::IF_MATCH, etag.clone())
+            .json(&json!({ "summary": "first" }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        server
+            .post("/api/tasks/bulk")
+            .with_csrf(&login)
+            .add_header(header::IF_MATCH, etag)
+            .json(&json!({ "create": [{ "summary": "second" }] }))
+            .await
+            .assert_status(StatusCode::PRECONDITION_FAILED);
+
+        let all = server.get("/api/tasks").await.json::<Vec<Task>>();
+        assert_eq!(all.len(), 1);
+    }
+}
@@ -1,36 +1,166 @@
-use bcrypt_pbkdf::bcrypt_pbkdf;
 use rand::random;
-use std::collections::{HashMap, hash_map::Entry};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::password::{hash_password, is_password_hash};
+use crate::policy::{AccountCreationError, username_key};
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 pub type UserId = u64;
 pub type SessionId = u64;
-type PassHash = [u8; 32];
-type Salt = [u8; 32];
-const BCRYPT_ROUNDS: u32 = 10;
+pub type TokenId = u64;
 
 #[derive(Debug, Clone, Default)]
 pub struct Users {
     users: HashMap<UserId, UserData>,
-    sessions: HashMap<SessionId, UserId>,
+    sessions: HashMap<SessionId, SessionData>,
+    tokens: HashMap<TokenId, TokenData>,
+    /// Calendar feed secrets, at most one per user. Only a hash of the secret is kept.
+    feeds: HashMap<UserId, [u8; 32]>,
 }
 
 #[derive(Debug, Clone)]
 pub struct UserData {
     username: String,
-    pass_hash: PassHash,
-    salt: Salt,
+    /// A PHC string, so the algorithm and its parameters are stored alongside the hash
+    pass_hash: String,
+}
+
+/// Where a session was started from, as far as the server can tell.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Metadata kept for every logged in session, so users can see and revoke their devices.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct SessionData {
+    #[serde(skip)]
+    pub user_id: UserId,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// Seconds since the unix epoch
+    pub last_seen: u64,
+    pub user_agent: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+impl SessionData {
+    fn new(user_id: UserId, client: ClientInfo) -> Self {
+        let now = unix_now();
+        Self {
+            user_id,
+            created_at: now,
+            last_seen: now,
+            user_agent: client.user_agent,
+            ip: client.ip,
+        }
+    }
+}
+
+/// What a request was authenticated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Credential {
+    Session(SessionId),
+    Token(TokenId),
+}
+
+impl std::fmt::Display for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Session(id) => write!(f, "session {id}"),
+            Credential::Token(id) => write!(f, "token {id}"),
+        }
+    }
+}
+
+/// What an API token is allowed to do. Each scope includes the ones before it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    pub fn allows(self, needed: Scope) -> bool {
+        self >= needed
+    }
+}
+
+/// A long-lived credential for scripts. Only a hash of the secret part is kept.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TokenData {
+    #[serde(skip)]
+    pub user_id: UserId,
+    pub name: String,
+    pub scope: Scope,
+    #[serde(skip)]
+    secret_hash: [u8; 32],
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// Seconds since the unix epoch
+    pub last_used: Option<u64>,
+}
+
+const TOKEN_PREFIX: &str = "rte";
+const FEED_PREFIX: &str = "rtf";
+
+/// Tokens look like `rte_<id>_<secret>`, so the id can be used to look up the stored hash.
+/// Feed secrets look the same, with the user's id and an `rtf` prefix.
+fn format_token(prefix: &str, id: u64, secret: &[u8; 32]) -> String {
+    format!("{prefix}_{id:016x}_{}", hex::encode(secret))
+}
+
+fn parse_token(prefix: &str, token: &str) -> Option<(u64, [u8; 32])> {
+    let mut parts = token.split('_');
+    if parts.next()? != prefix {
+        return None;
+    }
+    let id = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let mut secret = [0; 32];
+    hex::decode_to_slice(parts.next()?, &mut secret).ok()?;
+    match parts.next() {
+        Some(_) => None,
+        None => Some((id, secret)),
+    }
+}
+
+fn hash_secret(secret: &[u8]) -> [u8; 32] {
+    Sha256::digest(secret).into()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 impl Users {
-    pub fn try_add(&mut self, user: UserData) -> Option<UserId> {
-        if self.users.iter().any(|u| u.1.username == user.username) {
-            None
+    pub fn try_add(&mut self, user: UserData) -> Result<UserId, AccountCreationError> {
+        let key = username_key(&user.username);
+        if self
+            .users
+            .values()
+            .any(|u| username_key(&u.username) == key)
+        {
+            Err(AccountCreationError::UsernameTaken)
         } else {
             loop {
                 let id = random();
                 if let Entry::Vacant(e) = self.users.entry(id) {
                     e.insert(user);
-                    return Some(id);
+                    return Ok(id);
                 } else {
                     continue;
                 }
@@ -38,58 +168,369 @@ impl Users {
         }
     }
 
-    pub fn try_login(&mut self, username: String, password: String) -> Option<UserId> {
-        let user_id = self
-            .users
+    /// Looks up the id and stored password hash for a username.
+    pub fn find_login(&self, username: &str) -> Option<(UserId, String)> {
+        let key = username_key(username);
+        self.users
             .iter()
-            .find(|u| {
-                if u.1.username == username {
-                    // TODO: fix for possible timing attack
-                    let user = u.1;
-                    let mut pass_hash = PassHash::default();
-                    bcrypt_pbkdf(&password, &user.salt, BCRYPT_ROUNDS, &mut pass_hash).unwrap();
-                    pass_hash == user.pass_hash
-                } else {
-                    false
-                }
-            })
-            .map(|(id, _)| *id)?;
-        let session_id = random();
-        self.sessions.insert(session_id, user_id);
-        Some(session_id)
+            .find(|(_, user)| username_key(&user.username) == key)
+            .map(|(id, user)| (*id, user.pass_hash.clone()))
+    }
+
+    /// Replaces a user's password hash, e.g. after rehashing it with newer parameters.
+    pub fn set_pass_hash(&mut self, user_id: UserId, pass_hash: String) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.pass_hash = pass_hash;
+        }
+    }
+
+    /// Starts a session for a user whose password has already been checked.
+    pub fn start_session(&mut self, user_id: UserId, client: ClientInfo) -> SessionId {
+        loop {
+            let session_id = random();
+            if let Entry::Vacant(e) = self.sessions.entry(session_id) {
+                e.insert(SessionData::new(user_id, client));
+                return session_id;
+            }
+        }
     }
 
     pub fn get_session(&self, session_id: SessionId) -> Option<UserId> {
-        self.sessions.get(&session_id).copied()
+        self.sessions.get(&session_id).map(|s| s.user_id)
+    }
+
+    /// Marks the session as active now, and records the latest client details if there are any.
+    pub fn touch_session(&mut self, session_id: SessionId, client: Option<ClientInfo>) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.last_seen = unix_now();
+            if let Some(client) = client {
+                if client.user_agent.is_some() {
+                    session.user_agent = client.user_agent;
+                }
+                if client.ip.is_some() {
+                    session.ip = client.ip;
+                }
+            }
+        }
+    }
+
+    /// Marks whatever the request was authenticated with as being used now.
+    pub fn touch(&mut self, credential: Credential, client: Option<ClientInfo>) {
+        match credential {
+            Credential::Session(session_id) => self.touch_session(session_id, client),
+            Credential::Token(token_id) => {
+                if let Some(token) = self.tokens.get_mut(&token_id) {
+                    token.last_used = Some(unix_now());
+                }
+            }
+        }
     }
 
     pub fn get_sessions(&self, id: UserId) -> Vec<SessionId> {
         self.sessions
             .iter()
-            .filter(|(_session, user_id)| **user_id == id)
+            .filter(|(_session, data)| data.user_id == id)
             .map(|(id, _)| *id)
             .collect()
     }
 
+    pub fn list_sessions(&self, id: UserId) -> Vec<(SessionId, SessionData)> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_session, data)| data.user_id == id)
+            .map(|(id, data)| (*id, data.clone()))
+            .collect();
+        sessions.sort_by_key(|(_, data)| data.created_at);
+        sessions
+    }
+
     pub fn logout_session(&mut self, session_id: SessionId) {
         self.sessions.remove(&session_id);
     }
+
+    /// Creates a new token, returning its id and the only copy of the plaintext token.
+    pub fn create_token(
+        &mut self,
+        user_id: UserId,
+        name: String,
+        scope: Scope,
+    ) -> (TokenId, String, TokenData) {
+        let secret: [u8; 32] = random();
+        let data = TokenData {
+            user_id,
+            name,
+            scope,
+            secret_hash: hash_secret(&secret),
+            created_at: unix_now(),
+            last_used: None,
+        };
+        loop {
+            let id = random();
+            if let Entry::Vacant(e) = self.tokens.entry(id) {
+                e.insert(data.clone());
+                return (id, format_token(TOKEN_PREFIX, id, &secret), data);
+            }
+        }
+    }
+
+    /// Checks a plaintext token, recording that it was used if it's valid.
+    pub fn use_token(&mut self, token: &str) -> Option<(TokenId, UserId, Scope)> {
+        let (id, secret) = parse_token(TOKEN_PREFIX, token)?;
+        let data = self.tokens.get_mut(&id)?;
+        if data.secret_hash != hash_secret(&secret) {
+            return None;
+        }
+        data.last_used = Some(unix_now());
+        Some((id, data.user_id, data.scope))
+    }
+
+    pub fn list_tokens(&self, id: UserId) -> Vec<(TokenId, TokenData)> {
+        let mut tokens: Vec<_> = self
+            .tokens
+            .iter()
+            .filter(|(_token, data)| data.user_id == id)
+            .map(|(id, data)| (*id, data.clone()))
+            .collect();
+        tokens.sort_by_key(|(_, data)| data.created_at);
+        tokens
+    }
+
+    /// Revokes one of the user's tokens, returning whether it existed.
+    pub fn revoke_token(&mut self, user_id: UserId, token_id: TokenId) -> bool {
+        match self.tokens.entry(token_id) {
+            Entry::Occupied(e) if e.get().user_id == user_id => {
+                e.remove();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Creates the user's calendar feed secret, replacing any old one, and returns the only copy
+    /// of it.
+    pub fn create_feed(&mut self, user_id: UserId) -> String {
+        let secret: [u8; 32] = random();
+        self.feeds.insert(user_id, hash_secret(&secret));
+        format_token(FEED_PREFIX, user_id, &secret)
+    }
+
+    /// Finds whose calendar feed a secret is for.
+    pub fn use_feed(&self, feed: &str) -> Option<UserId> {
+        let (user_id, secret) = parse_token(FEED_PREFIX, feed)?;
+        let hash = self.feeds.get(&user_id)?;
+        (*hash == hash_secret(&secret)).then_some(user_id)
+    }
+
+    /// Revokes the user's calendar feed secret, returning whether there was one.
+    pub fn revoke_feed(&mut self, user_id: UserId) -> bool {
+        self.feeds.remove(&user_id).is_some()
+    }
+
+    /// Removes every session belonging to the user, returning the ids that were removed.
+    pub fn logout_all(&mut self, id: UserId) -> Vec<SessionId> {
+        let sessions = self.get_sessions(id);
+        for session_id in &sessions {
+            self.sessions.remove(session_id);
+        }
+        sessions
+    }
+}
+
+/// Everything in [`Users`], for backups. Passwords and secrets are only there as hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsersBackup {
+    users: Vec<UserBackup>,
+    /// Left out unless asked for, as they are only any use with the same cookie secret
+    #[serde(default)]
+    sessions: Vec<SessionBackup>,
+    tokens: Vec<TokenBackup>,
+    feeds: Vec<FeedBackup>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct UserBackup {
+    id: UserId,
+    username: String,
+    pass_hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SessionBackup {
+    id: SessionId,
+    user_id: UserId,
+    created_at: u64,
+    last_seen: u64,
+    user_agent: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct TokenBackup {
+    id: TokenId,
+    user_id: UserId,
+    name: String,
+    scope: Scope,
+    /// Hex encoded
+    secret_hash: String,
+    created_at: u64,
+    last_used: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FeedBackup {
+    user_id: UserId,
+    /// Hex encoded
+    secret_hash: String,
+}
+
+impl Users {
+    pub fn backup(&self, sessions: bool) -> UsersBackup {
+        let mut backup = UsersBackup {
+            users: self
+                .users
+                .iter()
+                .map(|(id, user)| UserBackup {
+                    id: *id,
+                    username: user.username.clone(),
+                    pass_hash: user.pass_hash.clone(),
+                })
+                .collect(),
+            sessions: self
+                .sessions
+                .iter()
+                .filter(|_| sessions)
+                .map(|(id, session)| SessionBackup {
+                    id: *id,
+                    user_id: session.user_id,
+                    created_at: session.created_at,
+                    last_seen: session.last_seen,
+                    user_agent: session.user_agent.clone(),
+                    ip: session.ip,
+                })
+                .collect(),
+            tokens: self
+                .tokens
+                .iter()
+                .map(|(id, token)| TokenBackup {
+                    id: *id,
+                    user_id: token.user_id,
+                    name: token.name.clone(),
+                    scope: token.scope,
+                    secret_hash: hex::encode(token.secret_hash),
+                    created_at: token.created_at,
+                    last_used: token.last_used,
+                })
+                .collect(),
+            feeds: self
+                .feeds
+                .iter()
+                .map(|(user_id, hash)| FeedBackup {
+                    user_id: *user_id,
+                    secret_hash: hex::encode(hash),
+                })
+                .collect(),
+        };
+        // Sorted, so backing up the same users twice gives the same archive
+        backup.users.sort_by_key(|user| user.id);
+        backup.sessions.sort_by_key(|session| session.id);
+        backup.tokens.sort_by_key(|token| token.id);
+        backup.feeds.sort_by_key(|feed| feed.user_id);
+        backup
+    }
+
+    /// Rebuilds the users from a backup, checking it's consistent first.
+    pub fn restore(backup: UsersBackup) -> Result<Self, String> {
+        let mut restored = Users::default();
+        let mut usernames = HashSet::new();
+        for user in backup.users {
+            if !usernames.insert(username_key(&user.username)) {
+                return Err(format!("username {:?} is used twice", user.username));
+            }
+            if !is_password_hash(&user.pass_hash) {
+                return Err(format!("user {} has an unreadable password hash", user.id));
+            }
+            let data = UserData {
+                username: user.username,
+                pass_hash: user.pass_hash,
+            };
+            if restored.users.insert(user.id, data).is_some() {
+                return Err(format!("user id {} is used twice", user.id));
+            }
+        }
+        let known = |user_id: UserId| {
+            if restored.users.contains_key(&user_id) {
+                Ok(())
+            } else {
+                Err(format!("user {user_id} doesn't exist"))
+            }
+        };
+        let mut sessions = HashMap::new();
+        for session in backup.sessions {
+            known(session.user_id)?;
+            let data = SessionData {
+                user_id: session.user_id,
+                created_at: session.created_at,
+                last_seen: session.last_seen,
+                user_agent: session.user_agent,
+                ip: session.ip,
+            };
+            if sessions.insert(session.id, data).is_some() {
+                return Err(format!("session id {} is used twice", session.id));
+            }
+        }
+        let mut tokens = HashMap::new();
+        for token in backup.tokens {
+            known(token.user_id)?;
+            let data = TokenData {
+                user_id: token.user_id,
+                name: token.name,
+                scope: token.scope,
+                secret_hash: decode_hash(&token.secret_hash)?,
+                created_at: token.created_at,
+                last_used: token.last_used,
+            };
+            if tokens.insert(token.id, data).is_some() {
+                return Err(format!("token id {} is used twice", token.id));
+            }
+        }
+        let mut feeds = HashMap::new();
+        for feed in backup.feeds {
+            known(feed.user_id)?;
+            if feeds
+                .insert(feed.user_id, decode_hash(&feed.secret_hash)?)
+                .is_some()
+            {
+                return Err(format!("user {} has two calendar feeds", feed.user_id));
+            }
+        }
+        restored.sessions = sessions;
+        restored.tokens = tokens;
+        restored.feeds = feeds;
+        Ok(restored)
+    }
+
+    pub fn contains(&self, user_id: UserId) -> bool {
+        self.users.contains_key(&user_id)
+    }
 }
 
-pub enum AccountCreationError {
-    PasswordTooShort,
+fn decode_hash(hash: &str) -> Result<[u8; 32], String> {
+    let mut decoded = [0; 32];
+    hex::decode_to_slice(hash, &mut decoded)
+        .map_err(|_| format!("{hash:?} isn't a SHA-256 hash"))?;
+    Ok(decoded)
 }
 
 impl UserData {
+    /// Hashes the password, which is slow on purpose, so this should be called from a blocking
+    /// thread.
     pub fn new(username: String, password: String) -> Result<Self, AccountCreationError> {
-        let salt: Salt = random();
-        let mut pass_hash = PassHash::default();
-        bcrypt_pbkdf(&password, &salt, BCRYPT_ROUNDS, &mut pass_hash)
-            .map_err(|_| AccountCreationError::PasswordTooShort)?;
+        let pass_hash =
+            hash_password(&password).map_err(|_| AccountCreationError::HashingFailed)?;
         Ok(Self {
             username,
             pass_hash,
-            salt,
         })
     }
 }
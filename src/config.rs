@@ -0,0 +1,518 @@
+//! The server's settings, from defaults, a TOML file, environment variables and command line
+//! flags, each overriding the ones before.
+//!
+//! Every setting has one name, used as is in the file, in upper case as an environment variable,
+//! and in kebab case as a flag: `max_tasks` is `MAX_TASKS` and `--max-tasks`. Lists are comma
+//! separated outside the file. The file is `rust-elm.toml` if there is one, or whatever
+//! `CONFIG_FILE` or `--config` points at.
+
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::{Table, Value};
+
+use crate::app::{Env, Heartbeat, MessageLimits, Shutdown};
+use crate::password::derive_cookie_key;
+use crate::policy::AccountPolicy;
+
+const DEFAULT_FILE: &str = "rust-elm.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// The Elm app and other static files
+    pub assets_dir: PathBuf,
+    /// Where users and tasks are saved on shutdown and loaded from on startup, if anywhere
+    pub state_file: PathBuf,
+    /// Encrypts the session cookies. Any length works, but longer ones are harder to guess
+    pub cookie_secret: String,
+    /// Secrets the cookies used to be encrypted with, so changing it doesn't log everyone out
+    pub retired_cookie_secrets: Vec<String>,
+    /// Only lets browsers send cookies over HTTPS
+    pub cookie_secure: bool,
+    pub allowed_origins: Vec<String>,
+    /// Lets operators use `/api/admin`, which is off without it
+    pub admin_token: String,
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    /// Characters allowed in usernames on top of letters and digits
+    pub username_extra_chars: String,
+    pub password_min_length: usize,
+    pub password_max_length: usize,
+    /// Refuses the most common passwords, from a list built into the server
+    pub reject_common_passwords: bool,
+    /// Passwords to refuse on top of the common ones, ignoring case
+    pub banned_passwords: Vec<String>,
+    /// Serves `/metrics` on this address, like `127.0.0.1:9100`, instead of alongside the app
+    pub metrics_address: String,
+    /// Has `/metrics` ask for this bearer token
+    pub metrics_token: String,
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_missed_pongs: u32,
+    pub max_message_size: usize,
+    pub max_tasks: usize,
+    pub max_summary_length: usize,
+    pub shutdown_deadline_secs: u64,
+    pub reconnect_in_secs: u64,
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let heartbeat = Heartbeat::default();
+        let limits = MessageLimits::default();
+        let shutdown = Shutdown::default();
+        let policy = AccountPolicy::default();
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 3000,
+            assets_dir: PathBuf::from("assets"),
+            state_file: PathBuf::new(),
+            cookie_secret: String::new(),
+            retired_cookie_secrets: Vec::new(),
+            cookie_secure: false,
+            allowed_origins: Vec::new(),
+            admin_token: String::new(),
+            username_min_length: policy.username_min_length,
+            username_max_length: policy.username_max_length,
+            username_extra_chars: policy.username_extra_chars,
+            password_min_length: policy.password_min_length,
+            password_max_length: policy.password_max_length,
+            reject_common_passwords: policy.reject_common_passwords,
+            banned_passwords: policy.banned_passwords,
+            metrics_address: String::new(),
+            metrics_token: String::new(),
+            heartbeat_interval_secs: heartbeat.interval.as_secs(),
+            heartbeat_missed_pongs: heartbeat.missed_pongs,
+            max_message_size: limits.max_message_size,
+            max_tasks: limits.max_tasks,
+            max_summary_length: limits.max_summary_length,
+            shutdown_deadline_secs: shutdown.deadline.as_secs(),
+            reconnect_in_secs: shutdown.reconnect_in.as_secs(),
+            log_format: LogFormat::default(),
+        }
+    }
+}
+
+/// Everything wrong with the settings, one problem per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<String> for ConfigError {
+    fn from(problem: String) -> Self {
+        Self(vec![problem])
+    }
+}
+
+impl Config {
+    /// Reads the settings from the file, `env` and the flags in `args`.
+    pub fn load(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut flags = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(format!("Unexpected argument `{arg}`").into());
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag, value.clone()),
+                    None => return Err(format!("--{flag} needs a value").into()),
+                },
+            };
+            flags.push((name.to_string(), value));
+        }
+
+        let file = match flags.iter().rfind(|(name, _)| name == "config") {
+            Some((_, path)) => Some(PathBuf::from(path)),
+            None => env("CONFIG_FILE")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+        };
+        let mut settings = match &file {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_FILE).exists() => read_file(Path::new(DEFAULT_FILE))?,
+            None => Table::new(),
+        };
+
+        let defaults = Table::try_from(Config::default()).expect("the defaults are valid TOML");
+        let mut problems = Vec::new();
+        for (key, default) in &defaults {
+            let name = key.to_uppercase();
+            if let Some(raw) = env(&name) {
+                match parse(&name, key, default, &raw) {
+                    Ok(value) => settings.insert(key.clone(), value),
+                    Err(problem) => {
+                        problems.push(problem);
+                        None
+                    }
+                };
+            }
+        }
+        for (flag, raw) in flags.iter().filter(|(name, _)| name != "config") {
+            let name = format!("--{flag}");
+            let key = flag.replace('-', "_");
+            let Some(default) = defaults.get(&key) else {
+                problems.push(format!("{name} isn't a setting"));
+                continue;
+            };
+            match parse(&name, &key, default, raw) {
+                Ok(value) => {
+                    settings.insert(key, value);
+                }
+                Err(problem) => problems.push(problem),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(ConfigError(problems));
+        }
+        Value::Table(settings)
+            .try_into()
+            .map_err(|e| format!("Invalid settings: {e}").into())
+    }
+
+    /// Checks the settings make sense together, and turns them into what the server runs with.
+    pub fn into_env(self) -> Result<Env, ConfigError> {
+        let mut problems = Vec::new();
+        if (self.host.as_str(), self.port).to_socket_addrs().is_err() {
+            problems.push(format!(
+                "host `{}` isn't an address to listen on",
+                self.host
+            ));
+        }
+        if !self.assets_dir.is_dir() {
+            problems.push(format!(
+                "assets_dir `{}` isn't a directory",
+                self.assets_dir.display()
+            ));
+        }
+        let state_file = Some(self.state_file).filter(|path| !path.as_os_str().is_empty());
+        if let Some(dir) = state_file.as_deref().and_then(Path::parent)
+            && !dir.as_os_str().is_empty()
+            && !dir.is_dir()
+        {
+            problems.push(format!(
+                "state_file is in `{}`, which isn't a directory",
+                dir.display()
+            ));
+        }
+        let metrics_address = match self.metrics_address.trim() {
+            "" => None,
+            address => match address.parse::<SocketAddr>() {
+                Ok(address) => Some(address),
+                Err(_) => {
+                    problems.push(format!(
+                        "metrics_address `{address}` should be an IP address and port"
+                    ));
+                    None
+                }
+            },
+        };
+        if self.cookie_secret.is_empty() {
+            problems.push("cookie_secret (COOKIE_SECRET) must be set".to_string());
+        }
+        if self.heartbeat_interval_secs == 0 {
+            problems.push("heartbeat_interval_secs can't be 0".to_string());
+        }
+        if self.heartbeat_missed_pongs == 0 {
+            problems.push("heartbeat_missed_pongs can't be 0".to_string());
+        }
+        if self.username_min_length > self.username_max_length {
+            problems.push("username_min_length can't be more than username_max_length".to_string());
+        }
+        if self.password_min_length > self.password_max_length {
+            problems.push("password_min_length can't be more than password_max_length".to_string());
+        }
+        if self.max_message_size == 0 {
+            problems.push("max_message_size can't be 0".to_string());
+        }
+        if !problems.is_empty() {
+            return Err(ConfigError(problems));
+        }
+
+        let retired_secrets: Vec<_> = self
+            .retired_cookie_secrets
+            .iter()
+            .filter(|secret| !secret.is_empty())
+            .collect();
+        let mut retired_cookie_keys: Vec<_> = retired_secrets
+            .iter()
+            .map(|secret| derive_cookie_key(secret))
+            .collect();
+        // Keys used to be the first 64 bytes of the secret, without a KDF
+        retired_cookie_keys.extend(
+            std::iter::once(&&self.cookie_secret)
+                .chain(&retired_secrets)
+                .filter_map(|secret| secret.as_bytes().first_chunk::<64>().copied()),
+        );
+
+        Ok(Env {
+            port: self.port,
+            host: self.host,
+            assets_dir: self.assets_dir,
+            cookie_key: derive_cookie_key(&self.cookie_secret),
+            retired_cookie_keys,
+            cookie_secure: self.cookie_secure,
+            account_policy: AccountPolicy {
+                username_min_length: self.username_min_length,
+                username_max_length: self.username_max_length,
+                username_extra_chars: self.username_extra_chars,
+                password_min_length: self.password_min_length,
+                password_max_length: self.password_max_length,
+                reject_common_passwords: self.reject_common_passwords,
+                banned_passwords: self
+                    .banned_passwords
+                    .into_iter()
+                    .filter(|password| !password.is_empty())
+                    .collect(),
+            },
+            allowed_origins: self
+                .allowed_origins
+                .into_iter()
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            admin_token: Some(self.admin_token).filter(|token| !token.is_empty()),
+            restore: None,
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(self.heartbeat_interval_secs),
+                missed_pongs: self.heartbeat_missed_pongs,
+            },
+            message_limits: MessageLimits {
+                max_message_size: self.max_message_size,
+                max_tasks: self.max_tasks,
+                max_summary_length: self.max_summary_length,
+            },
+            shutdown: Shutdown {
+                deadline: Duration::from_secs(self.shutdown_deadline_secs),
+                reconnect_in: Duration::from_secs(self.reconnect_in_secs),
+            },
+            state_file,
+            metrics_address,
+            metrics_token: Some(self.metrics_token).filter(|token| !token.is_empty()),
+        })
+    }
+
+    /// Every setting, with its default, for `--help`.
+    pub fn help() -> String {
+        let defaults = Table::try_from(Config::default()).expect("the defaults are valid TOML");
+        defaults
+            .iter()
+            .map(|(key, default)| {
+                format!(
+                    "  --{:<26} {:<26} default: {default}\n",
+                    key.replace('_', "-"),
+                    key.to_uppercase(),
+                )
+            })
+            .collect()
+    }
+}
+
+fn read_file(path: &Path) -> Result<Table, ConfigError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read the config file {}: {e}", path.display()))?;
+    // Read as settings first, for errors that say which line is wrong
+    toml::from_str::<Config>(&text)
+        .and_then(|_| toml::from_str::<Table>(&text))
+        .map_err(|e| format!("Invalid config file {}: {e}", path.display()).into())
+}
+
+/// Reads a setting given as text, which has the same type as its default. `name` is where it came
+/// from, for errors.
+fn parse(name: &str, key: &str, default: &Value, raw: &str) -> Result<Value, String> {
+    let value = match default {
+        Value::Integer(_) => raw
+            .trim()
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("{name} should be a whole number, not `{raw}`"))?,
+        Value::Boolean(_) => raw
+            .trim()
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("{name} should be true or false, not `{raw}`"))?,
+        Value::Array(_) => Value::Array(
+            raw.split(',')
+                .map(|item| Value::String(item.trim().to_string()))
+                .collect(),
+        ),
+        _ => Value::String(raw.to_string()),
+    };
+    // Catches numbers out of range and unknown choices, while it's known where they came from
+    Value::Table(Table::from_iter([(key.to_string(), value.clone())]))
+        .try_into::<Config>()
+        .map_err(|e| format!("{name}: {}", e.message()))?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::policy::AccountCreationError;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn config_file(contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rust-elm-config-{}.toml", rand::random::<u64>()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn unit_later_layers_win() {
+        let file = config_file("port = 4000\nmax_tasks = 5\nlog_format = \"json\"\n");
+        let config = Config::load(
+            &args(&["--config", file.to_str().unwrap(), "--max-tasks=7"]),
+            env(&[
+                ("PORT", "5000"),
+                ("MAX_TASKS", "6"),
+                ("ALLOWED_ORIGINS", "a, b"),
+            ]),
+        )
+        .unwrap();
+        std::fs::remove_file(file).unwrap();
+
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.max_tasks, 7);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.allowed_origins, vec!["a", "b"]);
+        assert_eq!(config.host, Config::default().host);
+    }
+
+    #[test]
+    fn unit_account_policy_can_be_changed() {
+        let config = Config::load(
+            &args(&["--password-min-length", "12"]),
+            env(&[
+                ("BANNED_PASSWORDS", "tasks-and-todos, todo"),
+                ("REJECT_COMMON_PASSWORDS", "false"),
+            ]),
+        )
+        .unwrap();
+        let config = Config {
+            cookie_secret: SECRET.to_string(),
+            assets_dir: std::env::temp_dir(),
+            ..config
+        };
+        let Ok(env) = config.into_env() else {
+            panic!("expected the settings to be accepted");
+        };
+
+        let policy = env.account_policy;
+        assert_eq!(policy.password_min_length, 12);
+        assert_eq!(
+            policy.username_min_length,
+            AccountPolicy::default().username_min_length
+        );
+        assert_eq!(
+            policy.check("testuser", "testpass"),
+            Err(AccountCreationError::PasswordTooShort)
+        );
+        assert_eq!(
+            policy.check("testuser", "Tasks-And-Todos"),
+            Err(AccountCreationError::PasswordTooCommon)
+        );
+        assert!(policy.check("testuser", "password1234").is_ok());
+        assert_eq!(policy.banned_passwords, vec!["tasks-and-todos", "todo"]);
+    }
+
+    #[test]
+    fn unit_errors_say_which_setting_is_wrong() {
+        let ConfigError(problems) = Config::load(
+            &args(&["--log-format", "xml", "--colour", "blue"]),
+            env(&[("PORT", "abc"), ("MAX_TASKS", "-1")]),
+        )
+        .unwrap_err();
+        assert_eq!(problems.len(), 4);
+        for name in ["PORT", "MAX_TASKS", "--log-format", "--colour"] {
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(name)),
+                "{name} isn't blamed in {problems:?}"
+            );
+        }
+
+        let file = config_file("prot = 4000\n");
+        let error = Config::load(&args(&["--config", file.to_str().unwrap()]), env(&[]))
+            .unwrap_err()
+            .to_string();
+        std::fs::remove_file(file).unwrap();
+        assert!(error.contains("prot"), "{error}");
+    }
+
+    #[test]
+    fn unit_checks_settings_without_panicking() {
+        let config = Config {
+            heartbeat_interval_secs: 0,
+            assets_dir: PathBuf::from("/no/such/dir"),
+            metrics_address: "localhost".to_string(),
+            ..Config::default()
+        };
+        let Err(ConfigError(problems)) = config.into_env() else {
+            panic!("expected the settings to be refused");
+        };
+        assert_eq!(problems.len(), 4, "{problems:?}");
+
+        let config = Config {
+            cookie_secret: "short".to_string(),
+            retired_cookie_secrets: vec![SECRET.to_string()],
+            assets_dir: std::env::temp_dir(),
+            admin_token: String::new(),
+            ..Config::default()
+        };
+        let env = config.into_env().unwrap();
+        assert_eq!(env.admin_token, None);
+        assert_eq!(env.state_file, None);
+        assert_eq!(env.metrics_address, None);
+        assert_eq!(env.cookie_key, derive_cookie_key("short"));
+        assert_eq!(
+            env.retired_cookie_keys,
+            vec![
+                derive_cookie_key(SECRET),
+                *SECRET.as_bytes().first_chunk().unwrap()
+            ]
+        );
+    }
+}
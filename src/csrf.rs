@@ -0,0 +1,61 @@
+use axum::http::{HeaderMap, Uri, header};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use rand::random;
+
+/// A cookie the browser javascript can read, and has to echo back in [`CSRF_HEADER`].
+/// A cross-site page can make the browser send the cookie, but it can't read it to set the header.
+pub const CSRF_COOKIE: &str = "csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+pub fn new_csrf_cookie(secure: bool) -> Cookie<'static> {
+    let token: [u8; 32] = random();
+    let mut cookie = Cookie::new(CSRF_COOKIE, hex::encode(token));
+    cookie.set_path("/");
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_secure(secure);
+    cookie
+}
+
+/// Checks the `Origin` header is either the same as the `Host` or one of the allowed origins.
+/// Requests without an `Origin` don't come from a cross-site page in a browser, so they are
+/// allowed.
+pub fn origin_allowed(headers: &HeaderMap, allowed_origins: &[String]) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    if allowed_origins
+        .iter()
+        .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    {
+        return true;
+    }
+    let Some(authority) = origin
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().cloned())
+    else {
+        return false;
+    };
+    headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .is_some_and(|host| host.eq_ignore_ascii_case(authority.as_str()))
+}
+
+/// Checks the double-submitted token in [`CSRF_HEADER`] matches the [`CSRF_COOKIE`].
+pub fn csrf_token_matches(headers: &HeaderMap, jar: &CookieJar) -> bool {
+    let Some(cookie) = jar.get(CSRF_COOKIE) else {
+        return false;
+    };
+    let sent = headers
+        .get(CSRF_HEADER)
+        .and_then(|sent| sent.to_str().ok())
+        .unwrap_or_default();
+    !cookie.value().is_empty() && cookie.value() == sent
+}
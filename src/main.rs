@@ -1,27 +1,101 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::path::{Path, PathBuf};
+use tracing_subscriber::{Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::app::*;
 
 mod app;
 mod auth;
+mod config;
+mod csrf;
+mod password;
+mod policy;
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("generate-elm") => {
+            let path = elm_module_path();
+            std::fs::write(&path, elm_module()).expect("failed to write the Elm module");
+            println!("Wrote {}", path.display());
+            return;
+        }
+        Some("--help" | "-h" | "help") => {
+            println!("Usage: rust-elm [restore <backup file>] [--<setting> <value>]...");
+            println!("       rust-elm generate-elm\n");
+            println!("Settings, as flags and environment variables, or in the file given with");
+            println!("--config or CONFIG_FILE (rust-elm.toml by default):");
+            print!("{}", config::Config::help());
+            return;
+        }
+        _ => {}
+    }
+
+    let (restore, flags) = match args.first().map(String::as_str) {
+        Some("restore") => match args.get(1) {
+            Some(path) if !path.starts_with("--") => (Some(PathBuf::from(path)), &args[2..]),
+            _ => {
+                eprintln!("Usage: rust-elm restore <backup file>");
+                std::process::exit(2);
+            }
+        },
+        _ => (None, &args[..]),
+    };
+
     dotenv::dotenv().ok();
-    let env = Env {
-        port: 3000,
-        host: "0.0.0.0".to_string(),
-        cookie_secret: std::env::var("COOKIE_SECRET").expect("COOKIE_SECRET must be set"),
+    let config = config::Config::load(flags, |name| std::env::var(name).ok());
+    let config = config.unwrap_or_else(|error| {
+        eprintln!("{error}");
+        std::process::exit(2);
+    });
+    let log_format = config.log_format;
+    let mut env = config.into_env().unwrap_or_else(|error| {
+        eprintln!("{error}");
+        std::process::exit(2);
+    });
+
+    // What was saved when the server last shut down, unless there's a backup to start from
+    env.restore = match (restore, &env.state_file) {
+        (Some(path), _) => match read_backup(&path) {
+            Ok(backup) => Some(backup),
+            Err(error) => {
+                eprintln!("Can't restore {}: {error}", path.display());
+                std::process::exit(1);
+            }
+        },
+        (None, Some(path)) if path.exists() => match read_backup(path) {
+            Ok(backup) => Some(backup),
+            Err(error) => {
+                eprintln!("Can't load the state file {}: {error}", path.display());
+                std::process::exit(1);
+            }
+        },
+        (None, _) => None,
     };
 
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match log_format {
+        config::LogFormat::Full => fmt.boxed(),
+        config::LogFormat::Compact => fmt.compact().boxed(),
+        config::LogFormat::Pretty => fmt.pretty().boxed(),
+        config::LogFormat::Json => fmt.json().boxed(),
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
                 format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt)
         .init();
 
-    run_app(env).await;
+    if let Err(error) = run_app(env).await {
+        eprintln!("Can't start the server: {error}");
+        std::process::exit(1);
+    }
+}
+
+fn read_backup(path: &Path) -> Result<Backup, String> {
+    let archive = std::fs::read(path).map_err(|e| e.to_string())?;
+    Backup::from_slice(&archive).map_err(|e| e.to_string())
 }
@@ -0,0 +1,194 @@
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use bcrypt_pbkdf::bcrypt_pbkdf;
+use std::sync::OnceLock;
+
+/// The PHC identifier used for hashes made before the switch to Argon2id.
+/// They look like `$bcrypt-pbkdf$r=10$<salt>$<hash>`.
+const BCRYPT_PBKDF: &str = "bcrypt-pbkdf";
+
+/// Keeps keys derived from a secret for cookies apart from anything else derived from it.
+const COOKIE_KEY_SALT: &[u8] = b"rust-elm session cookies";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Invalid,
+    Valid,
+    /// The password is right, but was hashed with an old algorithm or parameters.
+    ValidNeedsRehash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashError;
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+/// Hashes a password into a PHC string with the current algorithm and parameters.
+/// This is slow on purpose, so it should be called from a blocking thread.
+pub fn hash_password(password: &str) -> Result<String, HashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| HashError)
+}
+
+/// Checks a password against a stored PHC string.
+/// This is slow on purpose, so it should be called from a blocking thread.
+pub fn check_password(password: &str, stored: &str) -> PasswordCheck {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return PasswordCheck::Invalid;
+    };
+    if hash.algorithm.as_str() == BCRYPT_PBKDF {
+        return match check_bcrypt_pbkdf(password, &hash) {
+            true => PasswordCheck::ValidNeedsRehash,
+            false => PasswordCheck::Invalid,
+        };
+    }
+    if argon2()
+        .verify_password(password.as_bytes(), &hash)
+        .is_err()
+    {
+        return PasswordCheck::Invalid;
+    }
+    let current = Params::default();
+    let up_to_date = hash.algorithm == Algorithm::Argon2id.ident()
+        && hash.version == Some(Version::V0x13.into())
+        && Params::try_from(&hash).is_ok_and(|params| {
+            params.m_cost() == current.m_cost()
+                && params.t_cost() == current.t_cost()
+                && params.p_cost() == current.p_cost()
+        });
+    if up_to_date {
+        PasswordCheck::Valid
+    } else {
+        PasswordCheck::ValidNeedsRehash
+    }
+}
+
+/// Whether a stored value is a PHC string this module can check passwords against.
+pub fn is_password_hash(stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok()
+}
+
+/// Does the same work as checking a real password, so a missing user takes as long to reject
+/// as a wrong password does.
+pub fn check_no_password(password: &str) -> PasswordCheck {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let dummy = DUMMY.get_or_init(|| hash_password("dummy password").unwrap_or_default());
+    check_password(password, dummy);
+    PasswordCheck::Invalid
+}
+
+/// Derives the key cookies are encrypted with from a secret of any length. Argon2id keeps short
+/// secrets expensive to guess. This is slow on purpose, so it's done once, at startup.
+pub fn derive_cookie_key(secret: &str) -> [u8; 64] {
+    let mut key = [0; 64];
+    argon2()
+        .hash_password_into(secret.as_bytes(), COOKIE_KEY_SALT, &mut key)
+        .expect("64 bytes is a valid Argon2 output length");
+    key
+}
+
+fn check_bcrypt_pbkdf(password: &str, hash: &PasswordHash) -> bool {
+    let Some(rounds) = hash.params.get_decimal("r").filter(|rounds| *rounds > 0) else {
+        return false;
+    };
+    let (Some(salt), Some(expected)) = (hash.salt, hash.hash) else {
+        return false;
+    };
+    let mut salt_bytes = [0; 64];
+    let Ok(salt) = salt.decode_b64(&mut salt_bytes) else {
+        return false;
+    };
+    let mut actual = vec![0; expected.len()];
+    bcrypt_pbkdf(password, salt, rounds, &mut actual).is_ok() && expected.as_bytes() == actual
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::Output;
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+
+    use super::*;
+
+    fn legacy_hash(password: &str) -> String {
+        let salt: [u8; 32] = rand::random();
+        let mut hash = [0; 32];
+        bcrypt_pbkdf(password, &salt, 10, &mut hash).unwrap();
+        format!(
+            "${BCRYPT_PBKDF}$r=10${}${}",
+            SaltString::encode_b64(&salt).unwrap().as_str(),
+            Output::new(&hash).unwrap()
+        )
+    }
+
+    #[test]
+    fn unit_hash_is_argon2id_phc() {
+        let hash = hash_password("testpass").unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$"));
+        assert_eq!(check_password("testpass", &hash), PasswordCheck::Valid);
+    }
+
+    #[test]
+    fn unit_wrong_password() {
+        let hash = hash_password("testpass").unwrap();
+
+        assert_eq!(
+            check_password("bad_password", &hash),
+            PasswordCheck::Invalid
+        );
+    }
+
+    #[test]
+    fn unit_legacy_hash_needs_rehash() {
+        let hash = legacy_hash("testpass");
+
+        assert_eq!(
+            check_password("testpass", &hash),
+            PasswordCheck::ValidNeedsRehash
+        );
+        assert_eq!(
+            check_password("bad_password", &hash),
+            PasswordCheck::Invalid
+        );
+    }
+
+    #[test]
+    fn unit_weaker_argon2_params_need_rehash() {
+        let weak = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8 * 1024, 1, 1, None).unwrap(),
+        );
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = weak.hash_password(b"testpass", &salt).unwrap().to_string();
+
+        assert_eq!(
+            check_password("testpass", &hash),
+            PasswordCheck::ValidNeedsRehash
+        );
+    }
+
+    #[test]
+    fn unit_garbage_hash_is_invalid() {
+        assert_eq!(
+            check_password("testpass", "not a hash"),
+            PasswordCheck::Invalid
+        );
+    }
+
+    #[test]
+    fn unit_cookie_keys_come_from_any_secret() {
+        let short = derive_cookie_key("hunter2");
+        assert_eq!(short, derive_cookie_key("hunter2"));
+        assert_ne!(short, derive_cookie_key("hunter3"));
+        assert_ne!(derive_cookie_key(&"x".repeat(200)), [0; 64]);
+    }
+}
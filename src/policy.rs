@@ -0,0 +1,249 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{collections::HashSet, sync::OnceLock};
+use unicode_normalization::UnicodeNormalization;
+
+/// A small offline list of the most common passwords, one per line.
+const COMMON_PASSWORDS: &str = include_str!("common-passwords.txt");
+
+/// The rules usernames and passwords must follow when an account is created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountPolicy {
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    /// Characters allowed in usernames on top of letters and digits
+    pub username_extra_chars: String,
+    pub password_min_length: usize,
+    /// Keeps the cost of hashing a password bounded
+    pub password_max_length: usize,
+    pub reject_common_passwords: bool,
+    /// Refused on top of the common passwords, like the site's name. Compared ignoring case
+    pub banned_passwords: Vec<String>,
+}
+
+impl Default for AccountPolicy {
+    fn default() -> Self {
+        Self {
+            username_min_length: 3,
+            username_max_length: 32,
+            username_extra_chars: "._-".to_string(),
+            password_min_length: 8,
+            password_max_length: 1024,
+            reject_common_passwords: true,
+            banned_passwords: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountCreationError {
+    UsernameTooShort,
+    UsernameTooLong,
+    UsernameInvalidCharacters,
+    UsernameTaken,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordTooCommon,
+    PasswordMatchesUsername,
+    HashingFailed,
+}
+
+impl AccountPolicy {
+    /// Checks a new account against the policy, returning the normalized username to store.
+    pub fn check(&self, username: &str, password: &str) -> Result<String, AccountCreationError> {
+        let username = normalize_username(username);
+        let length = username.chars().count();
+        if length < self.username_min_length {
+            return Err(AccountCreationError::UsernameTooShort);
+        }
+        if length > self.username_max_length {
+            return Err(AccountCreationError::UsernameTooLong);
+        }
+        if !username
+            .chars()
+            .all(|c| c.is_alphanumeric() || self.username_extra_chars.contains(c))
+        {
+            return Err(AccountCreationError::UsernameInvalidCharacters);
+        }
+
+        let length = password.chars().count();
+        if length < self.password_min_length {
+            return Err(AccountCreationError::PasswordTooShort);
+        }
+        if length > self.password_max_length {
+            return Err(AccountCreationError::PasswordTooLong);
+        }
+        if username_key(password) == username_key(&username) {
+            return Err(AccountCreationError::PasswordMatchesUsername);
+        }
+        if self.reject_common_passwords && is_common_password(password) {
+            return Err(AccountCreationError::PasswordTooCommon);
+        }
+        let lowercase = password.to_lowercase();
+        if self
+            .banned_passwords
+            .iter()
+            .any(|banned| banned.to_lowercase() == lowercase)
+        {
+            return Err(AccountCreationError::PasswordTooCommon);
+        }
+        Ok(username)
+    }
+
+    /// A human readable explanation of why an account was rejected.
+    pub fn describe(&self, error: AccountCreationError) -> String {
+        match error {
+            AccountCreationError::UsernameTooShort => format!(
+                "Usernames must be at least {} characters",
+                self.username_min_length
+            ),
+            AccountCreationError::UsernameTooLong => format!(
+                "Usernames must be at most {} characters",
+                self.username_max_length
+            ),
+            AccountCreationError::UsernameInvalidCharacters => format!(
+                "Usernames can only contain letters, numbers and any of `{}`",
+                self.username_extra_chars
+            ),
+            AccountCreationError::UsernameTaken => {
+                "Username already exists, pick a different one".to_string()
+            }
+            AccountCreationError::PasswordTooShort => format!(
+                "Passwords must be at least {} characters",
+                self.password_min_length
+            ),
+            AccountCreationError::PasswordTooLong => format!(
+                "Passwords must be at most {} characters",
+                self.password_max_length
+            ),
+            AccountCreationError::PasswordTooCommon => {
+                "That password is too common, pick a different one".to_string()
+            }
+            AccountCreationError::PasswordMatchesUsername => {
+                "Passwords can't be the same as the username".to_string()
+            }
+            AccountCreationError::HashingFailed => "Failed to create the account".to_string(),
+        }
+    }
+}
+
+/// Usernames are stored in NFKC form, so lookalike encodings of the same name are one name.
+pub fn normalize_username(username: &str) -> String {
+    username.nfkc().collect()
+}
+
+/// The form usernames are compared in, so `Alice` and `alice` can't both be registered.
+pub fn username_key(username: &str) -> String {
+    normalize_username(username).to_lowercase().nfkc().collect()
+}
+
+fn is_common_password(password: &str) -> bool {
+    static LIST: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    let list = LIST.get_or_init(|| {
+        COMMON_PASSWORDS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect()
+    });
+    list.contains(password.to_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use pretty_assertions::{assert_eq, assert_ne, assert_str_eq};
+
+    use super::*;
+
+    #[test]
+    fn unit_valid_account() {
+        let policy = AccountPolicy::default();
+
+        assert_eq!(
+            policy.check("testuser", "testpass"),
+            Ok("testuser".to_string())
+        );
+    }
+
+    #[test]
+    fn unit_username_is_normalized() {
+        let policy = AccountPolicy::default();
+
+        // The fullwidth letters are compatibility equivalents of plain ascii
+        assert_eq!(
+            policy.check("ｔｅｓｔuser", "testpass"),
+            Ok("testuser".to_string())
+        );
+    }
+
+    #[test]
+    fn unit_username_key_ignores_case() {
+        assert_eq!(username_key("TestUser"), username_key("testuser"));
+        assert_eq!(username_key("Straße"), username_key("STRAßE"));
+    }
+
+    #[test]
+    fn unit_username_rules() {
+        let policy = AccountPolicy::default();
+
+        assert_eq!(
+            policy.check("ab", "testpass"),
+            Err(AccountCreationError::UsernameTooShort)
+        );
+        assert_eq!(
+            policy.check(&"a".repeat(33), "testpass"),
+            Err(AccountCreationError::UsernameTooLong)
+        );
+        assert_eq!(
+            policy.check("test user", "testpass"),
+            Err(AccountCreationError::UsernameInvalidCharacters)
+        );
+    }
+
+    #[test]
+    fn unit_password_rules() {
+        let policy = AccountPolicy::default();
+
+        assert_eq!(
+            policy.check("testuser", "short"),
+            Err(AccountCreationError::PasswordTooShort)
+        );
+        assert_eq!(
+            policy.check("testuser", &"a".repeat(1025)),
+            Err(AccountCreationError::PasswordTooLong)
+        );
+        assert_eq!(
+            policy.check("testuser", "Password1"),
+            Err(AccountCreationError::PasswordTooCommon)
+        );
+        assert_eq!(
+            policy.check("testuser", "TESTUSER"),
+            Err(AccountCreationError::PasswordMatchesUsername)
+        );
+    }
+
+    #[test]
+    fn unit_common_passwords_can_be_allowed() {
+        let policy = AccountPolicy {
+            reject_common_passwords: false,
+            ..AccountPolicy::default()
+        };
+
+        assert!(policy.check("testuser", "password").is_ok());
+    }
+
+    #[test]
+    fn unit_passwords_can_be_banned() {
+        let policy = AccountPolicy {
+            banned_passwords: vec!["rust-elm-tasks".to_string()],
+            ..AccountPolicy::default()
+        };
+
+        assert_eq!(
+            policy.check("testuser", "Rust-Elm-Tasks"),
+            Err(AccountCreationError::PasswordTooCommon)
+        );
+    }
+}